@@ -0,0 +1,46 @@
+#ifndef QBA_DB_H
+#define QBA_DB_H
+
+/* Generated by cbindgen from src/capi.rs, see there for documentation */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define QBA_OK 0
+
+#define QBA_ERROR 1
+
+#define QBA_ABORT 4
+
+#define QBA_BUSY 5
+
+#define QBA_FULL 13
+
+#define QBA_MISUSE 21
+
+typedef struct QbaDb QbaDb;
+
+typedef int (*QbaRowCallback)(void *user_data,
+                              int n_columns,
+                              const char *const *values,
+                              const char *const *names);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+int qba_open(const char *path, struct QbaDb **db);
+
+int qba_exec(struct QbaDb *db, const char *sql, QbaRowCallback callback, void *user_data);
+
+const char *qba_errmsg(const struct QbaDb *db);
+
+int qba_close(struct QbaDb *db);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* QBA_DB_H */
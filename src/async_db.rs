@@ -0,0 +1,60 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::db::{Db, DbOptions, ExecuteResponse};
+
+/**
+ * Handle to a db for async applications. Statements run on tokio's blocking thread pool
+ * so file I/O never blocks the async runtime. Cloning the handle shares the same db.
+ */
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Arc<Mutex<Db>>,
+}
+
+impl AsyncDb {
+    pub async fn open(file_path: String) -> Result<AsyncDb, String> {
+        let db = run_blocking(move || Db::open(file_path, DbOptions::default()))
+            .await?
+            .map_err(|e| e.to_string())?;
+
+        Ok(AsyncDb {
+            db: Arc::new(Mutex::new(db)),
+        })
+    }
+
+    pub async fn execute(&self, user_input: impl Into<String>) -> Result<ExecuteResponse, String> {
+        let db = Arc::clone(&self.db);
+        let user_input = user_input.into();
+
+        run_blocking(move || {
+            let mut db = db.lock().unwrap_or_else(PoisonError::into_inner);
+            db.execute(&user_input)
+        })
+        .await?
+    }
+
+    /**
+     * Saves the db without blocking the runtime. Dropping the last handle also saves the db,
+     * but does so on the current thread.
+     */
+    pub async fn close(self) -> Result<(), String> {
+        let db = self.db;
+
+        run_blocking(move || {
+            let mut db = db.lock().unwrap_or_else(PoisonError::into_inner);
+            db.close_db().map_err(String::from)
+        })
+        .await?
+    }
+}
+
+async fn run_blocking<T, F>(f: F) -> Result<T, String>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => Ok(result),
+        Err(e) => Err(format!("Db task failed: {}", e)),
+    }
+}
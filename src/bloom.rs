@@ -0,0 +1,61 @@
+use crate::{leaf_node::LEAF_NODE_MAX_CELLS, pager::TABLE_MAX_PAGES};
+
+// bits per key for the most keys a table can hold, with BIT_PROBES about 1% false positives
+const BITS_PER_KEY: usize = 10;
+const BIT_PROBES: u64 = 7;
+
+/**
+ * Bloom filter over the keys of a table. A key that was added is always reported as maybe
+ * present, a key that wasn't only when other keys happened to set all of its bits. Keys
+ * can't be removed, deleted keys stay maybe present until the filter is built again.
+ */
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    // Pager::reloads when the filter was built, keys written by other connections since are
+    // missing from it
+    pub(crate) reloads: u32,
+}
+
+impl BloomFilter {
+    /**
+     * Empty filter sized for every key a table can hold, which is bounded by TABLE_MAX_PAGES
+     */
+    pub(crate) fn new(reloads: u32) -> BloomFilter {
+        let bit_count = TABLE_MAX_PAGES * LEAF_NODE_MAX_CELLS * BITS_PER_KEY;
+
+        BloomFilter {
+            bits: vec![0; bit_count.div_ceil(u64::BITS as usize)],
+            reloads,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: u32) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / u64::BITS as usize] |= 1 << (bit % u64::BITS as usize);
+        }
+    }
+
+    /**
+     * False only when key was never inserted
+     */
+    pub(crate) fn may_contain(&self, key: u32) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / u64::BITS as usize] & (1 << (bit % u64::BITS as usize)) != 0)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.bits.fill(0);
+    }
+
+    // double hashing, both hashes taken from one splitmix64 of the key
+    fn bit_positions(&self, key: u32) -> impl Iterator<Item = usize> {
+        let mut hash = u64::from(key).wrapping_add(0x9e3779b97f4a7c15);
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^= hash >> 31;
+
+        let bit_count = (self.bits.len() * u64::BITS as usize) as u64;
+        let (first, step) = (hash & u64::from(u32::MAX), (hash >> 32) | 1);
+        (0..BIT_PROBES).map(move |probe| (first.wrapping_add(probe * step) % bit_count) as usize)
+    }
+}
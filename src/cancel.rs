@@ -0,0 +1,107 @@
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::error::DbError;
+
+/**
+ * Cancels a running statement from another thread. Clones share the same flag, so keep one
+ * clone and pass the other to Db::execute_with_cancel.
+ */
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/**
+ * Called every n_pages pages a statement visits with the number of pages visited so far.
+ * Returning true aborts the statement, like a non-zero return from sqlite3_progress_handler.
+ */
+#[derive(Clone)]
+pub struct ProgressHandler {
+    n_pages: u32,
+    callback: Arc<dyn Fn(u32) -> bool + Send + Sync>,
+}
+
+impl ProgressHandler {
+    pub fn new(n_pages: u32, callback: impl Fn(u32) -> bool + Send + Sync + 'static) -> Self {
+        ProgressHandler {
+            n_pages: n_pages.max(1),
+            callback: Arc::new(callback),
+        }
+    }
+
+    /**
+     * Calls the callback when pages_visited is a multiple of n_pages, returns whether it asked
+     * to abort
+     */
+    pub(crate) fn report(&self, pages_visited: u32) -> bool {
+        pages_visited.is_multiple_of(self.n_pages) && (self.callback)(pages_visited)
+    }
+}
+
+/**
+ * Checked by long running statements between leaf pages. Trips when the token is cancelled,
+ * the statement runs past its max duration or the progress handler asks to abort.
+ */
+pub struct StatementInterrupt<'a> {
+    token: &'a CancellationToken,
+    deadline: Option<Instant>,
+    progress_handler: Option<&'a ProgressHandler>,
+    pages_visited: Cell<u32>,
+}
+
+impl<'a> StatementInterrupt<'a> {
+    pub fn new(
+        token: &'a CancellationToken,
+        max_duration: Option<Duration>,
+        progress_handler: Option<&'a ProgressHandler>,
+    ) -> StatementInterrupt<'a> {
+        StatementInterrupt {
+            token,
+            deadline: max_duration.map(|max_duration| Instant::now() + max_duration),
+            progress_handler,
+            pages_visited: Cell::new(0),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), DbError> {
+        let pages_visited = self.pages_visited.get() + 1;
+        self.pages_visited.set(pages_visited);
+
+        let timed_out = self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline);
+
+        if self.token.is_cancelled() || timed_out {
+            return Err(DbError::Cancelled);
+        }
+
+        if let Some(handler) = self.progress_handler {
+            if handler.report(pages_visited) {
+                return Err(DbError::Cancelled);
+            }
+        }
+
+        Ok(())
+    }
+}
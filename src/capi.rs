@@ -0,0 +1,225 @@
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{
+    cancel::CancellationToken,
+    db::{Db, DbOptions},
+    error::DbError,
+};
+
+/*
+ * C API, built into a shared or static library with
+ * `cargo rustc --lib --release --features capi --crate-type cdylib` (or staticlib).
+ * include/qba_db.h is generated from this file with `cbindgen --config cbindgen.toml`.
+ */
+
+pub const QBA_OK: c_int = 0;
+pub const QBA_ERROR: c_int = 1;
+// the row callback asked to stop
+pub const QBA_ABORT: c_int = 4;
+// another connection holds a conflicting lock on the db file
+pub const QBA_BUSY: c_int = 5;
+// the write would grow the db file past its page quota
+pub const QBA_FULL: c_int = 13;
+pub const QBA_MISUSE: c_int = 21;
+
+/**
+ * Called once per row returned by a select, like the callback of sqlite3_exec. values and
+ * names hold n_columns NUL terminated strings. Returning non-zero stops the statement.
+ */
+pub type QbaRowCallback = Option<
+    unsafe extern "C" fn(
+        user_data: *mut c_void,
+        n_columns: c_int,
+        values: *const *const c_char,
+        names: *const *const c_char,
+    ) -> c_int,
+>;
+
+/**
+ * Db handle, owned by the C caller until it is passed to qba_close
+ */
+pub struct QbaDb {
+    // None when qba_open failed, the handle then only carries the error
+    db: Option<Db>,
+    // message of the last failed qba_open or qba_exec
+    last_error: CString,
+}
+
+/**
+ * Opens or creates the db file at path, like sqlite3_open. *db is set to a new handle even
+ * when opening fails, so the reason is available from qba_errmsg; the handle must be passed
+ * to qba_close either way. Returns QBA_MISUSE, with *db untouched, if db is NULL.
+ *
+ * # Safety
+ * path must be a valid NUL terminated string, db must be valid for writes.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn qba_open(path: *const c_char, db: *mut *mut QbaDb) -> c_int {
+    if path.is_null() || db.is_null() {
+        return QBA_MISUSE;
+    }
+
+    let opened = match CStr::from_ptr(path).to_str() {
+        // a corrupt file can still panic deep inside the pager, which must not unwind into C
+        Ok(path) => panic::catch_unwind(|| Db::open(path.to_string(), DbOptions::default()))
+            .unwrap_or_else(|_| Err(DbError::Storage("Opening the db file panicked"))),
+        Err(_) => Err(DbError::Storage("Path is not valid utf-8")),
+    };
+
+    let (handle, code) = match opened {
+        Ok(opened) => (
+            QbaDb {
+                db: Some(opened),
+                last_error: CString::default(),
+            },
+            QBA_OK,
+        ),
+        Err(e) => (
+            QbaDb {
+                db: None,
+                last_error: CString::new(e.to_string()).unwrap_or_default(),
+            },
+            if e == DbError::Busy {
+                QBA_BUSY
+            } else {
+                QBA_ERROR
+            },
+        ),
+    };
+    *db = Box::into_raw(Box::new(handle));
+    code
+}
+
+/**
+ * Executes one statement, calling callback with user_data for every row a select returns.
+ * callback may be NULL. On failure the message is available from qba_errmsg.
+ *
+ * # Safety
+ * db must come from qba_open and not be closed yet, sql must be a valid NUL terminated
+ * string.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn qba_exec(
+    db: *mut QbaDb,
+    sql: *const c_char,
+    callback: QbaRowCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let Some(handle) = db.as_mut() else {
+        return QBA_MISUSE;
+    };
+    if sql.is_null() {
+        return QBA_MISUSE;
+    }
+    // the handle of a failed qba_open keeps its open error
+    let Some(opened) = handle.db.as_mut() else {
+        return QBA_MISUSE;
+    };
+    let Ok(sql) = CStr::from_ptr(sql).to_str() else {
+        handle.last_error = CString::from(c"Statement is not valid utf-8");
+        return QBA_ERROR;
+    };
+
+    // stops the scan at the next leaf once the callback asks to abort
+    let token = CancellationToken::new();
+    let mut aborted = false;
+
+    let mut on_row = |values: &[(crate::db::Column, String)]| {
+        let Some(callback) = callback else {
+            return;
+        };
+        if aborted {
+            return;
+        }
+
+        // C strings can't hold NUL bytes, a value containing one is passed as empty
+        let names: Vec<CString> = values
+            .iter()
+            .map(|(column, _)| CString::new(column.name()).unwrap_or_default())
+            .collect();
+        let texts: Vec<CString> = values
+            .iter()
+            .map(|(_, value)| CString::new(value.as_str()).unwrap_or_default())
+            .collect();
+        let name_ptrs: Vec<*const c_char> = names.iter().map(|name| name.as_ptr()).collect();
+        let text_ptrs: Vec<*const c_char> = texts.iter().map(|text| text.as_ptr()).collect();
+
+        if callback(
+            user_data,
+            values.len() as c_int,
+            text_ptrs.as_ptr(),
+            name_ptrs.as_ptr(),
+        ) != 0
+        {
+            aborted = true;
+            token.cancel();
+        }
+    };
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        opened.execute_with_rows(sql, &token, &mut on_row)
+    }));
+
+    match result {
+        _ if aborted => QBA_ABORT,
+        Ok(Ok(_)) => QBA_OK,
+        Ok(Err(message)) => {
+            let code = if message == DbError::Busy.to_string() {
+                QBA_BUSY
+            } else if message == DbError::QuotaExceeded.to_string() {
+                QBA_FULL
+            } else {
+                QBA_ERROR
+            };
+            handle.last_error = CString::new(message).unwrap_or_default();
+            code
+        }
+        Err(_) => {
+            handle.last_error = CString::from(c"Statement panicked");
+            QBA_ERROR
+        }
+    }
+}
+
+/**
+ * Message of the last failed qba_open or qba_exec on db, or an empty string. Owned by the handle and
+ * valid until the next qba_exec or qba_close.
+ *
+ * # Safety
+ * db must come from qba_open and not be closed yet.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn qba_errmsg(db: *const QbaDb) -> *const c_char {
+    match db.as_ref() {
+        Some(handle) => handle.last_error.as_ptr(),
+        None => c"".as_ptr(),
+    }
+}
+
+/**
+ * Saves and closes the db. The handle can't be used afterwards, even when saving fails.
+ *
+ * # Safety
+ * db must come from qba_open and not be closed yet. NULL is ignored.
+ */
+#[no_mangle]
+pub unsafe extern "C" fn qba_close(db: *mut QbaDb) -> c_int {
+    if db.is_null() {
+        return QBA_OK;
+    }
+
+    let handle = Box::from_raw(db);
+    let Some(mut opened) = handle.db else {
+        return QBA_OK;
+    };
+    let result = opened.close_db();
+    drop(opened);
+
+    match result {
+        Ok(_) => QBA_OK,
+        Err(_) => QBA_ERROR,
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::db::Row;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeOp {
+    Insert,
+    // one event per row removed by truncate or by a delete of a key range
+    Delete,
+    Update,
+}
+
+/**
+ * A row change, sent to subscribers once the statement that made it is committed
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeEvent {
+    pub table: &'static str,
+    pub op: ChangeOp,
+    pub key: u32,
+    pub before: Option<Row>,
+    pub after: Option<Row>,
+}
+
+/**
+ * Changes made since the last commit and the channels they are published on. Nothing is
+ * recorded while there are no subscribers.
+ */
+#[derive(Default)]
+pub(crate) struct ChangeLog {
+    pending: Vec<ChangeEvent>,
+    subscribers: Vec<Sender<ChangeEvent>>,
+}
+
+impl ChangeLog {
+    pub(crate) fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    pub(crate) fn is_observed(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    pub(crate) fn record(&mut self, event: ChangeEvent) {
+        if self.is_observed() {
+            self.pending.push(event);
+        }
+    }
+
+    /**
+     * Sends the pending changes to every subscriber, dropping the ones whose receiver is gone
+     */
+    pub(crate) fn publish(&mut self) {
+        for event in self.pending.drain(..) {
+            self.subscribers
+                .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        }
+    }
+}
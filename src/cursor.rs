@@ -1,80 +1,231 @@
-use crate::{db, internal_node::InternalNode, leaf_node::LeafNode, pager::NodeType};
+use crate::{db, internal_node::InternalView, leaf_node::LeafView, pager::NodeType};
 use db::Table;
 
+/**
+ * Position in a table. The cursor remembers the key it is on and the generation of its
+ * page, and moves back to that key when the page changed since, like after a split moved
+ * its cell.
+ */
 pub struct Cursor<'a> {
     pub table: &'a mut Table,
     pub page_num: u32,
     pub cell_num: u32,
     pub end_of_table: bool,
+    // key of the cell under the cursor, or the key searched for when there is none
+    pub(crate) key: u32,
+    // generation of the page when the cursor was positioned on it
+    pub(crate) generation: u32,
 }
 
 impl<'a> Cursor<'a> {
-    pub fn table_start(table: &mut Table) -> Cursor {
-        let cursor = Self::table_find(table, 0);
-        let root_page_num = cursor.page_num;
+    pub fn table_start(table: &mut Table) -> Cursor<'_> {
+        let mut cursor = Self::table_find(table, 0);
+        cursor.cell_num = 0;
+        cursor.skip_exhausted_leaves();
 
-        let leaf_node = table.pager.get_page_leaf(root_page_num as usize).unwrap();
-        let num_cells = leaf_node.num_cells;
-
-        return Cursor {
-            table,
-            page_num: root_page_num,
-            cell_num: 0,
-            end_of_table: num_cells == 0,
-        };
+        cursor
     }
 
-    pub fn table_end(table: &mut Table) -> Cursor {
+    pub fn table_end(table: &mut Table) -> Cursor<'_> {
         let root_page_num = table.root_page_num;
         let root_node = table.pager.get_page_leaf(root_page_num as usize).unwrap();
-        let num_cells = root_node.num_cells;
+        let num_cells = root_node.num_cells();
+        let generation = table.pager.page_generation(root_page_num as usize);
 
-        return Cursor {
+        Cursor {
             table,
             page_num: root_page_num,
             cell_num: num_cells,
             end_of_table: true,
-        };
+            key: u32::MAX,
+            generation,
+        }
     }
 
-    pub fn table_find(table: &mut Table, key: u32) -> Cursor {
+    pub fn table_find(table: &mut Table, key: u32) -> Cursor<'_> {
         let root_page_num = table.root_page_num as usize;
 
-        match table.pager.get_page_node_type(root_page_num) {
-            NodeType::Leaf => {
-                return LeafNode::node_find(table, root_page_num as u32, key);
-            }
-            NodeType::Internal => {
-                return InternalNode::node_find(table, root_page_num as u32, key);
+        match table.pager.get_page_node_type(root_page_num).unwrap() {
+            NodeType::Leaf => LeafView::node_find(table, root_page_num as u32, key),
+            NodeType::Internal => InternalView::node_find(table, root_page_num as u32, key),
+        }
+    }
+
+    /**
+     * Cursor on the first key not less than key, at the end of the table if every key is less
+     */
+    pub fn seek(table: &mut Table, key: u32) -> Cursor<'_> {
+        let mut cursor = Self::table_find(table, key);
+        // node_find only knows about the end of its own leaf
+        cursor.end_of_table = false;
+        cursor.skip_exhausted_leaves();
+
+        cursor
+    }
+
+    /**
+     * Cursor on the last key not greater than key, invalid if every key is greater
+     */
+    pub fn seek_for_prev(table: &mut Table, key: u32) -> Cursor<'_> {
+        let root_page_num = table.root_page_num;
+        let mut cursor = Cursor {
+            table,
+            page_num: root_page_num,
+            cell_num: 0,
+            end_of_table: true,
+            key,
+            generation: 0,
+        };
+        cursor.position_at_or_before(key);
+
+        cursor
+    }
+
+    /**
+     * Whether the cursor is on a key, false once it moved past either end of the table
+     */
+    pub fn is_valid(&mut self) -> bool {
+        !self.end_of_table && self.get_cursor_key().is_some()
+    }
+
+    /**
+     * Moves to the next key, the same as advance_cursor
+     */
+    pub fn next(&mut self) {
+        self.advance_cursor();
+    }
+
+    /**
+     * Moves to the previous key. The cursor becomes invalid when it was on the first key, and
+     * stays invalid when it already was.
+     */
+    pub fn prev(&mut self) {
+        if self.end_of_table {
+            return;
+        }
+
+        // a deleted key still has the same previous key
+        match self.key.checked_sub(1) {
+            Some(key) => self.position_at_or_before(key),
+            None => self.end_of_table = true,
+        }
+    }
+
+    fn position_at_or_before(&mut self, key: u32) {
+        let root_page_num = self.table.root_page_num;
+
+        match find_last_at_or_before(self.table, root_page_num, key) {
+            Some((page_num, cell_num, cell_key)) => {
+                self.page_num = page_num;
+                self.cell_num = cell_num;
+                self.key = cell_key;
+                self.generation = self.table.pager.page_generation(page_num as usize);
+                self.end_of_table = false;
             }
+            None => self.end_of_table = true,
         }
     }
 
     pub fn advance_cursor(&mut self) {
-        let page_num = self.page_num;
-        self.cell_num = self.cell_num + 1;
+        let key = self.key;
+        let was_on_key = self.reposition_if_stale();
+
+        // a deleted key leaves the repositioned cursor on the next key already
+        if was_on_key || self.key == key {
+            self.cell_num += 1;
+        }
+        self.skip_exhausted_leaves();
+    }
+
+    /**
+     * Moves the cursor back to its key if its page changed since the cursor was positioned.
+     * Lands on the next larger key when the key itself was deleted. Returns whether the
+     * cursor is still on the cell it was on before, unmoved.
+     */
+    fn reposition_if_stale(&mut self) -> bool {
+        let generation = self.table.pager.page_generation(self.page_num as usize);
+        if self.end_of_table || generation == self.generation {
+            return true;
+        }
+
+        let cursor = Cursor::table_find(self.table, self.key);
+        let (page_num, cell_num, generation) =
+            (cursor.page_num, cursor.cell_num, cursor.generation);
+
+        self.page_num = page_num;
+        self.cell_num = cell_num;
+        self.generation = generation;
+        self.skip_exhausted_leaves();
+
+        false
+    }
+
+    /**
+     * Moves past the end of the current leaf and past leaves emptied by deletes, to the next
+     * cell holding a row
+     */
+    fn skip_exhausted_leaves(&mut self) {
+        loop {
+            let node = self
+                .table
+                .pager
+                .get_page_leaf(self.page_num as usize)
+                .unwrap();
+            if self.cell_num < node.num_cells() {
+                self.key = node.get_cell_key(self.cell_num);
+                return;
+            }
 
-        let node = self.table.pager.get_page_leaf(page_num as usize).unwrap();
-        if self.cell_num >= node.num_cells {
             // advance to next leaf node
-            let next_page_num = node.next_leaf;
+            let next_page_num = node.next_leaf();
 
             if next_page_num == 0 {
                 self.end_of_table = true;
-            } else {
-                self.page_num = next_page_num;
-                self.cell_num = 0;
+                return;
             }
+
+            self.page_num = next_page_num;
+            self.cell_num = 0;
+            self.generation = self.table.pager.page_generation(next_page_num as usize);
         }
     }
 
-    pub fn get_cursor_value(cursor: &mut Cursor) -> Result<*mut u8, &'static str> {
+    /**
+     * Key of the cell under the cursor, or None when the cursor is past the last cell of its
+     * leaf. Reads only the key, not the row
+     */
+    pub fn get_cursor_key(&mut self) -> Option<u32> {
+        self.reposition_if_stale();
+
+        let node = self
+            .table
+            .pager
+            .get_page_leaf(self.page_num as usize)
+            .unwrap();
+
+        if self.cell_num < node.num_cells() {
+            Some(node.get_cell_key(self.cell_num))
+        } else {
+            None
+        }
+    }
+
+    /**
+     * Iterates over the keys from the cursor to the end of the table without deserializing rows
+     */
+    pub fn keys(self) -> Keys<'a> {
+        Keys { cursor: self }
+    }
+
+    pub fn get_cursor_value<'b>(cursor: &'b mut Cursor) -> Result<&'b mut [u8], &'static str> {
+        cursor.reposition_if_stale();
+
         let page_num = cursor.page_num as usize;
 
-        match cursor.table.pager.get_page_node_type(page_num) {
+        match cursor.table.pager.get_page_node_type(page_num)? {
             NodeType::Leaf => {
                 let node = cursor.table.pager.get_page_leaf(page_num).unwrap();
-                return Ok(node.get_cell_value(cursor.cell_num));
+                Ok(node.get_cell_value(cursor.cell_num))
             }
             NodeType::Internal => {
                 panic!("Trying to fetch value of an internal node");
@@ -84,3 +235,56 @@ impl<'a> Cursor<'a> {
         }
     }
 }
+
+/**
+ * Page, cell and key of the largest key not greater than key below page_num. Children left
+ * of the search path only hold smaller keys, so the first of them, from the right, holding
+ * any key has the answer. Leaves emptied by deletes are passed over that way.
+ */
+pub(crate) fn find_last_at_or_before(
+    table: &mut Table,
+    page_num: u32,
+    key: u32,
+) -> Option<(u32, u32, u32)> {
+    match table.pager.get_page_node_type(page_num as usize).unwrap() {
+        NodeType::Leaf => {
+            let node = table.pager.get_page_leaf(page_num as usize).unwrap();
+            let mut cell_count = node.lower_bound(key);
+            if cell_count < node.num_cells() && node.get_cell_key(cell_count) == key {
+                cell_count += 1;
+            }
+
+            let cell_num = cell_count.checked_sub(1)?;
+            Some((page_num, cell_num, node.get_cell_key(cell_num)))
+        }
+        NodeType::Internal => {
+            let node = table.pager.get_page_internal(page_num as usize).unwrap();
+            let child_index = node.find_child_index(key);
+            let children: Vec<u32> = (0..=child_index).map(|i| node.get_child(i)).collect();
+
+            children
+                .into_iter()
+                .rev()
+                .find_map(|child_page_num| find_last_at_or_before(table, child_page_num, key))
+        }
+    }
+}
+
+pub struct Keys<'a> {
+    cursor: Cursor<'a>,
+}
+
+impl Iterator for Keys<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.cursor.end_of_table {
+            return None;
+        }
+
+        let key = self.cursor.get_cursor_key();
+        self.cursor.advance_cursor();
+
+        key
+    }
+}
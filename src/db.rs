@@ -1,25 +1,111 @@
-use crate::leaf_node::LeafNode;
+use crate::bloom::BloomFilter;
+use crate::cancel::{CancellationToken, ProgressHandler, StatementInterrupt};
+use crate::changes::{ChangeEvent, ChangeLog, ChangeOp};
+use crate::error::DbError;
+use crate::export::{ParquetExporter, SnapshotWriter};
+use crate::internal_node::{InternalView, INTERNAL_NODE_MAX_CELLS};
+use crate::leaf_node::{DuplicatePolicy, LeafView, LEAF_NODE_MAX_CELLS};
+use crate::metrics::{trace_span, Metrics};
+use crate::row_meta::{RowMeta, RowMetaStore};
+use crate::settings::{OutputMode, Settings};
 use crate::{cursor, pager};
 
 use cursor::Cursor;
-use log::info;
+use log::{error, info};
 
-use std::io::{stdin, stdout, Write};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::{stdin, stdout, BufRead, Write};
 use std::mem;
-use std::os::unix::fs::FileExt;
+use std::process;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use pager::Pager;
 use pager::PAGE_SIZE;
+use pager::{NodeType, Pager, TABLE_MAX_PAGES};
 
 enum StatementType {
     Select,
+    SelectCount,
     Insert,
+    Update,
+    Delete,
+    Truncate,
     PrintTree,
+    // select ... for delete limit <count>, takes the rows it returns off the table
+    SelectForDelete,
 }
 
 struct Statement {
     statement_type: StatementType,
     row_to_insert: Row,
+    // columns returned by a select, in the order they were requested
+    columns: Vec<Column>,
+    // select distinct
+    distinct: bool,
+    // where id in (...)
+    key_filter: Option<KeyFilter>,
+    // explain select, returns how the rows would be found instead of the rows
+    explain: bool,
+    // attached database named by the statement, None for the main database
+    database: Option<String>,
+    // order by id desc
+    descending: bool,
+    // most rows a select returns
+    limit: Option<usize>,
+    // where id between <first> and <last>, or where id = <key> as <key>..=<key>
+    key_range: Option<(u32, u32)>,
+    // update ... set <column> = <value>, ...
+    assignments: Vec<(Column, String)>,
+    // update ... where id = <key> and _version = <version>
+    expected_version: Option<u64>,
+    // insert, update or delete ... returning <column>, ..., columns of the written rows
+    // handed back as rows
+    returning: Option<Vec<Column>>,
+}
+
+impl Statement {
+    fn new() -> Statement {
+        Statement {
+            statement_type: StatementType::Select,
+            row_to_insert: Row {
+                id: 0,
+                username: "".to_string(),
+                email: "".to_string(),
+            },
+            columns: Column::ALL.to_vec(),
+            distinct: false,
+            key_filter: None,
+            explain: false,
+            database: None,
+            descending: false,
+            limit: None,
+            key_range: None,
+            assignments: vec![],
+            expected_version: None,
+            returning: None,
+        }
+    }
+}
+
+enum KeyFilter {
+    // where id in (1, 2, 3)
+    Keys(Vec<u32>),
+    // where id in (select id ...)
+    Subquery(Box<Statement>),
+}
+
+/**
+ * How the rows of a key filter are found
+ */
+#[derive(Debug, PartialEq, Eq)]
+enum KeyLookup {
+    // descend from the root once per key
+    Seek,
+    // descend to the first key, then follow next_leaf up to the last one
+    Scan,
 }
 
 const MAX_STRING_SIZE: usize = 64;
@@ -31,358 +117,3358 @@ const ID_OFFSET: usize = 0;
 const USERNAME_OFFSET: usize = ID_OFFSET + ID_SIZE;
 const EMAIL_OFFSET: usize = USERNAME_OFFSET + USERNAME_SIZE;
 
-pub const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
-pub const ROWS_PER_PAGE: u32 = PAGE_SIZE as u32 / ROW_SIZE as u32;
+pub const ROW_SIZE: usize = ID_SIZE + USERNAME_SIZE + EMAIL_SIZE;
+pub const ROWS_PER_PAGE: u32 = PAGE_SIZE as u32 / ROW_SIZE as u32;
+
+// name of the single table in each db file, reported in change events
+pub const TABLE_NAME: &str = "users";
+// name of the database a Db was opened with, tables of attached databases are named
+// <database>.users
+pub const MAIN_DATABASE: &str = "main";
+// rows shown in each part of .peek when no count is given
+const PEEK_ROWS: usize = 5;
+
+pub struct Table {
+    pub root_page_num: u32,
+    pub pager: Pager,
+    // share of cells kept in a leaf split by an ascending insert
+    pub(crate) fill_factor: f64,
+    changes: ChangeLog,
+    // hidden columns of the rows, only kept when a db is opened with a ttl
+    row_meta: Option<Box<RowMetaStore>>,
+    // keys of the table, only kept when a db is opened with bloom_filter
+    bloom_filter: Option<BloomFilter>,
+    // rightmost leaf and its page generation when an insert last appended to it
+    append_leaf: Option<(u32, u32)>,
+}
+
+impl Table {
+    pub(crate) fn open(file_descriptor: String, options: &DbOptions) -> Result<Self, DbError> {
+        let pager = Pager::open_file(file_descriptor, options)?;
+
+        Ok(Table {
+            root_page_num: 0,
+            pager,
+            fill_factor: options.fill_factor.clamp(0.5, 1.0),
+            changes: ChangeLog::default(),
+            row_meta: None,
+            bloom_filter: None,
+            append_leaf: None,
+        })
+    }
+
+    /**
+     * Flushes every change to disk and releases the db file lock, then publishes the changes
+     * to subscribers
+     */
+    pub(crate) fn commit(&mut self) -> Result<(), &'static str> {
+        self.pager.flush()?;
+        self.pager.unlock_file();
+        self.changes.publish();
+
+        Ok(())
+    }
+
+    /**
+     * Keys of the rows whose ttl ran out, which reads skip until they are purged
+     */
+    pub(crate) fn expired_keys(&mut self) -> Result<HashSet<u32>, &'static str> {
+        match &mut self.row_meta {
+            Some(row_meta) => row_meta.expired_keys(),
+            None => Ok(HashSet::new()),
+        }
+    }
+
+    /**
+     * Largest key in the table, None when it is empty. Read from the root, which caches the
+     * max key of its subtree, without walking to the last leaf
+     */
+    pub fn max_key(&mut self) -> Option<u32> {
+        if self.pager.row_count() == 0 {
+            return None;
+        }
+
+        let root_page_num = self.root_page_num as usize;
+        match self.pager.get_page_node_type(root_page_num).unwrap() {
+            NodeType::Internal => Some(
+                self.pager
+                    .get_page_internal(root_page_num)
+                    .unwrap()
+                    .max_key(),
+            ),
+            NodeType::Leaf => {
+                let node = self.pager.get_page_leaf(root_page_num).unwrap();
+                node.num_cells()
+                    .checked_sub(1)
+                    .map(|cell_num| node.get_cell_key(cell_num))
+            }
+        }
+    }
+
+    /**
+     * Smallest and largest key in the table, None when it is empty. The largest is read from
+     * the root like max_key and the smallest from the first leaf, so only the leftmost path
+     * of the tree is read.
+     */
+    pub fn key_range(&mut self) -> Option<(u32, u32)> {
+        let max_key = self.max_key()?;
+        let min_key = Cursor::table_start(self).get_cursor_key()?;
+
+        Some((min_key, max_key))
+    }
+
+    /**
+     * Number of rows from the count kept in the table header, without a scan. Rows whose ttl
+     * ran out are counted until they are purged.
+     */
+    pub fn approx_row_count(&self) -> u32 {
+        self.pager.row_count()
+    }
+
+    /**
+     * Child pages whose parent pointer does not name the internal node pointing at them, as
+     * (page_num, stored parent, actual parent). The actual parents are found by a descent from
+     * the root.
+     */
+    pub(crate) fn misplaced_parent_pointers(&mut self) -> Vec<(u32, u32, u32)> {
+        let mut misplaced = vec![];
+        let mut pending = vec![self.root_page_num];
+
+        while let Some(page_num) = pending.pop() {
+            if let NodeType::Leaf = self.pager.get_page_node_type(page_num as usize).unwrap() {
+                continue;
+            }
+
+            let node = self.pager.get_page_internal(page_num as usize).unwrap();
+            // a node without children yet
+            if node.right_child() == 0 {
+                continue;
+            }
+            let children: Vec<u32> = (0..=node.num_keys()).map(|i| node.get_child(i)).collect();
+
+            for child_page_num in children {
+                let parent = match self
+                    .pager
+                    .get_page_node_type(child_page_num as usize)
+                    .unwrap()
+                {
+                    NodeType::Leaf => self
+                        .pager
+                        .get_page_leaf(child_page_num as usize)
+                        .unwrap()
+                        .parent(),
+                    NodeType::Internal => self
+                        .pager
+                        .get_page_internal(child_page_num as usize)
+                        .unwrap()
+                        .parent(),
+                };
+
+                if parent != page_num {
+                    misplaced.push((child_page_num, parent, page_num));
+                }
+                pending.push(child_page_num);
+            }
+        }
+
+        misplaced
+    }
+
+    /**
+     * Pages of the internal nodes depth levels below the root, from left to right. Descends
+     * only to the leftmost node of the level and follows the sibling links from there. Empty
+     * when that level holds leaves.
+     */
+    pub fn internal_level(&mut self, depth: u32) -> Vec<u32> {
+        let mut page_num = self.root_page_num;
+
+        for _ in 0..depth {
+            if let NodeType::Leaf = self.pager.get_page_node_type(page_num as usize).unwrap() {
+                return vec![];
+            }
+            let node = self.pager.get_page_internal(page_num as usize).unwrap();
+            if node.right_child() == 0 {
+                return vec![];
+            }
+            page_num = node.get_child(0);
+        }
+
+        if let NodeType::Leaf = self.pager.get_page_node_type(page_num as usize).unwrap() {
+            return vec![];
+        }
+
+        let mut pages = vec![page_num];
+        // a broken link could point back into the level, or at a leaf, which ends the level
+        while pages.len() < TABLE_MAX_PAGES {
+            page_num = match self.pager.get_page_internal(page_num as usize) {
+                Ok(node) => node.next_sibling(),
+                Err(_) => break,
+            };
+            if page_num == 0 {
+                break;
+            }
+            pages.push(page_num);
+        }
+
+        pages
+    }
+
+    /**
+     * Points every child page back at the internal node pointing at it. Splits keep parent
+     * pointers up to date, this fixes files where they went stale. Returns the number of
+     * pages fixed.
+     */
+    pub fn repair_parent_pointers(&mut self) -> usize {
+        let misplaced = self.misplaced_parent_pointers();
+
+        for &(page_num, _, parent) in &misplaced {
+            let page_num = page_num as usize;
+            self.pager.mark_dirty(page_num);
+
+            match self.pager.get_page_node_type(page_num).unwrap() {
+                NodeType::Leaf => self
+                    .pager
+                    .get_page_leaf(page_num)
+                    .unwrap()
+                    .set_parent(parent),
+                NodeType::Internal => self
+                    .pager
+                    .get_page_internal(page_num)
+                    .unwrap()
+                    .set_parent(parent),
+            }
+        }
+
+        misplaced.len()
+    }
+
+    /**
+     * Leaf that keys past max_key are inserted into, None unless the last append went to it
+     * and it hasn't changed since
+     */
+    fn append_leaf(&mut self) -> Option<u32> {
+        let (page_num, generation) = self.append_leaf?;
+
+        (self.pager.page_generation(page_num as usize) == generation).then_some(page_num)
+    }
+
+    /**
+     * Remembers the rightmost leaf after appending to page_num, which is one leaf short of it
+     * when the append split the leaf. A split root leaf became an internal node, the next
+     * append finds the rightmost leaf with a descent instead.
+     */
+    fn remember_append_leaf(&mut self, mut page_num: u32) {
+        self.append_leaf = None;
+        if !matches!(
+            self.pager.get_page_node_type(page_num as usize),
+            Ok(NodeType::Leaf)
+        ) {
+            return;
+        }
+
+        loop {
+            let next_leaf = self
+                .pager
+                .get_page_leaf(page_num as usize)
+                .unwrap()
+                .next_leaf();
+            if next_leaf == 0 {
+                break;
+            }
+            page_num = next_leaf;
+        }
+
+        let generation = self.pager.page_generation(page_num as usize);
+        self.append_leaf = Some((page_num, generation));
+    }
+
+    /**
+     * Inserts rows in key order, descending from the root once for every leaf they go to
+     * instead of once per row. The rows routed to a leaf are inserted one after the other
+     * until one of them splits it, the rows after that are routed again. Fails before
+     * inserting anything when a key repeats within the batch or is already in the table, when
+     * a string is too long, or when the splits the rows could cause would grow the db file
+     * past its quota. The rows are saved with the next commit of the db, like the inserts run
+     * through Db::execute. Returns the number of inserted rows.
+     */
+    pub fn insert_batch(&mut self, mut rows: Vec<Row>) -> Result<usize, &'static str> {
+        rows.sort_by_key(|row| row.id);
+        if rows.windows(2).any(|pair| pair[0].id == pair[1].id) {
+            return Err("Duplicate key detected");
+        }
+        if rows
+            .iter()
+            .any(|row| row.username.len() > MAX_STRING_SIZE || row.email.len() > MAX_STRING_SIZE)
+        {
+            return Err("String is too long");
+        }
+        if rows.iter().any(|row| self.contains_key(row.id)) {
+            return Err("Duplicate key detected");
+        }
+        // each row may split its leaf and every node above it, like check_insert_quota counts
+        if self.pager.max_pages().is_some() {
+            let split_pages = (tree_height(self) as u32 + 1).saturating_mul(rows.len() as u32);
+            self.pager.check_quota(split_pages)?;
+        }
+
+        let row_count = rows.len();
+        let mut rows = rows.iter().peekable();
+        while let Some(row) = rows.peek() {
+            let (page_num, upper_bound) = self.find_leaf(row.id);
+
+            while let Some(row) =
+                rows.next_if(|row| upper_bound.is_none_or(|upper_bound| row.id <= upper_bound))
+            {
+                let leaf = self.pager.get_page_leaf(page_num as usize).unwrap();
+                let splits = leaf.num_cells() as usize >= LEAF_NODE_MAX_CELLS;
+
+                let mut cursor = LeafView::node_find(self, page_num, row.id);
+                LeafView::insert(&mut cursor, row.id, row, DuplicatePolicy::Reject)?;
+                record_insert(self, row)?;
+
+                // the split moved cells to a new leaf, whose bound isn't known yet
+                if splits {
+                    break;
+                }
+            }
+        }
+
+        Ok(row_count)
+    }
+
+    /**
+     * Leaf that key is routed to, with the largest key routed to it: the key of the nearest
+     * internal cell it is below, None for the rightmost leaf
+     */
+    fn find_leaf(&mut self, key: u32) -> (u32, Option<u32>) {
+        let mut page_num = self.root_page_num;
+        let mut upper_bound = None;
+
+        loop {
+            match self.pager.get_page_node_type(page_num as usize).unwrap() {
+                NodeType::Internal => {
+                    let node = self.pager.get_page_internal(page_num as usize).unwrap();
+                    let child_index = node.find_child_index(key);
+                    if child_index < node.num_keys() {
+                        upper_bound = Some(node.cell(child_index).0);
+                    }
+                    page_num = node.get_child(child_index);
+                }
+                NodeType::Leaf => return (page_num, upper_bound),
+            }
+        }
+    }
+
+    /**
+     * Builds the bloom filter from a scan of every key in the table
+     */
+    fn build_bloom_filter(&mut self) {
+        let mut bloom_filter = BloomFilter::new(self.pager.reloads());
+        for key in Cursor::table_start(self).keys() {
+            bloom_filter.insert(key);
+        }
+
+        self.bloom_filter = Some(bloom_filter);
+    }
+
+    /**
+     * False when the bloom filter rules out a row under key, without reading a page. Always
+     * true without a bloom filter. A filter that misses the writes of another connection is
+     * built again first.
+     */
+    pub(crate) fn may_contain_key(&mut self, key: u32) -> bool {
+        match &self.bloom_filter {
+            None => return true,
+            Some(bloom_filter) if bloom_filter.reloads != self.pager.reloads() => {
+                self.build_bloom_filter()
+            }
+            Some(_) => {}
+        }
+
+        self.bloom_filter
+            .as_ref()
+            .is_none_or(|bloom_filter| bloom_filter.may_contain(key))
+    }
+
+    /**
+     * Whether a row is stored under key. Descends from the root comparing only keys, without
+     * positioning a cursor or reading the row
+     */
+    pub fn contains_key(&mut self, key: u32) -> bool {
+        if !self.may_contain_key(key) {
+            return false;
+        }
+
+        let mut page_num = self.root_page_num as usize;
+
+        loop {
+            match self.pager.get_page_node_type(page_num).unwrap() {
+                NodeType::Internal => {
+                    let node = self.pager.get_page_internal(page_num).unwrap();
+                    page_num = node.get_child(node.find_child_index(key)) as usize;
+                }
+                NodeType::Leaf => {
+                    let node = self.pager.get_page_leaf(page_num).unwrap();
+                    let cell_num = node.lower_bound(key);
+                    return cell_num < node.num_cells() && node.get_cell_key(cell_num) == key;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Row {
+    pub id: u32,
+    pub username: String,
+    pub email: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    Id,
+    Username,
+    Email,
+    // hidden columns kept by the engine, selected with _meta(<name>)
+    ExpiresAt,
+    CreatedAt,
+    UpdatedAt,
+    TxnId,
+    Version,
+    // a line of the tree printed by print_tree, not selectable
+    Tree,
+    // columns of the rows returned by show statements, not selectable
+    Name,
+    Value,
+    Table,
+    Rows,
+    Pages,
+    Columns,
+    // the row returned by select count(*), not selectable
+    Count,
+    // how explain select would find the rows, not selectable
+    Plan,
+}
+
+impl Column {
+    pub const ALL: [Column; 3] = [Column::Id, Column::Username, Column::Email];
+    pub const META: [Column; 5] = [
+        Column::ExpiresAt,
+        Column::CreatedAt,
+        Column::UpdatedAt,
+        Column::TxnId,
+        Column::Version,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Id => "id",
+            Column::Username => "username",
+            Column::Email => "email",
+            Column::ExpiresAt => "expires_at",
+            Column::CreatedAt => "created_at",
+            Column::UpdatedAt => "updated_at",
+            Column::TxnId => "txn_id",
+            Column::Version => "version",
+            Column::Tree => "tree",
+            Column::Name => "name",
+            Column::Value => "value",
+            Column::Table => "table",
+            Column::Rows => "rows",
+            Column::Pages => "pages",
+            Column::Columns => "columns",
+            Column::Count => "count",
+            Column::Plan => "plan",
+        }
+    }
+
+    /**
+     * Column named name, or the hidden column named in `_meta(<name>)`
+     */
+    pub fn from_name(name: &str) -> Option<Column> {
+        match name
+            .strip_prefix("_meta(")
+            .and_then(|name| name.strip_suffix(')'))
+        {
+            Some(name) => Column::META
+                .into_iter()
+                .find(|column| column.name() == name.trim()),
+            None => Column::ALL.into_iter().find(|column| column.name() == name),
+        }
+    }
+
+    pub fn is_meta(&self) -> bool {
+        Column::META.contains(self)
+    }
+}
+
+/**
+ * Receives the rows of a select, each as the selected columns with their values as text
+ */
+pub type RowSink<'a> = &'a mut dyn FnMut(&[(Column, String)]);
+
+pub struct Db {
+    pub table: Arc<Mutex<Table>>,
+    // databases added with attach, by name. Each has its own pager and page cache.
+    attached: HashMap<String, Arc<Mutex<Table>>>,
+    // every database attached through this handle or its clones, saved by the exit signal
+    // handler along with the table
+    exit_tables: Arc<Mutex<Vec<Weak<Mutex<Table>>>>>,
+    // set when writes are committed in groups by a background thread
+    commit_queue: Option<Sender<()>>,
+    max_statement_duration: Option<Duration>,
+    progress_handler: Option<ProgressHandler>,
+    // options the db was opened with, the attach statement opens attached databases with them
+    options: DbOptions,
+    settings: Settings,
+    // input ended inside a /* block comment */ that continues on the next line
+    in_block_comment: bool,
+    // rows changed by the last statement that succeeded
+    rows_affected: u32,
+}
+
+#[derive(Clone)]
+pub struct DbOptions {
+    /// How long a write may wait so it can be committed together with the writes that follow
+    /// it. Zero commits (flushes and syncs) every write statement on its own.
+    pub commit_window: Duration,
+    /// Encrypts every page of the db file with a key derived from this passphrase. Needs the
+    /// encryption feature.
+    pub passphrase: Option<String>,
+    /// Reads pages through a memory map of the db file instead of a read syscall per page.
+    /// Needs the mmap feature.
+    pub mmap: bool,
+    /// Statements still running after this long are cancelled. None lets them run to the end.
+    pub max_statement_duration: Option<Duration>,
+    /// Reports progress of statements that visit many pages and lets the caller abort them.
+    pub progress_handler: Option<ProgressHandler>,
+    /// Most pages kept in the page cache between statements. None caches every page.
+    pub cache_size: Option<usize>,
+    /// Share of cells, between 0.5 and 1, left in a leaf that splits because keys are
+    /// inserted in ascending order. Leaves split by other inserts are split in half. Only
+    /// takes effect when leaves hold more than one cell: at the current page size
+    /// (LEAF_NODE_MAX_CELLS is 1) every split leaves one cell on each side.
+    pub fill_factor: f64,
+    /// How long a statement waits for another connection to the same db file to finish its
+    /// write before failing with DbError::Busy.
+    pub busy_timeout: Duration,
+    /// Rows expire this long after they were inserted. Expired rows are skipped by reads and
+    /// removed by Db::purge_expired. Expiry times are kept in `<db file>.meta`, rows inserted
+    /// while the db was opened without a ttl never expire.
+    pub ttl: Option<Duration>,
+    /// Keeps the hidden created_at, updated_at and txn_id columns of every row written from
+    /// now on, read with `select _meta(<column>)`. Kept in `<db file>.meta` like expiry times.
+    pub row_metadata: bool,
+    /// Writes the pages of every commit to `<db file>.dwb` and syncs them there before they
+    /// overwrite their place in the db file, so pages torn by a crash mid-write are restored
+    /// when the db is opened again. Costs a second write and sync per commit.
+    pub double_write: bool,
+    /// Keeps a bloom filter of the keys in memory, built with a scan when the db is opened,
+    /// so looking up a key that isn't there (a select or delete by key, or the duplicate check
+    /// of an insert) mostly skips the descent from the root.
+    pub bloom_filter: bool,
+    /// Most pages the db file may hold. Inserts that could split their way past it fail with
+    /// DbError::QuotaExceeded before touching the tree, so the quota is reached a few pages
+    /// early. The `.meta` and `.dwb` files beside it aren't counted. None lets it grow up to
+    /// the table page limit.
+    pub max_pages: Option<u32>,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        DbOptions {
+            commit_window: Duration::ZERO,
+            passphrase: None,
+            mmap: false,
+            max_statement_duration: None,
+            progress_handler: None,
+            cache_size: None,
+            fill_factor: 0.9,
+            busy_timeout: Duration::ZERO,
+            ttl: None,
+            row_metadata: false,
+            double_write: false,
+            bloom_filter: false,
+            max_pages: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExecuteResponse {
+    Success,
+    Exit,
+}
+
+/**
+ * Space a table takes up on disk, from Db::table_sizes
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableSize {
+    // users, or <database>.users for the table of an attached database
+    pub name: String,
+    // pages reachable from the root
+    pub tree_pages: u32,
+    // pages in the db file outside the tree, freed by deletes and handed out again by inserts
+    pub unused_pages: u32,
+    // length of the db file, with its header
+    pub file_bytes: u64,
+    // length of `<db file>.meta`, 0 when the db keeps no row metadata
+    pub row_meta_bytes: u64,
+}
+
+/**
+ * What a statement run through Db::execute_for_test returned, for assertions in tests
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatementOutcome {
+    pub response: Result<ExecuteResponse, String>,
+    // rows returned by a select, each as the selected columns with their values as text
+    pub rows: Vec<Vec<(Column, String)>>,
+}
+
+impl StatementOutcome {
+    pub fn is_ok(&self) -> bool {
+        self.response.is_ok()
+    }
+
+    pub fn is_err(&self) -> bool {
+        self.response.is_err()
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.response.as_ref().err().map(String::as_str)
+    }
+
+    /**
+     * Values of every row without their column names
+     */
+    pub fn values(&self) -> Vec<Vec<&str>> {
+        self.rows
+            .iter()
+            .map(|row| row.iter().map(|(_, value)| value.as_str()).collect())
+            .collect()
+    }
+}
+
+impl Db {
+    /**
+     * Opens the db like Db::open with the default options, panicking if it can't be opened
+     */
+    pub fn new(file_descriptor: String) -> Db {
+        Db::new_with_options(file_descriptor, DbOptions::default())
+    }
+
+    /**
+     * Opens the db like Db::open, panicking if it can't be opened
+     */
+    pub fn new_with_options(file_descriptor: String, options: DbOptions) -> Db {
+        Db::open(file_descriptor, options).unwrap()
+    }
+
+    /**
+     * Opens the db file at file_descriptor, creating it if it doesn't exist. Fails when the
+     * file can't be read as a db, with the wrong passphrase or without one for an encrypted
+     * file, or when upgrading or restoring it fails.
+     */
+    pub fn open(file_descriptor: String, options: DbOptions) -> Result<Db, DbError> {
+        let table = Arc::new(Mutex::new(open_table(file_descriptor, &options)?));
+
+        let commit_queue = if options.commit_window.is_zero() {
+            None
+        } else {
+            Some(spawn_committer(
+                Arc::downgrade(&table),
+                options.commit_window,
+            ))
+        };
+
+        Ok(Db {
+            table,
+            attached: HashMap::new(),
+            exit_tables: Arc::new(Mutex::new(vec![])),
+            commit_queue,
+            max_statement_duration: options.max_statement_duration,
+            progress_handler: options.progress_handler.clone(),
+            options,
+            settings: Settings::default(),
+            in_block_comment: false,
+            rows_affected: 0,
+        })
+    }
+
+    pub fn run_db(&mut self) {
+        info!("Initialized QBA-DB version 0.0.1");
+
+        loop {
+            print_prompt();
+            let mut user_input = String::new();
+            let _ = stdout().flush();
+            let bytes_read = stdin()
+                .read_line(&mut user_input)
+                .expect("Did not enter a correct string");
+            if bytes_read == 0 {
+                // stdin was closed
+                return;
+            }
+            if let Some('\n') = user_input.chars().next_back() {
+                user_input.pop();
+            }
+            if let Some('\r') = user_input.chars().next_back() {
+                user_input.pop();
+            }
+
+            match self.execute(&user_input) {
+                Ok(ExecuteResponse::Success) => {}
+                Ok(ExecuteResponse::Exit) => return,
+                Err(e) => info!("{}", e),
+            }
+        }
+    }
+
+    /**
+     * Runs every statement read from `reader` without printing prompts, used when stdin is
+     * not a terminal. Stops at the first failing statement unless `continue_on_error` is set.
+     * Returns the number of statements that failed.
+     */
+    pub fn run_script<R: BufRead>(&mut self, reader: R, continue_on_error: bool) -> usize {
+        let mut failed_statements = 0;
+
+        for line in reader.lines() {
+            let user_input = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Error reading statement: {}", e);
+                    return failed_statements + 1;
+                }
+            };
+
+            match self.execute(user_input.trim_end_matches('\r')) {
+                Ok(ExecuteResponse::Success) => {}
+                Ok(ExecuteResponse::Exit) => break,
+                Err(e) => {
+                    error!("{}", e);
+                    failed_statements += 1;
+
+                    if !continue_on_error {
+                        break;
+                    }
+                }
+            }
+        }
+
+        failed_statements
+    }
+
+    /**
+     * Runs every statement read from `reader` like run_script with continue_on_error, but
+     * returns a transcript instead of logging: each statement after the prompt, followed by
+     * the rows it returned in the current output mode or its error. Output of meta commands
+     * is still logged. Used by the golden-file tests.
+     */
+    pub fn run_script_transcript<R: BufRead>(&mut self, reader: R) -> String {
+        let mut transcript = String::new();
+
+        for line in reader.lines() {
+            let user_input = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    transcript.push_str(&format!("Error reading statement: {}\n", e));
+                    break;
+                }
+            };
+            let user_input = user_input.trim_end_matches('\r');
+            transcript.push_str(&format!("qba-db> {}\n", user_input));
+
+            let output = self.settings.output;
+            let mut rows = String::new();
+            let result =
+                self.execute_with_rows(user_input, &CancellationToken::new(), &mut |values| {
+                    rows.push_str(&format_row(values, output));
+                    rows.push('\n');
+                });
+            transcript.push_str(&rows);
+
+            match result {
+                Ok(ExecuteResponse::Success) => {}
+                Ok(ExecuteResponse::Exit) => break,
+                Err(e) => transcript.push_str(&format!("Error: {}\n", e)),
+            }
+        }
+
+        transcript
+    }
+
+    /**
+     * Rows changed by the last statement that succeeded, 0 for statements that only read.
+     * An update whose version check failed changed none.
+     */
+    pub fn rows_affected(&self) -> u32 {
+        self.rows_affected
+    }
+
+    /**
+     * Executes a statement, collecting the rows it returns along with its result instead of
+     * logging them
+     */
+    pub fn execute_for_test(&mut self, user_input: &str) -> StatementOutcome {
+        let mut rows = vec![];
+        let response =
+            self.execute_with_rows(user_input, &CancellationToken::new(), &mut |values| {
+                rows.push(values.to_vec())
+            });
+
+        StatementOutcome { response, rows }
+    }
+
+    pub fn run_db_test(&mut self, user_input: String) {
+        // info!("Executing statement: {}", user_input);
+
+        if let Err(e) = self.execute(&user_input) {
+            info!("{}", e);
+        }
+    }
+
+    pub fn execute(&mut self, user_input: &str) -> Result<ExecuteResponse, String> {
+        self.execute_with_cancel(user_input, &CancellationToken::new())
+    }
+
+    /**
+     * Executes a statement that can be cancelled through the token from another thread
+     */
+    pub fn execute_with_cancel(
+        &mut self,
+        user_input: &str,
+        token: &CancellationToken,
+    ) -> Result<ExecuteResponse, String> {
+        let output = self.settings.output;
+        self.execute_with_rows(user_input, token, &mut |values| {
+            info!("{}", format_row(values, output))
+        })
+    }
+
+    /**
+     * Executes a statement, running it again while another connection holds a conflicting
+     * lock on the db file. Waits backoff before the first retry and twice as long before
+     * each retry after it. Fails with DbError::Busy once retries run out.
+     */
+    pub fn execute_retrying_busy(
+        &mut self,
+        user_input: &str,
+        retries: u32,
+        backoff: Duration,
+    ) -> Result<ExecuteResponse, String> {
+        let busy = DbError::Busy.to_string();
+        let in_block_comment = self.in_block_comment;
+        let mut backoff = backoff;
+
+        for _ in 0..retries {
+            match self.execute(user_input) {
+                Err(message) if message == busy => {
+                    // strip the comments of the same line again on the next attempt
+                    self.in_block_comment = in_block_comment;
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+
+        self.execute(user_input)
+    }
+
+    /**
+     * Executes a statement, handing every row a select returns to on_row instead of
+     * printing it
+     */
+    pub fn execute_with_rows(
+        &mut self,
+        user_input: &str,
+        token: &CancellationToken,
+        on_row: RowSink,
+    ) -> Result<ExecuteResponse, String> {
+        let started_at = Instant::now();
+        let interrupt = StatementInterrupt::new(
+            token,
+            self.max_statement_duration,
+            self.progress_handler.as_ref(),
+        );
+
+        let user_input = strip_comments(user_input, &mut self.in_block_comment);
+        let user_input = user_input.trim();
+
+        if user_input.trim().is_empty() {
+            return Ok(ExecuteResponse::Success);
+        }
+
+        if let Some(arguments) = user_input.strip_prefix("attach ") {
+            let Some((file_path, name)) = parse_attach(arguments) else {
+                return Err(format!("Syntax error in statement {}", user_input));
+            };
+
+            let options = self.options.clone();
+            return self
+                .attach(file_path, name, &options)
+                .map(|_| ExecuteResponse::Success);
+        }
+
+        if let Some(subject) = user_input.strip_prefix("show ") {
+            return self
+                .show(subject.trim(), on_row)
+                .map(|_| ExecuteResponse::Success);
+        }
+
+        if user_input.starts_with('.') {
+            return match perform_meta_command(user_input, &self.table, &mut self.settings) {
+                MetaCommandResponse::Success => Ok(ExecuteResponse::Success),
+                MetaCommandResponse::UnrecognizedCommand => {
+                    Err(format!("Unrecognized command {}", user_input))
+                }
+                MetaCommandResponse::Exit => Ok(ExecuteResponse::Exit),
+                MetaCommandResponse::CheckFailed(message) => Err(message),
+                MetaCommandResponse::InvalidSetting(message) => Err(message),
+                MetaCommandResponse::InvalidArgument(message) => Err(message),
+                MetaCommandResponse::LockFailed(message) => Err(message),
+            };
+        }
+
+        // prepare statement
+        let mut cur_statement = Statement::new();
+
+        let prepare_response = {
+            trace_span!("parse");
+            prepare_statement(user_input, &mut cur_statement)
+        };
+
+        match prepare_response {
+            StatementPrepareResponse::Success => {
+                let is_write = matches!(
+                    cur_statement.statement_type,
+                    StatementType::Insert
+                        | StatementType::Update
+                        | StatementType::Delete
+                        | StatementType::Truncate
+                        | StatementType::SelectForDelete
+                );
+
+                self.resolve_cross_database_subqueries(&mut cur_statement)?;
+
+                let database = cur_statement.database.clone();
+                let mut table = lock_table(self.database_table(database.as_deref())?);
+                table.pager.lock_file(is_write).map_err(|e| e.to_string())?;
+                let metrics_before = table.pager.metrics;
+
+                let result = {
+                    trace_span!("execute");
+                    execute_statement(cur_statement, &mut table, &interrupt, on_row)
+                };
+                let result = result.and_then(|rows_affected| {
+                    match database {
+                        None if is_write => self.commit(&mut table)?,
+                        // writes to attached databases are committed on their own
+                        Some(_) if is_write => table.commit()?,
+                        _ => {}
+                    }
+                    Ok(rows_affected)
+                });
+                table.pager.unlock_file();
+                let rows_affected = result?;
+
+                table.pager.shrink_cache();
+
+                if self.settings.timer {
+                    print_timer(started_at, &metrics_before, &table.pager.metrics);
+                }
+
+                drop(table);
+                self.rows_affected = rows_affected;
+
+                Ok(ExecuteResponse::Success)
+            }
+            StatementPrepareResponse::UnrecognizedCommand => {
+                Err(format!("Unrecognized statement {}", user_input))
+            }
+            StatementPrepareResponse::SyntaxError => {
+                Err(format!("Syntax error in statement {}", user_input))
+            }
+            StatementPrepareResponse::StringTooLong => {
+                Err(format!("String is too long in statement {}", user_input))
+            }
+            StatementPrepareResponse::UnknownColumn => {
+                Err(format!("Unknown column in statement {}", user_input))
+            }
+            StatementPrepareResponse::UnknownTable => {
+                Err(format!("Unknown table in statement {}", user_input))
+            }
+        }
+    }
+
+    /**
+     * Opens the db file at file_path with options as another database of this db, so
+     * statements can reference its table as <name>.users. The attach statement opens it with
+     * the options of this db. commit_window, max_statement_duration and progress_handler are
+     * ignored: writes to attached databases are committed right away, and statements run
+     * with the limits of this db.
+     */
+    pub fn attach(
+        &mut self,
+        file_path: &str,
+        name: &str,
+        options: &DbOptions,
+    ) -> Result<(), String> {
+        if name == MAIN_DATABASE || self.attached.contains_key(name) {
+            return Err(format!("Database {} is already in use", name));
+        }
+
+        let table = open_table(file_path.to_string(), options).map_err(|e| e.to_string())?;
+        let table = Arc::new(Mutex::new(table));
+        self.exit_tables
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::downgrade(&table));
+        self.attached.insert(name.to_string(), table);
+
+        Ok(())
+    }
+
+    fn database_table(&self, database: Option<&str>) -> Result<&Mutex<Table>, String> {
+        match database {
+            None => Ok(&self.table),
+            Some(name) => match self.attached.get(name) {
+                Some(table) => Ok(table),
+                None => Err(format!("No such database {}", name)),
+            },
+        }
+    }
+
+    /**
+     * Table of the db and those of the attached databases, in that order with the attached
+     * ones by name, each with its name as statements reference it
+     */
+    fn tables(&self) -> Vec<(String, &Mutex<Table>)> {
+        let mut names: Vec<&String> = self.attached.keys().collect();
+        names.sort();
+
+        let mut tables = vec![(TABLE_NAME.to_string(), &*self.table)];
+        for name in names {
+            tables.push((format!("{}.{}", name, TABLE_NAME), &*self.attached[name]));
+        }
+
+        tables
+    }
+
+    /**
+     * Space taken up by the table of the db and by those of the attached databases, in that
+     * order with the attached ones by name. Counted from the pages and files as of the last
+     * commit, which costs a walk over every tree.
+     */
+    pub fn table_sizes(&self) -> Result<Vec<TableSize>, &'static str> {
+        self.tables()
+            .into_iter()
+            .map(|(name, table)| Ok(read_table(table, |table| table_size(name, table))?))
+            .collect()
+    }
+
+    /**
+     * Runs `show tables`, `show indexes` or `show status`, handing their rows to on_row like
+     * the rows of a select so clients without the meta commands can read them. Tables and
+     * indexes cover the attached databases too, status is that of the main db file as
+     * `.dbinfo` and `.cache` print it.
+     */
+    fn show(&self, subject: &str, on_row: RowSink) -> Result<(), String> {
+        match subject {
+            "tables" => {
+                for (name, table) in self.tables() {
+                    let (rows, pages) = read_table(table, |table| {
+                        (table.pager.row_count(), table.pager.num_pages)
+                    })
+                    .map_err(|e| e.to_string())?;
+                    on_row(&[
+                        (Column::Name, name),
+                        (Column::Rows, rows.to_string()),
+                        (Column::Pages, pages.to_string()),
+                    ]);
+                }
+            }
+            // each table is a tree keyed by id, there are no secondary indexes
+            "indexes" => {
+                for (name, _) in self.tables() {
+                    on_row(&[
+                        (Column::Table, name),
+                        (Column::Name, String::from("primary key")),
+                        (Column::Columns, Column::Id.name().to_string()),
+                    ]);
+                }
+            }
+            "status" => {
+                let status = read_table(&self.table, |table| {
+                    let mut status = db_info(table);
+                    status.extend(cache_info(table));
+                    status
+                })
+                .map_err(|e| e.to_string())?;
+                for (name, value) in status {
+                    on_row(&[(Column::Name, name.to_string()), (Column::Value, value)]);
+                }
+            }
+            _ => return Err(format!("Unrecognized statement show {}", subject)),
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Replaces subqueries reading another database than their statement with the keys they
+     * select, so each statement only runs against one table
+     */
+    fn resolve_cross_database_subqueries(&self, statement: &mut Statement) -> Result<(), String> {
+        let Some(KeyFilter::Subquery(subquery)) = &mut statement.key_filter else {
+            return Ok(());
+        };
+        self.resolve_cross_database_subqueries(subquery)?;
+
+        if subquery.database != statement.database {
+            let mut table = lock_table(self.database_table(subquery.database.as_deref())?);
+            table.pager.lock_file(false).map_err(|e| e.to_string())?;
+            let keys = resolve_key_filter(statement.key_filter.as_ref().unwrap(), &mut table);
+            table.pager.unlock_file();
+
+            statement.key_filter = Some(KeyFilter::Keys(keys));
+        }
+
+        Ok(())
+    }
+
+    fn commit(&self, table: &mut Table) -> Result<(), &'static str> {
+        match &self.commit_queue {
+            Some(commit_queue) => {
+                // the committer only stops once the db is dropped
+                let _ = commit_queue.send(());
+                Ok(())
+            }
+            None => table.commit(),
+        }
+    }
+
+    /**
+     * Receives an event for every row changed by a write statement, once the write is
+     * committed. Dropping the receiver unsubscribes.
+     */
+    pub fn subscribe_changes(&self) -> Receiver<ChangeEvent> {
+        lock_table(&self.table).changes.subscribe()
+    }
+
+    /**
+     * Reads every row, splitting the key space at the separator keys of the root node and
+     * scanning each part on its own thread with its own page cache. Rows are returned in key
+     * order. Writes wait until the scan is done.
+     */
+    pub fn scan_parallel(&self, n_threads: usize) -> Result<Vec<Row>, &'static str> {
+        let mut table = lock_table(&self.table);
+        // the readers only see what is in the db file, which other connections can't write
+        // until the scan is done
+        table.commit()?;
+        table.pager.lock_file(false)?;
+
+        let result = scan_partitions(&mut table, n_threads);
+        table.pager.unlock_file();
+
+        result
+    }
+
+    /**
+     * Deletes the rows whose ttl ran out, returning how many were deleted. Does nothing for
+     * a db opened without a ttl.
+     */
+    pub fn purge_expired(&mut self) -> Result<usize, String> {
+        let mut table = lock_table(&self.table);
+        table.pager.lock_file(true).map_err(|e| e.to_string())?;
+
+        let expired_keys = table.expired_keys()?;
+        let result = expired_keys
+            .iter()
+            .try_for_each(|&key| delete_key_range(&mut table, key, key).map(|_| ()))
+            .and_then(|_| self.commit(&mut table));
+        table.pager.unlock_file();
+        result?;
+
+        Ok(expired_keys.len())
+    }
+
+    /**
+     * Writes every row a select sees, so not the expired ones, to a Parquet file in key order,
+     * returning the number of rows written. Needs the parquet feature.
+     */
+    pub fn export_parquet(&self, file_path: &str) -> Result<usize, &'static str> {
+        let mut exporter = ParquetExporter::create(file_path)?;
+        let rows = read_table(&self.table, |table| {
+            let expired_keys = table.expired_keys()?;
+            let mut cursor = Cursor::table_start(table);
+            let mut rows = 0;
+
+            while !cursor.end_of_table {
+                let row = read_row(Cursor::get_cursor_value(&mut cursor)?)?;
+                if !expired_keys.contains(&row.id) {
+                    exporter.push(row)?;
+                    rows += 1;
+                }
+                cursor.advance_cursor();
+            }
+            Ok::<_, &'static str>(rows)
+        })??;
+
+        exporter.finish()?;
+        Ok(rows)
+    }
+
+    /**
+     * Dumps the schema and every row a select sees, so not the expired ones, in key order. The
+     * bytes don't depend on how the rows are laid out in pages, so two dbs holding the same
+     * rows give equal snapshots.
+     */
+    pub fn export_snapshot(&self) -> Result<Vec<u8>, &'static str> {
+        let column_names = Column::ALL.map(|column| column.name());
+        let mut writer = SnapshotWriter::new(TABLE_NAME, &column_names);
+
+        read_table(&self.table, |table| {
+            let expired_keys = table.expired_keys()?;
+            let mut cursor = Cursor::table_start(table);
+
+            while !cursor.end_of_table {
+                let row = read_row(Cursor::get_cursor_value(&mut cursor)?)?;
+                if !expired_keys.contains(&row.id) {
+                    writer.push(&row);
+                }
+                cursor.advance_cursor();
+            }
+            Ok::<_, &'static str>(())
+        })??;
+
+        Ok(writer.finish())
+    }
+
+    /**
+     * Changes how many pages the page cache keeps, evicting clean pages right away if it
+     * holds more
+     */
+    pub fn set_cache_size(&self, pages: usize) {
+        lock_table(&self.table).pager.set_cache_capacity(pages);
+    }
+
+    /**
+     * Evicts every clean page from the page cache
+     */
+    pub fn clear_cache(&self) {
+        lock_table(&self.table).pager.clear_cache();
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /**
+     * Snapshot of the page cache and I/O counters since the db was opened
+     */
+    pub fn metrics(&self) -> Metrics {
+        lock_table(&self.table).pager.metrics
+    }
+
+    pub fn close_db(&mut self) -> Result<(), &'static str> {
+        // write all changed pages into file;
+        for table in self.attached.values() {
+            lock_table(table).commit()?;
+        }
+
+        let mut table = lock_table(&self.table);
+        table.commit()
+    }
+
+    /**
+     * Saves the db before exiting when the process receives SIGINT, SIGTERM or SIGHUP. Like
+     * close_db, the attached databases are committed first, then the table, which stays
+     * locked so no statement writes to it after it was saved. Can only be installed once per
+     * process.
+     */
+    pub fn handle_exit_signals(&self) -> Result<(), &'static str> {
+        let table = Arc::clone(&self.table);
+        let exit_tables = Arc::clone(&self.exit_tables);
+
+        let handler_result = ctrlc::set_handler(move || {
+            info!("Received exit signal, saving db");
+
+            let attached: Vec<Arc<Mutex<Table>>> = exit_tables
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .filter_map(Weak::upgrade)
+                .collect();
+            let result = attached
+                .iter()
+                .try_for_each(|attached| lock_table(attached).commit());
+
+            let mut table = lock_table(&table);
+            match result.and_then(|_| table.commit()) {
+                Ok(_) => process::exit(130),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        });
+
+        match handler_result {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Error installing exit signal handler"),
+        }
+    }
+}
+
+/**
+ * Another handle to the same db, to be moved to another thread. The table and the databases
+ * attached so far are shared, each statement locks them like it does for a single handle.
+ * Settings are copied, databases attached later are only seen by the handle that attached
+ * them. Every handle commits pending writes when it's dropped.
+ */
+impl Clone for Db {
+    fn clone(&self) -> Db {
+        Db {
+            table: Arc::clone(&self.table),
+            attached: self.attached.clone(),
+            exit_tables: Arc::clone(&self.exit_tables),
+            commit_queue: self.commit_queue.clone(),
+            max_statement_duration: self.max_statement_duration,
+            progress_handler: self.progress_handler.clone(),
+            options: self.options.clone(),
+            settings: self.settings.clone(),
+            in_block_comment: false,
+            rows_affected: 0,
+        }
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        if let Err(e) = self.close_db() {
+            error!("{}", e);
+        }
+    }
+}
+
+/**
+ * Opens the table of the db file at file_path, with the row metadata and bloom filter the
+ * options ask for
+ */
+fn open_table(file_path: String, options: &DbOptions) -> Result<Table, DbError> {
+    let mut table = Table::open(file_path.clone(), options)?;
+    if options.ttl.is_some() || options.row_metadata {
+        table.row_meta = Some(Box::new(RowMetaStore::open(
+            &file_path,
+            options.ttl,
+            options.busy_timeout,
+        )?));
+    }
+    if options.bloom_filter {
+        table.pager.lock_file(false)?;
+        table.build_bloom_filter();
+        table.pager.unlock_file();
+    }
+
+    Ok(table)
+}
+
+// a panic while holding the lock must not stop the db from being saved on exit
+fn lock_table(table: &Mutex<Table>) -> MutexGuard<'_, Table> {
+    table.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/**
+ * Runs read on table under a shared lock on its db file, so pages another connection
+ * committed since are read again instead of coming from the cache
+ */
+fn read_table<T>(table: &Mutex<Table>, read: impl FnOnce(&mut Table) -> T) -> Result<T, DbError> {
+    let mut table = lock_table(table);
+    table.pager.lock_file(false)?;
+    let result = read(&mut table);
+    table.pager.unlock_file();
+    Ok(result)
+}
+
+/**
+ * Splits the keys into at most n_threads inclusive ranges along the children of the root,
+ * the last range being open ended
+ */
+fn key_partitions(table: &mut Table, n_threads: usize) -> Vec<(u32, Option<u32>)> {
+    let root_page_num = table.root_page_num as usize;
+
+    let separators: Vec<u32> = match table.pager.get_page_node_type(root_page_num).unwrap() {
+        NodeType::Leaf => vec![],
+        NodeType::Internal => {
+            let root = table.pager.get_page_internal(root_page_num).unwrap();
+            (0..root.num_keys()).map(|i| root.cell(i).0).collect()
+        }
+    };
+
+    // the root has one more child than separators, hand them out in contiguous groups
+    let n_children = separators.len() + 1;
+    let n_partitions = n_threads.clamp(1, n_children);
+
+    let mut partitions = Vec::with_capacity(n_partitions);
+    let mut first_key = 0;
+    for partition in 1..n_partitions {
+        let last_key = separators[partition * n_children / n_partitions - 1];
+        partitions.push((first_key, Some(last_key)));
+        first_key = last_key + 1;
+    }
+    partitions.push((first_key, None));
+
+    partitions
+}
+
+/**
+ * Scans the parts of the key space from key_partitions, each on its own thread with its own
+ * pager over the db file
+ */
+fn scan_partitions(table: &mut Table, n_threads: usize) -> Result<Vec<Row>, &'static str> {
+    let partitions = key_partitions(table, n_threads);
+    let expired_keys = table.expired_keys()?;
+    let mut readers = Vec::with_capacity(partitions.len());
+    for _ in &partitions {
+        readers.push(Table {
+            root_page_num: table.root_page_num,
+            pager: table.pager.reader()?,
+            fill_factor: table.fill_factor,
+            changes: ChangeLog::default(),
+            row_meta: None,
+            bloom_filter: None,
+            append_leaf: None,
+        });
+    }
+
+    thread::scope(|scope| {
+        let scans: Vec<_> = partitions
+            .into_iter()
+            .zip(readers)
+            .map(|((first_key, last_key), mut reader)| {
+                scope.spawn(move || scan_key_range(&mut reader, first_key, last_key))
+            })
+            .collect();
+
+        let mut rows = vec![];
+        for scan in scans {
+            match scan.join() {
+                Ok(partition_rows) => rows.extend(partition_rows?),
+                Err(_) => return Err("Parallel scan thread panicked"),
+            }
+        }
+        rows.retain(|row| !expired_keys.contains(&row.id));
+
+        Ok(rows)
+    })
+}
+
+fn scan_key_range(
+    table: &mut Table,
+    first_key: u32,
+    last_key: Option<u32>,
+) -> Result<Vec<Row>, &'static str> {
+    let mut rows = vec![];
+    let mut cursor = Cursor::table_find(table, first_key);
+
+    while !cursor.end_of_table {
+        // the cursor may start past the last cell of a leaf
+        if let Some(key) = cursor.get_cursor_key() {
+            if last_key.is_some_and(|last_key| key > last_key) {
+                break;
+            }
+            rows.push(read_row(Cursor::get_cursor_value(&mut cursor)?)?);
+        }
+
+        cursor.advance_cursor();
+    }
+
+    Ok(rows)
+}
+
+/**
+ * Starts the background thread committing writes in groups. Each write statement sends a
+ * message on the returned queue; the first message of a group starts the commit window and
+ * every write arriving before it closes is flushed with a single sync.
+ */
+fn spawn_committer(table: Weak<Mutex<Table>>, commit_window: Duration) -> Sender<()> {
+    let (commit_queue, pending_writes) = mpsc::channel::<()>();
+
+    thread::spawn(move || {
+        while pending_writes.recv().is_ok() {
+            thread::sleep(commit_window);
+            while pending_writes.try_recv().is_ok() {}
+
+            let Some(table) = table.upgrade() else {
+                break;
+            };
+
+            let mut table = lock_table(&table);
+            let flush_result = table.commit();
+            table.pager.shrink_cache();
+            drop(table);
+
+            if let Err(e) = flush_result {
+                error!("Error committing writes: {}", e);
+            }
+        }
+    });
+
+    commit_queue
+}
+
+/**
+ * Removes `-- line comments` and `/* block comments */` from one line of input. Comments
+ * have to start at the beginning of a word, so values like a--b are kept. Block comments
+ * can span several lines, tracked through `in_block_comment`.
+ */
+fn strip_comments(line: &str, in_block_comment: &mut bool) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut rest = line;
+
+    loop {
+        if *in_block_comment {
+            match rest.find("*/") {
+                Some(end) => {
+                    rest = &rest[end + 2..];
+                    *in_block_comment = false;
+                    stripped.push(' ');
+                }
+                None => return stripped,
+            }
+        }
+
+        match find_comment_start(rest) {
+            Some((start, is_block_comment)) => {
+                stripped.push_str(&rest[..start]);
+
+                if !is_block_comment {
+                    return stripped;
+                }
+                rest = &rest[start + 2..];
+                *in_block_comment = true;
+            }
+            None => {
+                stripped.push_str(rest);
+                return stripped;
+            }
+        }
+    }
+}
+
+/**
+ * Returns the byte offset of the first comment in `input` and whether it is a block comment
+ */
+fn find_comment_start(input: &str) -> Option<(usize, bool)> {
+    input.char_indices().find_map(|(i, _)| {
+        let at_word_start = input[..i]
+            .chars()
+            .next_back()
+            .is_none_or(char::is_whitespace);
+
+        if !at_word_start {
+            None
+        } else if input[i..].starts_with("--") {
+            Some((i, false))
+        } else if input[i..].starts_with("/*") {
+            Some((i, true))
+        } else {
+            None
+        }
+    })
+}
+
+fn print_prompt() {
+    print!("qba-db> ");
+}
+
+enum MetaCommandResponse {
+    Success,
+    UnrecognizedCommand,
+    Exit,
+    CheckFailed(String),
+    InvalidSetting(String),
+    InvalidArgument(String),
+    LockFailed(String),
+}
+
+fn perform_meta_command(
+    command: &str,
+    table: &Mutex<Table>,
+    settings: &mut Settings,
+) -> MetaCommandResponse {
+    // the commands reading pages see what other connections committed
+    let read = |read: &mut dyn FnMut(&mut Table) -> MetaCommandResponse| {
+        read_table(table, read).unwrap_or_else(|e| MetaCommandResponse::LockFailed(e.to_string()))
+    };
+
+    if command == ".exit" {
+        MetaCommandResponse::Exit
+    } else if command == ".ping" {
+        info!("pong!");
+        MetaCommandResponse::Success
+    } else if command == ".dbinfo" {
+        read(&mut |table| {
+            for (name, value) in db_info(table) {
+                info!("{}: {}", name, value);
+            }
+            MetaCommandResponse::Success
+        })
+    } else if command == ".cache" {
+        read(&mut |table| {
+            for (name, value) in cache_info(table) {
+                info!("{}: {}", name, value);
+            }
+            MetaCommandResponse::Success
+        })
+    } else if command == ".check" {
+        read(&mut check_db)
+    } else if command == ".tree" {
+        read(&mut |table| {
+            let root_page_num = table.root_page_num as usize;
+            for line in table.pager.b_tree_lines(root_page_num) {
+                info!("{}", line);
+            }
+            MetaCommandResponse::Success
+        })
+    } else if command == ".tree dot" {
+        read(&mut |table| {
+            let root_page_num = table.root_page_num as usize;
+            for line in table.pager.b_tree_dot(root_page_num).lines() {
+                info!("{}", line);
+            }
+            MetaCommandResponse::Success
+        })
+    } else if let Some(page_num) = command.strip_prefix(".page ") {
+        read(&mut |table| match print_page(table, page_num.trim()) {
+            Ok(()) => MetaCommandResponse::Success,
+            Err(message) => MetaCommandResponse::InvalidArgument(message),
+        })
+    } else if let Some(arguments) = command.strip_prefix(".peek ") {
+        let output = settings.output;
+        read(
+            &mut |table| match print_peek(table, arguments.trim(), output) {
+                Ok(()) => MetaCommandResponse::Success,
+                Err(message) => MetaCommandResponse::InvalidArgument(message),
+            },
+        )
+    } else if let Some(assignment) = command.strip_prefix(".set ") {
+        match settings.set(assignment) {
+            Ok(()) => MetaCommandResponse::Success,
+            Err(message) => MetaCommandResponse::InvalidSetting(message),
+        }
+    } else if let Some(state) = command.strip_prefix(".timer ") {
+        match settings.set(&format!("timer={}", state.trim())) {
+            Ok(()) => MetaCommandResponse::Success,
+            Err(message) => MetaCommandResponse::InvalidSetting(message),
+        }
+    } else if command == ".show" {
+        for (name, value) in settings.entries() {
+            info!("{}: {}", name, value);
+        }
+        MetaCommandResponse::Success
+    } else {
+        MetaCommandResponse::UnrecognizedCommand
+    }
+}
+
+fn print_timer(started_at: Instant, metrics_before: &Metrics, metrics_after: &Metrics) {
+    let lookups = |metrics: &Metrics| metrics.cache_hits + metrics.cache_misses;
+    let pages_touched = lookups(metrics_after) - lookups(metrics_before);
+    let pages_read = metrics_after.page_reads - metrics_before.page_reads;
+
+    info!(
+        "Run Time: {:.6}s, pages touched: {} ({} read from disk)",
+        started_at.elapsed().as_secs_f64(),
+        pages_touched,
+        pages_read
+    );
+}
+
+/**
+ * Size and recovery fields of the db file as (name, value), in the order `.dbinfo` prints
+ * them
+ */
+fn db_info(table: &mut Table) -> Vec<(&'static str, String)> {
+    let leaf_utilization = leaf_utilization(table);
+    let table_size = table_size(TABLE_NAME.to_string(), table);
+    let pager = &table.pager;
+    let recovery = pager.recovery();
+
+    vec![
+        ("page size", PAGE_SIZE.to_string()),
+        ("page count", pager.num_pages.to_string()),
+        ("row count", pager.row_count().to_string()),
+        ("file length", pager.file_length.to_string()),
+        ("encrypted", pager.is_encrypted().to_string()),
+        ("memory mapped", pager.is_mapped().to_string()),
+        (
+            "leaf utilization",
+            format!("{:.1}%", leaf_utilization * 100.0),
+        ),
+        ("tree pages", table_size.tree_pages.to_string()),
+        ("unused pages", table_size.unused_pages.to_string()),
+        ("row metadata bytes", table_size.row_meta_bytes.to_string()),
+        (
+            "slots restored at open",
+            recovery.restored_slots.to_string(),
+        ),
+        ("rows upgraded at open", recovery.upgraded_rows.to_string()),
+    ]
+}
+
+/**
+ * Share of leaf cell slots holding a row, found by following the next_leaf chain
+ */
+fn leaf_utilization(table: &mut Table) -> f64 {
+    let mut page_num = Cursor::table_start(table).page_num;
+    let mut leaves = 0;
+    let mut cells = 0;
+
+    loop {
+        let leaf = table.pager.get_page_leaf(page_num as usize).unwrap();
+        leaves += 1;
+        cells += leaf.num_cells();
+
+        page_num = leaf.next_leaf();
+        if page_num == 0 {
+            break;
+        }
+    }
+
+    cells as f64 / (leaves * LEAF_NODE_MAX_CELLS) as f64
+}
+
+/**
+ * Page cache fields as (name, value), in the order `.cache` prints them
+ */
+fn cache_info(table: &Table) -> Vec<(&'static str, String)> {
+    let pager = &table.pager;
+
+    vec![
+        ("cached pages", pager.cached_pages().to_string()),
+        ("dirty pages", pager.dirty_pages.len().to_string()),
+        ("capacity", pager.cache_capacity().to_string()),
+        ("evictions", pager.metrics.cache_evictions.to_string()),
+        (
+            "hit ratio",
+            format!("{:.2}", pager.metrics.cache_hit_rate()),
+        ),
+    ]
+}
+
+/**
+ * Prints the header fields of a page, a hexdump of its bytes and, for leaf and internal
+ * nodes, its cells
+ */
+fn print_page(table: &mut Table, page_num: &str) -> Result<(), String> {
+    let page_num = match page_num.parse::<u32>() {
+        Ok(page_num) if page_num < table.pager.num_pages => page_num,
+        _ => {
+            return Err(format!(
+                "Invalid page number {}, the db file has {} pages",
+                page_num, table.pager.num_pages
+            ))
+        }
+    };
+    let mut page = pager::Page {
+        data: table
+            .pager
+            .get_page_data(page_num as usize)?
+            .try_into()
+            .unwrap(),
+    };
+
+    info!("page {}", page_num);
+    match page.node_type() {
+        Ok(NodeType::Leaf) => {
+            let leaf = LeafView::new(&mut page.data);
+            info!("type: leaf");
+            info!("is root: {}", leaf.is_root());
+            info!("parent: {}", leaf.parent());
+            info!("num cells: {}", leaf.num_cells());
+            info!("next leaf: {}", leaf.next_leaf());
+        }
+        Ok(NodeType::Internal) => {
+            let node = InternalView::new(&mut page.data);
+            info!("type: internal");
+            info!("is root: {}", node.is_root());
+            info!("parent: {}", node.parent());
+            info!("num keys: {}", node.num_keys());
+            info!("right child: {}", node.right_child());
+            info!("max key: {}", node.max_key());
+            info!("next sibling: {}", node.next_sibling());
+        }
+        Err(_) => info!("type: unknown ({})", page.data[0]),
+    }
+
+    for (line, bytes) in page.data.chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = bytes
+            .iter()
+            .map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+                true => byte as char,
+                false => '.',
+            })
+            .collect();
+        info!("{:04x}  {:<47}  |{}|", line * 16, hex.join(" "), text);
+    }
+
+    if let Some(leaf) = page.leaf_view() {
+        // a damaged count is cut back to the cells that fit in the page
+        let num_cells = leaf.num_cells().min(LEAF_NODE_MAX_CELLS as u32);
+        for cell_num in 0..num_cells {
+            let leaf = LeafView::new(&mut page.data);
+            let key = leaf.get_cell_key(cell_num);
+            match read_row(leaf.get_cell_value(cell_num)) {
+                Ok(row) => info!(
+                    "cell {}: key {}, row ({}, {}, {})",
+                    cell_num, key, row.id, row.username, row.email
+                ),
+                Err(e) => info!("cell {}: key {}, {}", cell_num, key, e),
+            }
+        }
+    } else if let Some(node) = page.internal_view() {
+        for cell_num in 0..node.num_keys().min(INTERNAL_NODE_MAX_CELLS as u32) {
+            let (key, child) = node.cell(cell_num);
+            info!("cell {}: child {}, key {}", cell_num, child, key);
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Prints the first and last n rows of a table and n rows sampled from all of them, read
+ * in a single scan. Arguments are `<table> [n]`, n defaults to PEEK_ROWS.
+ */
+fn print_peek(table: &mut Table, arguments: &str, output: OutputMode) -> Result<(), String> {
+    let (table_name, n) = match arguments.split_once(' ') {
+        Some((table_name, n)) => match n.trim().parse::<usize>() {
+            Ok(n) if n > 0 => (table_name, n),
+            _ => return Err(format!("Invalid row count {}", n.trim())),
+        },
+        None => (arguments, PEEK_ROWS),
+    };
+    if table_name != TABLE_NAME && table_name != format!("{}.{}", MAIN_DATABASE, TABLE_NAME) {
+        return Err(format!("Unknown table {}", table_name));
+    }
+
+    let expired_keys = table.expired_keys()?;
+    let mut first_rows = vec![];
+    let mut last_rows = VecDeque::with_capacity(n);
+    let mut sampled_rows = vec![];
+    let mut rng = SampleRng::new();
+    let mut row_count = 0;
+
+    let mut cursor = Cursor::table_start(table);
+    while !cursor.end_of_table {
+        let row = read_row(Cursor::get_cursor_value(&mut cursor)?)?;
+        cursor.advance_cursor();
+        if expired_keys.contains(&row.id) {
+            continue;
+        }
+
+        if first_rows.len() < n {
+            first_rows.push(row.clone());
+        }
+        if last_rows.len() == n {
+            last_rows.pop_front();
+        }
+        last_rows.push_back(row.clone());
+
+        // reservoir sampling, the row replaces a sampled one with probability n / rows seen
+        if sampled_rows.len() < n {
+            sampled_rows.push(row);
+        } else {
+            let slot = rng.below(row_count + 1) as usize;
+            if slot < n {
+                sampled_rows[slot] = row;
+            }
+        }
+        row_count += 1;
+    }
+    sampled_rows.sort_by_key(|row| row.id);
+
+    info!("{} rows", row_count);
+    for (heading, rows) in [
+        ("first", first_rows.iter().collect::<Vec<_>>()),
+        ("last", last_rows.iter().collect()),
+        ("sampled", sampled_rows.iter().collect()),
+    ] {
+        info!("{} {}:", heading, rows.len());
+        for row in rows {
+            let values = [
+                (Column::Id, row.id.to_string()),
+                (Column::Username, row.username.clone()),
+                (Column::Email, row.email.clone()),
+            ];
+            info!("{}", format_row(&values, output));
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * xorshift64, seeded from the random keys std draws for every RandomState. Good enough to
+ * pick rows to sample, not for anything that has to be unpredictable.
+ */
+struct SampleRng(u64);
+
+impl SampleRng {
+    fn new() -> SampleRng {
+        // xorshift never leaves a zero state
+        SampleRng(RandomState::new().build_hasher().finish() | 1)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/**
+ * Internal nodes of every level below the root from left to right, found by descending to
+ * each of them, to check the sibling links against
+ */
+fn internal_levels(table: &mut Table) -> Vec<Vec<u32>> {
+    let mut levels = vec![];
+    let mut level = vec![table.root_page_num];
+
+    loop {
+        level.retain(|&page_num| {
+            matches!(
+                table.pager.get_page_node_type(page_num as usize).unwrap(),
+                NodeType::Internal
+            )
+        });
+        if level.is_empty() {
+            return levels;
+        }
+
+        let mut next_level = vec![];
+        for &page_num in &level {
+            let node = table.pager.get_page_internal(page_num as usize).unwrap();
+            if node.right_child() != 0 {
+                next_level.extend((0..=node.num_keys()).map(|i| node.get_child(i)));
+            }
+        }
+
+        levels.push(mem::replace(&mut level, next_level));
+    }
+}
+
+/**
+ * Checks the row count in the table header against a full scan of the keys, and the parent
+ * pointers and sibling links of the pages against a descent from the root
+ */
+fn check_db(table: &mut Table) -> MetaCommandResponse {
+    for (depth, level) in internal_levels(table).into_iter().enumerate() {
+        let linked_level = table.internal_level(depth as u32);
+
+        if linked_level != level {
+            return MetaCommandResponse::CheckFailed(format!(
+                "Sibling link mismatch at depth {}: links give pages {:?} but the level holds {:?}",
+                depth, linked_level, level
+            ));
+        }
+    }
+
+    if let Some(&(page_num, parent, actual_parent)) = table.misplaced_parent_pointers().first() {
+        return MetaCommandResponse::CheckFailed(format!(
+            "Parent pointer mismatch: page {} points at parent {} but is a child of {}",
+            page_num, parent, actual_parent
+        ));
+    }
+
+    let row_count = table.pager.row_count();
+    let scanned_rows = Cursor::table_start(table).keys().count();
+
+    if scanned_rows != row_count as usize {
+        return MetaCommandResponse::CheckFailed(format!(
+            "Row count mismatch: table header has {} rows but the table holds {}",
+            row_count, scanned_rows
+        ));
+    }
+
+    info!("ok");
+    MetaCommandResponse::Success
+}
+
+enum StatementPrepareResponse {
+    Success,
+    SyntaxError,
+    StringTooLong,
+    UnknownColumn,
+    UnknownTable,
+    UnrecognizedCommand,
+}
+
+fn prepare_statement(user_input: &str, statement: &mut Statement) -> StatementPrepareResponse {
+    if let Some(select) = user_input.strip_prefix("explain ") {
+        let response = prepare_statement(select.trim(), statement);
+        if !matches!(statement.statement_type, StatementType::Select) {
+            return StatementPrepareResponse::SyntaxError;
+        }
+
+        statement.explain = true;
+        response
+    } else if let Some(from_clause) = user_input.strip_prefix("select count(*)") {
+        statement.statement_type = StatementType::SelectCount;
+
+        // select count(*) [from <table>]
+        let from_clause = from_clause.trim();
+        if from_clause.is_empty() {
+            return StatementPrepareResponse::Success;
+        }
+        match from_clause.strip_prefix("from ") {
+            Some(table_name) => prepare_table_name(table_name.trim(), statement),
+            None => StatementPrepareResponse::SyntaxError,
+        }
+    } else if let Some(column_list) = user_input.strip_prefix("select") {
+        statement.statement_type = StatementType::Select;
+
+        // select [distinct], select [distinct] * or select [distinct] <column>, <column>, ...
+        let mut column_list = column_list.trim();
+        if let Some(rest) = column_list.strip_prefix("distinct") {
+            if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+                statement.distinct = true;
+                column_list = rest.trim();
+            }
+        }
+
+        // select ... [order by id [asc|desc]] [limit <count>]
+        let (column_list, limit) = split_trailing_clause(column_list, "limit");
+        if let Some(limit) = limit {
+            match limit.parse::<usize>() {
+                Ok(limit) => statement.limit = Some(limit),
+                Err(_) => return StatementPrepareResponse::SyntaxError,
+            }
+        }
+
+        // select <columns> [from <table>] for delete limit <count>, the smallest keys first
+        let column_list = match column_list.strip_suffix("for delete") {
+            Some(rest) if rest.is_empty() || rest.ends_with(char::is_whitespace) => {
+                if statement.limit.is_none() || statement.distinct {
+                    return StatementPrepareResponse::SyntaxError;
+                }
+                statement.statement_type = StatementType::SelectForDelete;
+                rest.trim()
+            }
+            _ => column_list,
+        };
+        let (column_list, order_by) = split_trailing_clause(column_list, "order by");
+        if let Some(order_by) = order_by {
+            // rows can only be returned in key order, either way
+            match order_by.split_whitespace().collect::<Vec<&str>>()[..] {
+                ["id"] | ["id", "asc"] => {}
+                ["id", "desc"] => statement.descending = true,
+                [name, ..] if Column::from_name(name).is_none() => {
+                    return StatementPrepareResponse::UnknownColumn
+                }
+                _ => return StatementPrepareResponse::SyntaxError,
+            }
+        }
+
+        let (column_list, where_clause) = split_clause(column_list, "where");
+        if let Some(where_clause) = where_clause {
+            match parse_key_filter(where_clause) {
+                Some(key_filter) => statement.key_filter = Some(key_filter),
+                None => return StatementPrepareResponse::SyntaxError,
+            }
+        }
+
+        let (column_list, table_name) = split_clause(column_list, "from");
+        if let Some(table_name) = table_name {
+            let response = prepare_table_name(table_name, statement);
+            if !matches!(response, StatementPrepareResponse::Success) {
+                return response;
+            }
+        }
+
+        // only ever takes the rows at the front of the table
+        if matches!(statement.statement_type, StatementType::SelectForDelete)
+            && (statement.key_filter.is_some() || statement.descending)
+        {
+            return StatementPrepareResponse::SyntaxError;
+        }
+
+        if column_list.is_empty() {
+            return StatementPrepareResponse::Success;
+        }
+
+        match parse_columns(column_list) {
+            Ok(columns) => statement.columns = columns,
+            Err(response) => return response,
+        }
+
+        StatementPrepareResponse::Success
+    } else if user_input.starts_with("insert") {
+        statement.statement_type = StatementType::Insert;
+
+        let user_input = match prepare_returning(user_input, statement) {
+            Ok(user_input) => user_input,
+            Err(response) => return response,
+        };
+
+        // read arguments from user input
+        let mut row_args: Vec<&str> = user_input.split_whitespace().collect();
+
+        // insert into <table> <id> <username> <email>
+        if row_args.len() > 2 && row_args[1] == "into" {
+            let response = prepare_table_name(row_args[2], statement);
+            if !matches!(response, StatementPrepareResponse::Success) {
+                return response;
+            }
+            row_args.drain(1..3);
+        }
+
+        if row_args.len() != 4 {
+            return StatementPrepareResponse::SyntaxError;
+        }
+
+        statement.row_to_insert.id = match row_args[1].parse::<u32>() {
+            Ok(id) => id,
+            Err(_) => return StatementPrepareResponse::SyntaxError,
+        };
+
+        if row_args[2].len() > MAX_STRING_SIZE || row_args[3].len() > MAX_STRING_SIZE {
+            return StatementPrepareResponse::StringTooLong;
+        }
+
+        statement.row_to_insert.username = row_args[2].to_string();
+        statement.row_to_insert.email = row_args[3].to_string();
+
+        StatementPrepareResponse::Success
+    } else if let Some(update) = user_input.strip_prefix("update ") {
+        statement.statement_type = StatementType::Update;
+
+        let update = match prepare_returning(update, statement) {
+            Ok(update) => update,
+            Err(response) => return response,
+        };
+
+        // update <table> set <column> = <value>, ... where id = <key> [and _version = <version>]
+        let (update, where_clause) = split_clause(update, "where");
+        let (table_name, assignments) = match split_clause(update, "set") {
+            (table_name, Some(assignments)) => (table_name, assignments),
+            _ => return StatementPrepareResponse::SyntaxError,
+        };
+        let response = prepare_table_name(table_name, statement);
+        if !matches!(response, StatementPrepareResponse::Success) {
+            return response;
+        }
+
+        match where_clause.and_then(parse_key_condition) {
+            Some((key, expected_version)) => {
+                statement.key_range = Some((key, key));
+                statement.expected_version = expected_version;
+            }
+            None => return StatementPrepareResponse::SyntaxError,
+        }
+
+        for assignment in assignments.split(',') {
+            let Some((name, value)) = assignment.split_once('=') else {
+                return StatementPrepareResponse::SyntaxError;
+            };
+            let value = value.trim();
+
+            match Column::from_name(name.trim()) {
+                Some(column @ (Column::Username | Column::Email)) => {
+                    if value.is_empty() || value.contains(char::is_whitespace) {
+                        return StatementPrepareResponse::SyntaxError;
+                    }
+                    if value.len() > MAX_STRING_SIZE {
+                        return StatementPrepareResponse::StringTooLong;
+                    }
+                    statement.assignments.push((column, value.to_string()));
+                }
+                // the key and hidden columns are not set by statements
+                Some(_) => return StatementPrepareResponse::SyntaxError,
+                None => return StatementPrepareResponse::UnknownColumn,
+            }
+        }
+
+        StatementPrepareResponse::Success
+    } else if let Some(from_clause) = user_input.strip_prefix("delete from ") {
+        statement.statement_type = StatementType::Delete;
+        let from_clause = match prepare_returning(from_clause, statement) {
+            Ok(from_clause) => from_clause,
+            Err(response) => return response,
+        };
+
+        // delete from <table> where id between <first> and <last>
+        let (table_name, where_clause) = split_clause(from_clause, "where");
+        let response = prepare_table_name(table_name, statement);
+        if !matches!(response, StatementPrepareResponse::Success) {
+            return response;
+        }
+
+        match where_clause.and_then(parse_key_range) {
+            Some(key_range) => statement.key_range = Some(key_range),
+            None => return StatementPrepareResponse::SyntaxError,
+        }
+
+        StatementPrepareResponse::Success
+    } else if user_input == "truncate" {
+        statement.statement_type = StatementType::Truncate;
+        StatementPrepareResponse::Success
+    } else if let Some(table_name) = user_input.strip_prefix("truncate ") {
+        statement.statement_type = StatementType::Truncate;
+        prepare_table_name(table_name.trim(), statement)
+    } else if user_input == "print_tree" {
+        statement.statement_type = StatementType::PrintTree;
+        StatementPrepareResponse::Success
+    } else {
+        StatementPrepareResponse::UnrecognizedCommand
+    }
+}
+
+/**
+ * Splits `<columns> <keyword> <clause>` at the first occurrence of keyword, like where in
+ * `<columns> where <condition>`
+ */
+fn split_clause<'a>(input: &'a str, keyword: &str) -> (&'a str, Option<&'a str>) {
+    for (index, _) in input.match_indices(keyword) {
+        let before = input[..index].chars().next_back();
+        let after = input[index + keyword.len()..].chars().next();
+
+        if before.is_none_or(char::is_whitespace) && after.is_some_and(char::is_whitespace) {
+            return (
+                input[..index].trim(),
+                Some(input[index + keyword.len()..].trim()),
+            );
+        }
+    }
+
+    (input, None)
+}
+
+/**
+ * Splits `<statement> <keyword> <clause>` at the last occurrence of keyword that is not
+ * inside the parentheses of a subquery, like limit in `<columns> where <condition> limit 5`
+ */
+fn split_trailing_clause<'a>(input: &'a str, keyword: &str) -> (&'a str, Option<&'a str>) {
+    for (index, _) in input.rmatch_indices(keyword) {
+        let before = input[..index].chars().next_back();
+        let clause = &input[index + keyword.len()..];
+
+        if before.is_none_or(char::is_whitespace)
+            && clause.starts_with(char::is_whitespace)
+            && !clause.contains(')')
+        {
+            return (input[..index].trim(), Some(clause.trim()));
+        }
+    }
+
+    (input, None)
+}
+
+/**
+ * Splits `... returning <column>, ...` off a write, setting the columns it hands back
+ */
+fn prepare_returning<'a>(
+    input: &'a str,
+    statement: &mut Statement,
+) -> Result<&'a str, StatementPrepareResponse> {
+    let (input, column_list) = split_clause(input, "returning");
+    if let Some(column_list) = column_list {
+        statement.returning = Some(parse_columns(column_list)?);
+    }
+
+    Ok(input)
+}
+
+/**
+ * Columns of `*` or of `<column>, <column>, ...`
+ */
+fn parse_columns(column_list: &str) -> Result<Vec<Column>, StatementPrepareResponse> {
+    if column_list == "*" {
+        return Ok(Column::ALL.to_vec());
+    }
+
+    column_list
+        .split(',')
+        .map(|name| Column::from_name(name.trim()).ok_or(StatementPrepareResponse::UnknownColumn))
+        .collect()
+}
+
+/**
+ * Sets the database of a statement from `users`, `main.users` or `<database>.users`
+ */
+fn prepare_table_name(table_name: &str, statement: &mut Statement) -> StatementPrepareResponse {
+    statement.database = match table_name.split_once('.') {
+        None if table_name == TABLE_NAME => None,
+        Some((MAIN_DATABASE, TABLE_NAME)) => None,
+        Some((database, TABLE_NAME)) if !database.is_empty() => Some(database.to_string()),
+        _ => return StatementPrepareResponse::UnknownTable,
+    };
+
+    StatementPrepareResponse::Success
+}
+
+/**
+ * Parses `'<file path>' as <name>`
+ */
+fn parse_attach(arguments: &str) -> Option<(&str, &str)> {
+    let (file_path, name) = arguments.trim().strip_prefix('\'')?.split_once('\'')?;
+    let name = name.trim_start().strip_prefix("as ")?.trim();
+
+    if file_path.is_empty()
+        || name.is_empty()
+        || name.contains(|c: char| !c.is_alphanumeric() && c != '_')
+    {
+        return None;
+    }
+
+    Some((file_path, name))
+}
+
+/**
+ * Parses `id between <first> and <last>`
+ */
+fn parse_key_range(condition: &str) -> Option<(u32, u32)> {
+    match condition.split_whitespace().collect::<Vec<&str>>()[..] {
+        ["id", "between", first, "and", last] => Some((first.parse().ok()?, last.parse().ok()?)),
+        _ => None,
+    }
+}
+
+/**
+ * Parses `id = <key>` or `id = <key> and _version = <version>`
+ */
+fn parse_key_condition(condition: &str) -> Option<(u32, Option<u64>)> {
+    match condition.split_whitespace().collect::<Vec<&str>>()[..] {
+        ["id", "=", key] => Some((key.parse().ok()?, None)),
+        ["id", "=", key, "and", "_version", "=", version] => {
+            Some((key.parse().ok()?, Some(version.parse().ok()?)))
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Parses `id in (<key>, <key>, ...)` or `id in (select id ...)`
+ */
+fn parse_key_filter(condition: &str) -> Option<KeyFilter> {
+    let list = condition
+        .strip_prefix("id")?
+        .trim_start()
+        .strip_prefix("in")?
+        .trim_start()
+        .strip_prefix('(')?
+        .strip_suffix(')')?
+        .trim();
+
+    if list.starts_with("select") {
+        let mut subquery = Statement::new();
+
+        return match prepare_statement(list, &mut subquery) {
+            StatementPrepareResponse::Success
+                if matches!(subquery.statement_type, StatementType::Select)
+                    && subquery.columns == [Column::Id] =>
+            {
+                Some(KeyFilter::Subquery(Box::new(subquery)))
+            }
+            _ => None,
+        };
+    }
+
+    list.split(',')
+        .map(|key| key.trim().parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()
+        .map(KeyFilter::Keys)
+}
 
-pub struct Table {
-    pub root_page_num: u32,
-    pub pager: Pager,
+/**
+ * Runs a prepared statement, returning the number of rows it changed
+ */
+fn execute_statement(
+    statement: Statement,
+    table: &mut Table,
+    interrupt: &StatementInterrupt,
+    on_row: RowSink,
+) -> Result<u32, &'static str> {
+    match statement.statement_type {
+        StatementType::Select => {
+            execute_select_statement(statement, table, interrupt, on_row).map(|_| 0)
+        }
+        StatementType::SelectCount => {
+            execute_select_count_statement(statement, table, on_row).map(|_| 0)
+        }
+        StatementType::Insert => execute_insert_statement(statement, table, on_row).map(|_| 1),
+        StatementType::Update => execute_update_statement(statement, table, on_row),
+        StatementType::Delete => execute_delete_statement(statement, table, on_row),
+        StatementType::Truncate => {
+            let row_count = table.pager.row_count();
+            execute_truncate_statement(statement, table).map(|_| row_count)
+        }
+        StatementType::PrintTree => {
+            execute_print_tree_statement(statement, table, on_row).map(|_| 0)
+        }
+        StatementType::SelectForDelete => {
+            execute_select_for_delete_statement(statement, table, on_row)
+        }
+    }
 }
 
-impl Table {
-    fn new(file_descriptor: String) -> Self {
-        let pager = Pager::open_file(file_descriptor).unwrap();
+fn execute_truncate_statement(_: Statement, table: &mut Table) -> Result<(), &'static str> {
+    if table.changes.is_observed() {
+        let mut deleted_rows = vec![];
+        let mut cursor = Cursor::table_start(table);
 
-        Table {
-            root_page_num: 0,
-            pager,
+        while !cursor.end_of_table {
+            deleted_rows.push(read_row(Cursor::get_cursor_value(&mut cursor)?)?);
+            cursor.advance_cursor();
+        }
+
+        for row in deleted_rows {
+            table.changes.record(ChangeEvent {
+                table: TABLE_NAME,
+                op: ChangeOp::Delete,
+                key: row.id,
+                before: Some(row),
+                after: None,
+            });
         }
     }
-}
 
-pub struct Row {
-    pub id: u32,
-    pub username: String,
-    pub email: String,
+    table.pager.truncate();
+    if let Some(row_meta) = &mut table.row_meta {
+        row_meta.clear()?;
+    }
+    if let Some(bloom_filter) = &mut table.bloom_filter {
+        bloom_filter.clear();
+    }
+
+    Ok(())
 }
 
-pub struct Db {
-    pub table: Table,
+/**
+ * Hands the tree to on_row a line at a time, so it's formatted like the rows of a select
+ */
+fn execute_print_tree_statement(
+    _: Statement,
+    table: &mut Table,
+    on_row: RowSink,
+) -> Result<(), &'static str> {
+    let root_page_num = table.root_page_num as usize;
+    for line in table.pager.b_tree_lines(root_page_num) {
+        on_row(&[(Column::Tree, line)]);
+    }
+
+    Ok(())
 }
 
-impl Db {
-    pub fn new(file_descriptor: String) -> Db {
-        Db {
-            table: Table::new(file_descriptor),
-        }
+fn execute_select_statement(
+    statement: Statement,
+    table: &mut Table,
+    interrupt: &StatementInterrupt,
+    on_row: RowSink,
+) -> Result<(), &'static str> {
+    // rows with the key are unique already, other projections are de-duplicated by hashing
+    let mut seen_rows = if statement.distinct && !statement.columns.contains(&Column::Id) {
+        Some(HashSet::<Vec<String>>::new())
+    } else {
+        None
+    };
+
+    if statement.limit == Some(0) {
+        return Ok(());
     }
+    let mut rows_left = statement.limit.unwrap_or(usize::MAX);
+    let expired_keys = table.expired_keys()?;
+    let row_metas = match &mut table.row_meta {
+        Some(row_meta) if statement.columns.iter().any(Column::is_meta) => row_meta.all()?,
+        _ => HashMap::new(),
+    };
+
+    // returns whether more rows are wanted
+    let mut print_row = |row_slot: &[u8]| -> Result<bool, &'static str> {
+        if !expired_keys.is_empty() && expired_keys.contains(&read_key(row_slot)) {
+            return Ok(true);
+        }
 
-    pub fn run_db(&mut self) {
-        info!("Initialized QBA-DB version 0.0.1");
+        // only deserialize the requested columns
+        let mut values = Vec::with_capacity(statement.columns.len());
+        for column in &statement.columns {
+            let value = match column.is_meta() {
+                true => meta_column(row_metas.get(&read_key(row_slot)), *column),
+                false => deserialize_column(row_slot, *column)?,
+            };
+            values.push((*column, value));
+        }
 
-        loop {
-            print_prompt();
-            let mut user_input = String::new();
-            let _ = stdout().flush();
-            stdin()
-                .read_line(&mut user_input)
-                .expect("Did not enter a correct string");
-            if let Some('\n') = user_input.chars().next_back() {
-                user_input.pop();
-            }
-            if let Some('\r') = user_input.chars().next_back() {
-                user_input.pop();
+        if let Some(seen_rows) = &mut seen_rows {
+            let row_values = values.iter().map(|(_, value)| value.clone()).collect();
+            if !seen_rows.insert(row_values) {
+                return Ok(true);
             }
+        }
 
-            if user_input.starts_with('.') {
-                match perform_meta_command(&user_input) {
-                    MetaCommandResponse::Success => {
-                        continue;
-                    }
-                    MetaCommandResponse::UnrecognizedCommand => {
-                        info!("Unrecognized command {}", user_input);
-                        continue;
-                    }
-                    MetaCommandResponse::Exit => return,
-                }
-            }
+        on_row(&values);
+        rows_left -= 1;
+        Ok(rows_left > 0)
+    };
+
+    if let Some(key_filter) = &statement.key_filter {
+        let mut keys = resolve_key_filter(key_filter, table);
+        // keys past the last key of the table are not looked up at all, nor are keys the
+        // bloom filter rules out
+        let max_key = table.max_key();
+        keys.truncate(keys.partition_point(|&key| max_key.is_some_and(|max_key| key <= max_key)));
+        keys.retain(|&key| table.may_contain_key(key));
+        // leaves are only linked forwards, descending rows are seeked one by one
+        let lookup = if statement.descending {
+            keys.reverse();
+            KeyLookup::Seek
+        } else {
+            plan_key_lookup(&keys, table)
+        };
 
-            // prepare statement
-            let mut cur_statement: Statement = Statement {
-                statement_type: StatementType::Select,
-                row_to_insert: Row {
-                    id: 0,
-                    username: "".to_string(),
-                    email: "".to_string(),
-                },
+        if statement.explain {
+            let plan = match lookup {
+                KeyLookup::Seek if statement.descending => {
+                    format!("seek {} keys from the root in descending order", keys.len())
+                }
+                KeyLookup::Seek => format!("seek {} keys from the root", keys.len()),
+                KeyLookup::Scan => format!(
+                    "scan leaves from key {} to key {} for {} keys",
+                    keys[0],
+                    keys[keys.len() - 1],
+                    keys.len()
+                ),
             };
+            on_row(&[(Column::Plan, plan)]);
+            return Ok(());
+        }
 
-            match prepare_statement(&user_input, &mut cur_statement) {
-                StatementPrepareResponse::Success => {
-                    execute_statement(cur_statement, &mut self.table);
-                }
-                StatementPrepareResponse::UnrecognizedCommand => {
-                    info!("Unrecognized statement {}", user_input);
-                    continue;
-                }
-                StatementPrepareResponse::SyntaxError => {
-                    info!("Syntax error in statement {}", user_input);
-                    continue;
+        match lookup {
+            KeyLookup::Seek => {
+                // point lookups in key order, so neighbouring keys hit the same pages
+                for key in keys {
+                    interrupt.check()?;
+
+                    let mut cursor = Cursor::table_find(table, key);
+                    if cursor.get_cursor_key() == Some(key)
+                        && !print_row(Cursor::get_cursor_value(&mut cursor)?)?
+                    {
+                        break;
+                    }
                 }
             }
-        }
-    }
+            KeyLookup::Scan => {
+                let mut keys = keys.into_iter().peekable();
+                let mut cursor = Cursor::table_find(table, keys.peek().copied().unwrap_or(0));
+
+                while !cursor.end_of_table {
+                    let Some(&next_key) = keys.peek() else {
+                        break;
+                    };
+
+                    // the cursor may start past the last cell of a leaf
+                    match cursor.get_cursor_key() {
+                        Some(key) if key > next_key => {
+                            keys.next();
+                            continue;
+                        }
+                        Some(key) if key == next_key => {
+                            if !print_row(Cursor::get_cursor_value(&mut cursor)?)? {
+                                break;
+                            }
+                            keys.next();
+                        }
+                        _ => {}
+                    }
 
-    pub fn run_db_test(&mut self, user_input: String) {
-        // info!("Executing statement: {}", user_input);
+                    let page_num = cursor.page_num;
+                    cursor.advance_cursor();
 
-        if user_input.starts_with('.') {
-            match perform_meta_command(&user_input) {
-                MetaCommandResponse::Success => {}
-                MetaCommandResponse::UnrecognizedCommand => {
-                    info!("Unrecognized command {}", user_input);
+                    if !cursor.end_of_table && cursor.page_num != page_num {
+                        interrupt.check()?;
+                    }
                 }
-                MetaCommandResponse::Exit => return,
             }
         }
 
-        // prepare statement
-        let mut cur_statement: Statement = Statement {
-            statement_type: StatementType::Select,
-            row_to_insert: Row {
-                id: 0,
-                username: "".to_string(),
-                email: "".to_string(),
-            },
+        return Ok(());
+    }
+
+    if statement.explain {
+        let plan = match statement.descending {
+            true => "scan table in descending key order",
+            false => "scan table",
         };
+        on_row(&[(Column::Plan, plan.to_string())]);
+        return Ok(());
+    }
 
-        match prepare_statement(&user_input, &mut cur_statement) {
-            StatementPrepareResponse::Success => {
-                execute_statement(cur_statement, &mut self.table);
-            }
-            StatementPrepareResponse::UnrecognizedCommand => {
-                info!("Unrecognized statement {}", user_input);
+    if statement.descending {
+        let mut cursor = Cursor::seek_for_prev(table, u32::MAX);
+
+        while cursor.is_valid() {
+            if !print_row(Cursor::get_cursor_value(&mut cursor)?)? {
+                break;
             }
-            StatementPrepareResponse::SyntaxError => {
-                info!("Syntax error in statement {}", user_input);
+
+            let page_num = cursor.page_num;
+            cursor.prev();
+
+            if cursor.is_valid() && cursor.page_num != page_num {
+                interrupt.check()?;
             }
         }
+
+        return Ok(());
     }
 
-    pub fn close_db(&mut self) -> Result<(), &str> {
-        // write all bytes of pages into file;
-        let mut cursor = Cursor::table_start(&mut self.table);
+    let mut cursor = Cursor::table_start(table);
+    let mut end_of_table = cursor.end_of_table;
+
+    while !end_of_table {
+        if !print_row(Cursor::get_cursor_value(&mut cursor)?)? {
+            break;
+        }
 
-        let mut end_of_table = cursor.end_of_table;
-        let mut pages_written = 0;
+        let page_num = cursor.page_num;
+        cursor.advance_cursor();
+        end_of_table = cursor.end_of_table;
 
-        while end_of_table == false {
-            info!("saving node at page_num: {} to file", cursor.page_num);
-            let mut node = cursor
-                .table
-                .pager
-                .get_page_leaf(cursor.page_num as usize)
-                .unwrap();
+        // check for cancellation whenever the scan moves on to the next leaf
+        if !end_of_table && cursor.page_num != page_num {
+            interrupt.check()?;
+        }
+    }
+
+    Ok(())
+}
 
-            let mut page_to_write = [0u8; PAGE_SIZE];
-            LeafNode::deserialize_node(&mut node, page_to_write.as_mut_ptr());
+/**
+ * Sorted, de-duplicated keys matched by a key filter. Keys of a literal list may not exist
+ * in the table, lookups skip those.
+ */
+fn resolve_key_filter(key_filter: &KeyFilter, table: &mut Table) -> Vec<u32> {
+    let mut keys = match key_filter {
+        KeyFilter::Keys(keys) => keys.clone(),
+        KeyFilter::Subquery(subquery) => {
+            let mut keys: Vec<u32> = match &subquery.key_filter {
+                Some(key_filter) => resolve_key_filter(key_filter, table),
+                None => Cursor::table_start(table).keys().collect(),
+            };
 
-            match cursor
-                .table
-                .pager
-                .file_descriptor
-                .write_all_at(&page_to_write, PAGE_SIZE as u64 * pages_written)
-            {
-                Ok(_) => {
-                    pages_written = pages_written + 1;
+            if let Some(limit) = subquery.limit {
+                // only keys the table holds count towards the limit
+                keys.retain(|&key| table.contains_key(key));
+                if subquery.descending {
+                    keys.reverse();
                 }
-                Err(_) => return Err("Error saving db to file!"),
+                keys.truncate(limit);
             }
 
-            cursor.advance_cursor();
-            end_of_table = cursor.end_of_table;
+            keys
         }
+    };
 
-        Ok(())
-    }
+    keys.sort_unstable();
+    keys.dedup();
+    keys
 }
 
-fn print_prompt() {
-    print!("qba-db> ");
+/**
+ * Picks between seeking every key and scanning the leaves between the first and last key,
+ * by the number of pages each is expected to read. Keys must be sorted.
+ */
+fn plan_key_lookup(keys: &[u32], table: &mut Table) -> KeyLookup {
+    let (Some(&first_key), Some(&last_key)) = (keys.first(), keys.last()) else {
+        return KeyLookup::Seek;
+    };
+
+    let height = tree_height(table);
+    let seek_pages = keys.len() as u64 * height;
+
+    // the span holds at most one row per key in it, and never more rows than the table
+    let rows_in_span =
+        (u64::from(last_key - first_key) + 1).min(u64::from(table.pager.row_count()));
+    let scan_pages = height + rows_in_span.div_ceil(LEAF_NODE_MAX_CELLS as u64);
+
+    if scan_pages < seek_pages {
+        KeyLookup::Scan
+    } else {
+        KeyLookup::Seek
+    }
 }
 
-enum MetaCommandResponse {
-    Success,
-    UnrecognizedCommand,
-    Exit,
+/**
+ * Size of one table, as listed by Db::table_sizes
+ */
+fn table_size(name: String, table: &mut Table) -> TableSize {
+    let tree_pages = tree_pages(table);
+
+    TableSize {
+        name,
+        tree_pages,
+        unused_pages: table.pager.num_pages.saturating_sub(tree_pages),
+        file_bytes: table.pager.file_length,
+        row_meta_bytes: table
+            .row_meta
+            .as_ref()
+            .map_or(0, |row_meta| row_meta.file_length()),
+    }
 }
 
-fn perform_meta_command(command: &String) -> MetaCommandResponse {
-    if command == ".exit" {
-        return MetaCommandResponse::Exit;
-    } else if command == ".ping" {
-        info!("pong!");
-        return MetaCommandResponse::Success;
-    } else {
-        return MetaCommandResponse::UnrecognizedCommand;
+/**
+ * Number of pages reachable from the root
+ */
+fn tree_pages(table: &mut Table) -> u32 {
+    let root_page_num = table.root_page_num as usize;
+    if let NodeType::Leaf = table.pager.get_page_node_type(root_page_num).unwrap() {
+        return 1;
+    }
+
+    let mut tree_pages = 0;
+    let mut internal_nodes = vec![root_page_num];
+    while let Some(page_num) = internal_nodes.pop() {
+        tree_pages += 1;
+
+        let node = table.pager.get_page_internal(page_num).unwrap();
+        let children: Vec<u32> = (0..=node.num_keys()).map(|i| node.get_child(i)).collect();
+        for child in children {
+            match table.pager.get_page_node_type(child as usize).unwrap() {
+                NodeType::Internal => internal_nodes.push(child as usize),
+                NodeType::Leaf => tree_pages += 1,
+            }
+        }
     }
+
+    tree_pages
 }
 
-enum StatementPrepareResponse {
-    Success,
-    SyntaxError,
-    UnrecognizedCommand,
+/**
+ * Pages read by a lookup going from the root down to a leaf
+ */
+fn tree_height(table: &mut Table) -> u64 {
+    let mut page_num = table.root_page_num as usize;
+    let mut height = 1;
+
+    while let NodeType::Internal = table.pager.get_page_node_type(page_num).unwrap() {
+        page_num = table
+            .pager
+            .get_page_internal(page_num)
+            .unwrap()
+            .get_child(0) as usize;
+        height += 1;
+    }
+
+    height
 }
 
-fn prepare_statement(user_input: &String, statement: &mut Statement) -> StatementPrepareResponse {
-    if user_input.starts_with("select") {
-        statement.statement_type = StatementType::Select;
-        return StatementPrepareResponse::Success;
-    } else if user_input.starts_with("insert") {
-        statement.statement_type = StatementType::Insert;
+fn format_row(values: &[(Column, String)], output: OutputMode) -> String {
+    match output {
+        OutputMode::Text => values
+            .iter()
+            .map(|(column, value)| format!("{}: {}", column.name(), value))
+            .collect::<Vec<_>>()
+            .join(", "),
+        OutputMode::Json => {
+            let fields = values
+                .iter()
+                .map(|(column, value)| match column {
+                    Column::Id => format!("\"{}\":{}", column.name(), value),
+                    Column::Username
+                    | Column::Email
+                    | Column::Tree
+                    | Column::Name
+                    | Column::Value
+                    | Column::Table
+                    | Column::Columns
+                    | Column::Plan => {
+                        format!("\"{}\":{}", column.name(), json_string(value))
+                    }
+                    _ if value.is_empty() => format!("\"{}\":null", column.name()),
+                    _ => format!("\"{}\":{}", column.name(), value),
+                })
+                .collect::<Vec<_>>();
 
-        // read arguments from user input
-        let row_args: Vec<&str> = user_input.split_whitespace().collect();
+            format!("{{{}}}", fields.join(","))
+        }
+    }
+}
 
-        if row_args.len() != 4 {
-            return StatementPrepareResponse::SyntaxError;
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
         }
+    }
 
-        statement.row_to_insert.id = row_args[1].parse::<u32>().unwrap();
-        statement.row_to_insert.username = row_args[2].to_string();
-        statement.row_to_insert.email = row_args[3].to_string();
+    escaped.push('"');
+    escaped
+}
 
-        return StatementPrepareResponse::Success;
-    } else if user_input.as_str() == "print_tree" {
-        statement.statement_type = StatementType::PrintTree;
-        return StatementPrepareResponse::Success;
-    } else {
-        return StatementPrepareResponse::UnrecognizedCommand;
+/**
+ * Hands the number of rows that haven't expired to on_row as a single row
+ */
+fn execute_select_count_statement(
+    _: Statement,
+    table: &mut Table,
+    on_row: RowSink,
+) -> Result<(), &'static str> {
+    let expired_keys = table.expired_keys()?;
+    let expired_rows = expired_keys
+        .into_iter()
+        .filter(|&key| table.contains_key(key))
+        .count();
+    let count = table.pager.row_count() as usize - expired_rows;
+    on_row(&[(Column::Count, count.to_string())]);
+
+    Ok(())
+}
+
+fn execute_insert_statement(
+    statement: Statement,
+    table: &mut Table,
+    on_row: RowSink,
+) -> Result<(), &'static str> {
+    insert_row(table, &statement.row_to_insert)?;
+
+    if let Some(columns) = &statement.returning {
+        on_row(&returned_row(table, statement.row_to_insert.id, columns)?);
     }
+
+    Ok(())
 }
 
-fn execute_statement(statement: Statement, table: &mut Table) {
-    match statement.statement_type {
-        StatementType::Select => execute_select_statement(statement, table).unwrap(),
-        StatementType::Insert => match execute_insert_statement(statement, table) {
-            Ok(_) => {}
-            Err(e) => info!("Error inserting! {}", e),
-        },
-        StatementType::PrintTree => execute_print_tree_statement(statement, table).unwrap(),
+/**
+ * Columns of the row with key as they are stored, for the returning clause of a write
+ */
+fn returned_row(
+    table: &mut Table,
+    key: u32,
+    columns: &[Column],
+) -> Result<Vec<(Column, String)>, &'static str> {
+    let meta = match &mut table.row_meta {
+        Some(row_meta) if columns.iter().any(Column::is_meta) => row_meta.get(key)?,
+        _ => None,
+    };
+
+    let mut cursor = Cursor::table_find(table, key);
+    let row_slot = Cursor::get_cursor_value(&mut cursor)?;
+    columns
+        .iter()
+        .map(|column| match column.is_meta() {
+            true => Ok((*column, meta_column(meta.as_ref(), *column))),
+            false => Ok((*column, deserialize_column(row_slot, *column)?)),
+        })
+        .collect()
+}
+
+fn execute_update_statement(
+    statement: Statement,
+    table: &mut Table,
+    on_row: RowSink,
+) -> Result<u32, &'static str> {
+    let (key, _) = statement.key_range.unwrap();
+    let updated_rows = update_row(
+        table,
+        key,
+        &statement.assignments,
+        statement.expected_version,
+    )?;
+    info!("updated {} rows", updated_rows);
+
+    if let Some(columns) = &statement.returning {
+        if updated_rows > 0 {
+            on_row(&returned_row(table, key, columns)?);
+        }
     }
+
+    Ok(updated_rows)
 }
 
-fn execute_print_tree_statement(_: Statement, table: &mut Table) -> Result<(), &'static str> {
-    info!("Print tree:");
+/**
+ * Sets the assigned columns of the row with key. With an expected version the row is only
+ * changed while it is still at that version, so a writer that read the row before someone
+ * else updated it changes nothing and can read it again. Returns the number of updated rows,
+ * 0 when the row is missing, expired or at another version.
+ */
+pub(crate) fn update_row(
+    table: &mut Table,
+    key: u32,
+    assignments: &[(Column, String)],
+    expected_version: Option<u64>,
+) -> Result<u32, &'static str> {
+    if expected_version.is_some() && table.row_meta.is_none() {
+        return Err("Row versions are only kept with row metadata");
+    }
 
-    let root_page_num = table.root_page_num as usize;
-    table.pager.print_b_tree(root_page_num, 0);
+    if !table.contains_key(key) || table.expired_keys()?.contains(&key) {
+        return Ok(0);
+    }
 
-    Ok(())
+    if let (Some(expected_version), Some(row_meta)) = (expected_version, &mut table.row_meta) {
+        let version = row_meta.get(key)?.map_or(0, |meta| meta.version);
+        if version != expected_version {
+            return Ok(0);
+        }
+    }
+
+    let (before, after) = {
+        let mut cursor = Cursor::table_find(table, key);
+        cursor.table.pager.mark_dirty(cursor.page_num as usize);
+        let value = Cursor::get_cursor_value(&mut cursor)?;
+
+        let before = read_row(value)?;
+        let mut after = before.clone();
+        for (column, new_value) in assignments {
+            match column {
+                Column::Username => after.username = new_value.clone(),
+                Column::Email => after.email = new_value.clone(),
+                _ => return Err("Only username and email can be updated"),
+            }
+        }
+        serialize_row(&after, value)?;
+
+        (before, after)
+    };
+
+    // the write is saved by the next flush, which bumps the change counter
+    let txn_id = u64::from(table.pager.change_counter()) + 1;
+    if let Some(row_meta) = &mut table.row_meta {
+        row_meta.record_update(key, txn_id)?;
+    }
+
+    table.changes.record(ChangeEvent {
+        table: TABLE_NAME,
+        op: ChangeOp::Update,
+        key,
+        before: Some(before),
+        after: Some(after),
+    });
+
+    Ok(1)
 }
 
-fn execute_select_statement(_: Statement, table: &mut Table) -> Result<(), &'static str> {
-    let mut cursor = Cursor::table_start(table);
-    let mut end_of_table = cursor.end_of_table;
+fn execute_delete_statement(
+    statement: Statement,
+    table: &mut Table,
+    on_row: RowSink,
+) -> Result<u32, &'static str> {
+    let (first, last) = statement.key_range.unwrap();
+
+    // handed back before the range is deleted, while the rows can still be read. Expired
+    // rows are deleted with the range but not handed back, as selects don't see them.
+    if let Some(columns) = &statement.returning {
+        let expired_keys = table.expired_keys()?;
+        let mut keys = vec![];
+        let mut cursor = Cursor::table_find(table, first);
+        while !cursor.end_of_table {
+            match cursor.get_cursor_key() {
+                Some(key) if key > last => break,
+                Some(key) if key >= first && !expired_keys.contains(&key) => keys.push(key),
+                _ => {}
+            }
+            cursor.advance_cursor();
+        }
 
-    while end_of_table == false {
-        let row_slot = Cursor::get_cursor_value(&mut cursor).unwrap();
+        for key in keys {
+            on_row(&returned_row(table, key, columns)?);
+        }
+    }
 
-        let mut row_data = Row {
-            id: 123,
-            email: String::from("123"),
-            username: String::from("!@3"),
-        };
-        deserialize_row(row_slot, &mut row_data).unwrap();
+    let deleted_rows = delete_key_range(table, first, last)?;
+    info!("deleted {} rows", deleted_rows);
 
-        info!(
-            "id: {}, username: {}, email: {}",
-            row_data.id, row_data.username, row_data.email
-        );
+    Ok(deleted_rows)
+}
 
-        cursor.advance_cursor();
-        end_of_table = cursor.end_of_table;
+/**
+ * Hands the rows with the smallest keys, up to the limit, to on_row and deletes them, so
+ * rows used as a queue are taken by exactly one statement. Expired rows are skipped, and
+ * deleted along with the rows taken when their keys are in between.
+ */
+fn execute_select_for_delete_statement(
+    statement: Statement,
+    table: &mut Table,
+    on_row: RowSink,
+) -> Result<u32, &'static str> {
+    let expired_keys = table.expired_keys()?;
+    let keys: Vec<u32> = Cursor::table_start(table)
+        .keys()
+        .filter(|key| !expired_keys.contains(key))
+        .take(statement.limit.unwrap())
+        .collect();
+    let (Some(&first), Some(&last)) = (keys.first(), keys.last()) else {
+        return Ok(0);
+    };
+
+    for key in keys {
+        on_row(&returned_row(table, key, &statement.columns)?);
     }
+    let deleted_rows = delete_key_range(table, first, last)?;
+    info!("deleted {} rows", deleted_rows);
 
-    Ok(())
+    Ok(deleted_rows)
 }
 
-fn execute_insert_statement(statement: Statement, table: &mut Table) -> Result<(), &'static str> {
-    let row = &statement.row_to_insert;
-    let key_to_insert = row.id;
+/**
+ * Deletes the rows with keys first..=last a leaf at a time instead of row by row. Leaves the
+ * range covers entirely are unlinked from their parent and from the leaf chain and freed,
+ * the leaves at either end of the range are trimmed. Returns the number of deleted rows.
+ */
+pub(crate) fn delete_key_range(
+    table: &mut Table,
+    first: u32,
+    last: u32,
+) -> Result<u32, &'static str> {
+    if first > last {
+        return Ok(0);
+    }
+
+    let mut page_num = Cursor::table_find(table, first).page_num;
+    let mut previous_leaf = previous_leaf(table, first);
+    let mut deleted_rows = 0;
+
+    loop {
+        let node = table.pager.get_page_leaf(page_num as usize)?;
+        let num_cells = node.num_cells();
+        let next_leaf = node.next_leaf();
+        let (is_root, parent) = (node.is_root(), node.parent());
+        let start = node.lower_bound(first);
+        let end = match last.checked_add(1) {
+            Some(key) => node.lower_bound(key),
+            None => num_cells,
+        };
+
+        if table.changes.is_observed() {
+            for cell_num in start..end {
+                let leaf = table.pager.get_page_leaf(page_num as usize)?;
+                let row = read_row(leaf.get_cell_value(cell_num))?;
+                table.changes.record(ChangeEvent {
+                    table: TABLE_NAME,
+                    op: ChangeOp::Delete,
+                    key: row.id,
+                    before: Some(row),
+                    after: None,
+                });
+            }
+        }
+        deleted_rows += end - start;
+
+        let covers_leaf = start == 0 && end == num_cells;
+        if !(covers_leaf && !is_root && unlink_leaf(table, page_num, previous_leaf)?) {
+            table.pager.mark_dirty(page_num as usize);
+            table
+                .pager
+                .get_page_leaf(page_num as usize)?
+                .remove_cells(start, end);
+
+            if end == num_cells && end > start && !is_root {
+                InternalView::refresh_max_key(table, parent);
+            }
+            previous_leaf = Some(page_num);
+        }
+
+        // the range ends in this leaf
+        if end < num_cells || next_leaf == 0 {
+            break;
+        }
+        page_num = next_leaf;
+    }
+
+    let row_count = table.pager.row_count();
+    table.pager.set_row_count(row_count - deleted_rows);
 
-    let mut cursor = Cursor::table_find(table, key_to_insert);
+    if let Some(row_meta) = &mut table.row_meta {
+        row_meta.remove_range(first, last)?;
+    }
 
-    // info!("Insert cursor is at cell_num: {}", cursor.cell_num);
+    Ok(deleted_rows)
+}
 
-    let node = cursor
-        .table
+/**
+ * Unlinks the leaf from its parent and from the leaf before it, and frees its page. Returns
+ * false, leaving the leaf in place, when it is the only child of its parent.
+ */
+fn unlink_leaf(
+    table: &mut Table,
+    page_num: u32,
+    previous_leaf: Option<u32>,
+) -> Result<bool, &'static str> {
+    let leaf = table.pager.get_page_leaf(page_num as usize)?;
+    let next_leaf = leaf.next_leaf();
+    let parent = leaf.parent();
+
+    table.pager.mark_dirty(parent as usize);
+    if !table
         .pager
-        .get_page_leaf(cursor.page_num as usize)
-        .unwrap();
+        .get_page_internal(parent as usize)?
+        .remove_child(page_num)
+    {
+        return Ok(false);
+    }
 
-    if cursor.cell_num < node.num_cells {
-        let key_at_index = node.get_cell_key(cursor.cell_num);
-        if key_at_index == key_to_insert {
-            return Err("Duplicate key detected");
+    if let Some(previous_leaf) = previous_leaf {
+        table.pager.mark_dirty(previous_leaf as usize);
+        table
+            .pager
+            .get_page_leaf(previous_leaf as usize)?
+            .set_next_leaf(next_leaf);
+    }
+    table.pager.free_page(page_num)?;
+    InternalView::refresh_max_key(table, parent);
+
+    Ok(true)
+}
+
+/**
+ * Leaf before the one key is searched in, None when that is the first leaf. It is the
+ * rightmost leaf of the child left of the search path, at the lowest node where there is one
+ */
+fn previous_leaf(table: &mut Table, key: u32) -> Option<u32> {
+    let mut page_num = table.root_page_num;
+    let mut left_subtree = None;
+
+    while let NodeType::Internal = table.pager.get_page_node_type(page_num as usize).unwrap() {
+        let node = table.pager.get_page_internal(page_num as usize).unwrap();
+        let child_index = node.find_child_index(key);
+        if child_index > 0 {
+            left_subtree = Some(node.get_child(child_index - 1));
         }
+        page_num = node.get_child(child_index);
     }
 
-    LeafNode::insert(&mut cursor, row.id, row);
+    let mut page_num = left_subtree?;
+    while let NodeType::Internal = table.pager.get_page_node_type(page_num as usize).unwrap() {
+        page_num = table
+            .pager
+            .get_page_internal(page_num as usize)
+            .unwrap()
+            .right_child();
+    }
 
-    Ok(())
+    Some(page_num)
+}
+
+/**
+ * Inserts a row into the table, failing on a duplicate key. The row is saved with the next
+ * flush of the pager.
+ */
+pub(crate) fn insert_row(table: &mut Table, row: &Row) -> Result<(), &'static str> {
+    let key_to_insert = row.id;
+
+    // checked before the leaf is touched, so a failed insert leaves no half-shifted cells
+    if row.username.len() > MAX_STRING_SIZE || row.email.len() > MAX_STRING_SIZE {
+        return Err("String is too long");
+    }
+
+    // keys past the max key can't be duplicates and go to the end of the rightmost leaf, which
+    // is kept from the previous append so ascending inserts skip both descents from the root
+    let is_append = table
+        .max_key()
+        .is_none_or(|max_key| key_to_insert > max_key);
+    if !is_append && table.contains_key(key_to_insert) {
+        return Err("Duplicate key detected");
+    }
+    // a duplicate fails the same way whether or not the db file is at its quota
+    check_insert_quota(table)?;
+
+    let mut cursor = match is_append.then(|| table.append_leaf()).flatten() {
+        Some(page_num) => LeafView::node_find(table, page_num, key_to_insert),
+        None => Cursor::table_find(table, key_to_insert),
+    };
+    let page_num = cursor.page_num;
+    LeafView::insert(&mut cursor, row.id, row, DuplicatePolicy::Reject)?;
+    if is_append {
+        table.remember_append_leaf(page_num);
+    }
+
+    record_insert(table, row)
 }
 
-pub fn serialize_row(source: &Row, destination: *mut u8) -> Result<(), &str> {
-    unsafe { return unsafe_serialize_row(source, destination) }
+/**
+ * Fails before an insert when the splits it could cause would grow the db file past its
+ * quota: one page for the leaf and one for each internal node above it, and one more when
+ * the root splits
+ */
+fn check_insert_quota(table: &mut Table) -> Result<(), &'static str> {
+    if table.pager.max_pages().is_none() {
+        return Ok(());
+    }
+
+    let split_pages = tree_height(table) as u32 + 1;
+    Ok(table.pager.check_quota(split_pages)?)
 }
 
-pub fn deserialize_row(source: *const u8, destination: &mut Row) -> Result<(), &str> {
-    unsafe { return unsafe_deserialize_row(source, destination) }
+/**
+ * Bookkeeping for a row just inserted into its leaf: the row count, the bloom filter, the
+ * hidden columns and the change event
+ */
+fn record_insert(table: &mut Table, row: &Row) -> Result<(), &'static str> {
+    let key_to_insert = row.id;
+    if let Some(bloom_filter) = &mut table.bloom_filter {
+        bloom_filter.insert(key_to_insert);
+    }
+
+    let row_count = table.pager.row_count();
+    table.pager.set_row_count(row_count + 1);
+
+    // the write is saved by the next flush, which bumps the change counter
+    let txn_id = u64::from(table.pager.change_counter()) + 1;
+    if let Some(row_meta) = &mut table.row_meta {
+        row_meta.record_insert(key_to_insert, txn_id)?;
+    }
+
+    table.changes.record(ChangeEvent {
+        table: TABLE_NAME,
+        op: ChangeOp::Insert,
+        key: key_to_insert,
+        before: None,
+        after: Some(row.clone()),
+    });
+
+    Ok(())
 }
 
-unsafe fn unsafe_serialize_row(source: &Row, destination: *mut u8) -> Result<(), &str> {
+pub fn serialize_row(source: &Row, destination: &mut [u8]) -> Result<(), &'static str> {
     // Serialize ID
-    std::ptr::copy_nonoverlapping(
-        &source.id as *const _ as *const u8,
-        destination.offset(ID_OFFSET as isize),
-        ID_SIZE,
-    );
+    destination[ID_OFFSET..ID_OFFSET + ID_SIZE].copy_from_slice(&source.id.to_ne_bytes());
 
     // Serialize Username
     if source.username.len() > MAX_STRING_SIZE {
         return Err("Username is too long!");
     }
     let username_bytes = source.username.as_bytes();
-    std::ptr::write_bytes(
-        destination.offset(USERNAME_OFFSET as isize),
-        0,
-        USERNAME_SIZE,
-    );
-    std::ptr::copy_nonoverlapping(
-        username_bytes.as_ptr(),
-        destination.offset(USERNAME_OFFSET as isize),
-        username_bytes.len(),
-    );
+    let username_slot = &mut destination[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE];
+    username_slot.fill(0);
+    username_slot[..username_bytes.len()].copy_from_slice(username_bytes);
 
     // Serialize Email
     if source.email.len() > MAX_STRING_SIZE {
         return Err("Email is too long!");
     }
     let email_bytes = source.email.as_bytes();
-    std::ptr::write_bytes(destination.offset(EMAIL_OFFSET as isize), 0u8, EMAIL_SIZE);
-    std::ptr::copy_nonoverlapping(
-        email_bytes.as_ptr(),
-        destination.offset(EMAIL_OFFSET as isize),
-        email_bytes.len(),
-    );
+    let email_slot = &mut destination[EMAIL_OFFSET..EMAIL_OFFSET + EMAIL_SIZE];
+    email_slot.fill(0);
+    email_slot[..email_bytes.len()].copy_from_slice(email_bytes);
 
     Ok(())
 }
 
-unsafe fn unsafe_deserialize_row(source: *const u8, destination: &mut Row) -> Result<(), &str> {
-    // SAFER: Deserialize ID
-    let id_slice = std::slice::from_raw_parts(source.offset(ID_OFFSET as isize), ID_SIZE);
-    let id = u32::from_ne_bytes(id_slice.try_into().unwrap());
+/**
+ * Value of a hidden column, empty for rows written without row metadata
+ */
+fn meta_column(meta: Option<&RowMeta>, column: Column) -> String {
+    let value = meta.and_then(|meta| match column {
+        Column::ExpiresAt => meta.expires_at,
+        Column::CreatedAt => Some(meta.created_at),
+        Column::UpdatedAt => Some(meta.updated_at),
+        Column::TxnId => Some(meta.txn_id),
+        Column::Version => Some(meta.version),
+        Column::Id
+        | Column::Username
+        | Column::Email
+        | Column::Tree
+        | Column::Name
+        | Column::Value
+        | Column::Table
+        | Column::Rows
+        | Column::Pages
+        | Column::Columns
+        | Column::Count
+        | Column::Plan => None,
+    });
+
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn read_key(source: &[u8]) -> u32 {
+    u32::from_ne_bytes(source[ID_OFFSET..ID_OFFSET + ID_SIZE].try_into().unwrap())
+}
+
+/**
+ * Reads a single column of a serialized row, formatted for display without its padding
+ */
+pub fn deserialize_column(source: &[u8], column: Column) -> Result<String, &'static str> {
+    match column {
+        Column::Id => {
+            let id_slice = &source[ID_OFFSET..ID_OFFSET + ID_SIZE];
+            Ok(u32::from_ne_bytes(id_slice.try_into().unwrap()).to_string())
+        }
+        Column::Username => {
+            let username_slice = &source[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE];
+            match std::str::from_utf8(username_slice) {
+                Ok(username) => Ok(username.trim_end_matches('\0').to_string()),
+                Err(_) => Err("Username is not valid utf-8!"),
+            }
+        }
+        Column::Email => {
+            let email_slice = &source[EMAIL_OFFSET..EMAIL_OFFSET + EMAIL_SIZE];
+            match std::str::from_utf8(email_slice) {
+                Ok(email) => Ok(email.trim_end_matches('\0').to_string()),
+                Err(_) => Err("Email is not valid utf-8!"),
+            }
+        }
+        Column::ExpiresAt
+        | Column::CreatedAt
+        | Column::UpdatedAt
+        | Column::TxnId
+        | Column::Version => Err("Hidden columns are not stored in the row"),
+        Column::Tree => Err("Tree lines are not stored in the row"),
+        Column::Name
+        | Column::Value
+        | Column::Table
+        | Column::Rows
+        | Column::Pages
+        | Column::Columns => Err("Show columns are not stored in the row"),
+        Column::Count => Err("Counts are not stored in the row"),
+        Column::Plan => Err("Query plans are not stored in the row"),
+    }
+}
+
+/**
+ * Reads a serialized row without the padding of its strings
+ */
+pub(crate) fn read_row(source: &[u8]) -> Result<Row, &'static str> {
+    Ok(Row {
+        id: u32::from_ne_bytes(source[ID_OFFSET..ID_OFFSET + ID_SIZE].try_into().unwrap()),
+        username: deserialize_column(source, Column::Username)?,
+        email: deserialize_column(source, Column::Email)?,
+    })
+}
 
-    // SAFER: Deserialize USERNAME
-    let username_slice =
-        std::slice::from_raw_parts(source.offset(USERNAME_OFFSET as isize), USERNAME_SIZE);
-    let username = std::str::from_utf8(username_slice).unwrap().to_string();
+pub fn deserialize_row(source: &[u8], destination: &mut Row) -> Result<(), &'static str> {
+    // Deserialize ID
+    let id_slice = &source[ID_OFFSET..ID_OFFSET + ID_SIZE];
+    let id = u32::from_ne_bytes(id_slice.try_into().unwrap());
 
-    // SAFER: Deserialize EMAIL
-    let email_slice = std::slice::from_raw_parts(source.offset(EMAIL_OFFSET as isize), EMAIL_SIZE);
-    let email = std::str::from_utf8(email_slice).unwrap().to_string();
+    // Deserialize USERNAME
+    let username_slice = &source[USERNAME_OFFSET..USERNAME_OFFSET + USERNAME_SIZE];
+    let username = match std::str::from_utf8(username_slice) {
+        Ok(username) => username.to_string(),
+        Err(_) => return Err("Username is not valid utf-8!"),
+    };
+
+    // Deserialize EMAIL
+    let email_slice = &source[EMAIL_OFFSET..EMAIL_OFFSET + EMAIL_SIZE];
+    let email = match std::str::from_utf8(email_slice) {
+        Ok(email) => email.to_string(),
+        Err(_) => return Err("Email is not valid utf-8!"),
+    };
 
     destination.id = id;
     destination.username = username;
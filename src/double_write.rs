@@ -0,0 +1,155 @@
+use std::{fs::File, mem, os::unix::fs::FileExt};
+
+/*
+ * Buffer layout, every number in native byte order:
+ * db file length (u64) | slot count (u32) | slots | checksum (u64) of everything before it
+ * and every slot:
+ * offset in the db file (u64) | length (u32) | slot bytes
+ */
+const FILE_LENGTH_SIZE: usize = mem::size_of::<u64>();
+const SLOT_COUNT_SIZE: usize = mem::size_of::<u32>();
+const SLOT_OFFSET_SIZE: usize = mem::size_of::<u64>();
+const SLOT_LENGTH_SIZE: usize = mem::size_of::<u32>();
+const CHECKSUM_SIZE: usize = mem::size_of::<u64>();
+
+// offset in the db file and bytes of a saved slot
+type SavedSlot<'a> = (u64, &'a [u8]);
+
+/**
+ * Scratch file next to the db file (<db file>.dwb) that the slots of a flush are written to
+ * and synced before they overwrite their place in the db file. A crash in the middle of a
+ * page write can leave the page half old and half new, and the buffer still has the whole
+ * new page to copy over it when the db is opened again. A crash while the buffer itself is
+ * written leaves it without a valid checksum, and the db file untouched.
+ */
+pub(crate) struct DoubleWriteBuffer {
+    file: File,
+}
+
+impl DoubleWriteBuffer {
+    pub(crate) fn open(db_file_path: &str) -> Result<DoubleWriteBuffer, &'static str> {
+        match File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(format!("{}.dwb", db_file_path))
+        {
+            Ok(file) => Ok(DoubleWriteBuffer { file }),
+            Err(_) => Err("Error opening double write buffer"),
+        }
+    }
+
+    /**
+     * Saves the slots about to be written and syncs them, along with the length the db file
+     * has once they are
+     */
+    pub(crate) fn save(
+        &mut self,
+        file_length: u64,
+        slots: &[(u64, Vec<u8>)],
+    ) -> Result<(), &'static str> {
+        let mut buffer = vec![];
+        buffer.extend_from_slice(&file_length.to_ne_bytes());
+        buffer.extend_from_slice(&(slots.len() as u32).to_ne_bytes());
+        for (offset, slot) in slots {
+            buffer.extend_from_slice(&offset.to_ne_bytes());
+            buffer.extend_from_slice(&(slot.len() as u32).to_ne_bytes());
+            buffer.extend_from_slice(slot);
+        }
+        buffer.extend_from_slice(&checksum(&buffer).to_ne_bytes());
+
+        if self.file.set_len(0).is_err()
+            || self.file.write_all_at(&buffer, 0).is_err()
+            || self.file.sync_data().is_err()
+        {
+            return Err("Error saving double write buffer");
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Empties the buffer once its slots are synced to the db file. Left unsynced, since
+     * copying the same slots again after a crash changes nothing.
+     */
+    pub(crate) fn clear(&mut self) -> Result<(), &'static str> {
+        match self.file.set_len(0) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Error clearing double write buffer"),
+        }
+    }
+
+    /**
+     * Copies the slots of a saved buffer over the db file and syncs it, then empties the
+     * buffer. Returns the number of restored slots, 0 when the buffer is empty or was never
+     * completely saved.
+     */
+    pub(crate) fn restore(&mut self, db_file: &File) -> Result<usize, &'static str> {
+        let mut buffer = vec![];
+        let length = match self.file.metadata() {
+            Ok(metadata) => metadata.len() as usize,
+            Err(_) => return Err("Error reading double write buffer"),
+        };
+        buffer.resize(length, 0u8);
+        if self.file.read_exact_at(&mut buffer, 0).is_err() {
+            return Err("Error reading double write buffer");
+        }
+
+        let Some((file_length, slots)) = parse_buffer(&buffer) else {
+            self.clear()?;
+            return Ok(0);
+        };
+
+        if db_file.set_len(file_length).is_err() {
+            return Err("Error restoring pages from double write buffer");
+        }
+        for (offset, slot) in &slots {
+            if db_file.write_all_at(slot, *offset).is_err() {
+                return Err("Error restoring pages from double write buffer");
+            }
+        }
+        if db_file.sync_data().is_err() {
+            return Err("Error syncing db file!");
+        }
+
+        self.clear()?;
+        Ok(slots.len())
+    }
+}
+
+/**
+ * Db file length and slots of a completely saved buffer, None for an empty or torn one
+ */
+fn parse_buffer(buffer: &[u8]) -> Option<(u64, Vec<SavedSlot<'_>>)> {
+    let checksum_offset = buffer.len().checked_sub(CHECKSUM_SIZE)?;
+    let (contents, saved_checksum) = buffer.split_at(checksum_offset);
+    if u64::from_ne_bytes(saved_checksum.try_into().unwrap()) != checksum(contents) {
+        return None;
+    }
+
+    let mut reader = contents;
+    let mut take = |size: usize| {
+        let (field, rest) = reader.split_at_checked(size)?;
+        reader = rest;
+        Some(field)
+    };
+
+    let file_length = u64::from_ne_bytes(take(FILE_LENGTH_SIZE)?.try_into().unwrap());
+    let slot_count = u32::from_ne_bytes(take(SLOT_COUNT_SIZE)?.try_into().unwrap());
+    let mut slots = vec![];
+    for _ in 0..slot_count {
+        let offset = u64::from_ne_bytes(take(SLOT_OFFSET_SIZE)?.try_into().unwrap());
+        let length = u32::from_ne_bytes(take(SLOT_LENGTH_SIZE)?.try_into().unwrap());
+        slots.push((offset, take(length as usize)?));
+    }
+
+    Some((file_length, slots))
+}
+
+// FNV-1a, enough to tell a buffer cut short by a crash from a complete one
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
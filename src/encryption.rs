@@ -0,0 +1,242 @@
+use std::{fs::File, os::unix::fs::FileExt};
+
+const HEADER_MAGIC: &[u8; 8] = b"qba-enc\0";
+pub const ENCRYPTED_FILE_HEADER_SIZE: usize = 64;
+
+/*
+* Encrypted Page Layout: nonce | encrypted page | tag
+*/
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+pub const ENCRYPTED_PAGE_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/**
+ * Checks for the encrypted file header. Unencrypted files start with the "qba-db" table
+ * header, or with a node type byte for format version 0, neither of which matches the magic.
+ */
+pub fn is_encrypted_file(file: &File) -> bool {
+    let mut magic = [0u8; HEADER_MAGIC.len()];
+
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(_) => &magic == HEADER_MAGIC,
+        Err(_) => false,
+    }
+}
+
+#[cfg(feature = "encryption")]
+pub use aes_gcm_cipher::PageCipher;
+
+#[cfg(feature = "encryption")]
+mod aes_gcm_cipher {
+    use std::{fs::File, os::unix::fs::FileExt};
+
+    use aes_gcm::{
+        aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+        Aes256Gcm, Key, Nonce,
+    };
+    use pbkdf2::pbkdf2_hmac;
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::pager::PAGE_SIZE;
+
+    /*
+     * Encrypted File Header Layout
+     */
+    const HEADER_MAGIC_OFFSET: usize = 0;
+    const CIPHER_ID_OFFSET: usize = HEADER_MAGIC_OFFSET + HEADER_MAGIC.len();
+    const CIPHER_ID_SIZE: usize = 1;
+    const KDF_ITERATIONS_OFFSET: usize = CIPHER_ID_OFFSET + CIPHER_ID_SIZE;
+    const KDF_ITERATIONS_SIZE: usize = 4;
+    const KDF_SALT_OFFSET: usize = KDF_ITERATIONS_OFFSET + KDF_ITERATIONS_SIZE;
+    const KDF_SALT_SIZE: usize = 16;
+    const KEY_CHECK_OFFSET: usize = KDF_SALT_OFFSET + KDF_SALT_SIZE;
+    // nonce + tag of an empty message, used to reject a wrong passphrase on open
+    const KEY_CHECK_SIZE: usize = NONCE_SIZE + TAG_SIZE;
+
+    const CIPHER_ID_AES_256_GCM: u8 = 1;
+    const KDF_ITERATIONS: u32 = 100_000;
+    // iteration counts accepted from a file header, so a corrupt one can't derive a weak key
+    // or keep open busy for hours
+    const KDF_ITERATIONS_RANGE: std::ops::RangeInclusive<u32> = 10_000..=10_000_000;
+    const KEY_CHECK_AAD: &[u8] = b"qba-db key check";
+
+    /**
+     * Encrypts pages with AES-256-GCM using a key derived from the passphrase with
+     * PBKDF2-HMAC-SHA256. Each page gets a fresh nonce on every write and is authenticated
+     * together with its page number, so pages can't be swapped around in the file.
+     */
+    #[derive(Clone)]
+    pub struct PageCipher {
+        cipher: Aes256Gcm,
+    }
+
+    impl PageCipher {
+        /**
+         * Writes a new encryption header to an empty db file
+         */
+        pub fn create(file: &File, passphrase: &str) -> Result<PageCipher, &'static str> {
+            let mut salt = [0u8; KDF_SALT_SIZE];
+            OsRng.fill_bytes(&mut salt);
+
+            let page_cipher = PageCipher::from_passphrase(passphrase, &salt, KDF_ITERATIONS);
+
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let key_check_tag = match page_cipher.cipher.encrypt(
+                &nonce,
+                Payload {
+                    msg: &[],
+                    aad: KEY_CHECK_AAD,
+                },
+            ) {
+                Ok(tag) => tag,
+                Err(_) => return Err("Error creating encryption key check"),
+            };
+
+            let mut header = [0u8; ENCRYPTED_FILE_HEADER_SIZE];
+            header[HEADER_MAGIC_OFFSET..CIPHER_ID_OFFSET].copy_from_slice(HEADER_MAGIC);
+            header[CIPHER_ID_OFFSET] = CIPHER_ID_AES_256_GCM;
+            header[KDF_ITERATIONS_OFFSET..KDF_SALT_OFFSET]
+                .copy_from_slice(&KDF_ITERATIONS.to_ne_bytes());
+            header[KDF_SALT_OFFSET..KEY_CHECK_OFFSET].copy_from_slice(&salt);
+            header[KEY_CHECK_OFFSET..KEY_CHECK_OFFSET + NONCE_SIZE].copy_from_slice(&nonce);
+            header[KEY_CHECK_OFFSET + NONCE_SIZE..KEY_CHECK_OFFSET + KEY_CHECK_SIZE]
+                .copy_from_slice(&key_check_tag);
+
+            if file.write_all_at(&header, 0).is_err() {
+                return Err("Error writing encryption header to db file");
+            }
+
+            Ok(page_cipher)
+        }
+
+        /**
+         * Reads the encryption header of a db file and checks the passphrase against it
+         */
+        pub fn open(file: &File, passphrase: &str) -> Result<PageCipher, &'static str> {
+            let mut header = [0u8; ENCRYPTED_FILE_HEADER_SIZE];
+            if file.read_exact_at(&mut header, 0).is_err() {
+                return Err("Error reading encryption header from db file");
+            }
+
+            if header[CIPHER_ID_OFFSET] != CIPHER_ID_AES_256_GCM {
+                return Err("Db file is encrypted with an unsupported cipher");
+            }
+
+            let kdf_iterations = u32::from_ne_bytes(
+                header[KDF_ITERATIONS_OFFSET..KDF_SALT_OFFSET]
+                    .try_into()
+                    .unwrap(),
+            );
+            if !KDF_ITERATIONS_RANGE.contains(&kdf_iterations) {
+                return Err("Db file has an invalid key derivation iteration count. Corrupt file");
+            }
+            let salt = &header[KDF_SALT_OFFSET..KEY_CHECK_OFFSET];
+            let page_cipher = PageCipher::from_passphrase(passphrase, salt, kdf_iterations);
+
+            let nonce = Nonce::from_slice(&header[KEY_CHECK_OFFSET..KEY_CHECK_OFFSET + NONCE_SIZE]);
+            let key_check_tag =
+                &header[KEY_CHECK_OFFSET + NONCE_SIZE..KEY_CHECK_OFFSET + KEY_CHECK_SIZE];
+            match page_cipher.cipher.decrypt(
+                nonce,
+                Payload {
+                    msg: key_check_tag,
+                    aad: KEY_CHECK_AAD,
+                },
+            ) {
+                Ok(_) => Ok(page_cipher),
+                Err(_) => Err("Wrong passphrase for encrypted db file"),
+            }
+        }
+
+        fn from_passphrase(passphrase: &str, salt: &[u8], kdf_iterations: u32) -> PageCipher {
+            let mut key = [0u8; 32];
+            pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, kdf_iterations, &mut key);
+
+            PageCipher {
+                cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+            }
+        }
+
+        pub fn encrypt_page(&self, page_num: usize, page: &[u8]) -> Result<Vec<u8>, &'static str> {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let page_num_bytes = (page_num as u32).to_ne_bytes();
+
+            let encrypted_page = match self.cipher.encrypt(
+                &nonce,
+                Payload {
+                    msg: page,
+                    aad: &page_num_bytes,
+                },
+            ) {
+                Ok(encrypted_page) => encrypted_page,
+                Err(_) => return Err("Error encrypting page"),
+            };
+
+            let mut page_slot = Vec::with_capacity(PAGE_SIZE + ENCRYPTED_PAGE_OVERHEAD);
+            page_slot.extend_from_slice(&nonce);
+            page_slot.extend_from_slice(&encrypted_page);
+
+            Ok(page_slot)
+        }
+
+        pub fn decrypt_page(
+            &self,
+            page_num: usize,
+            page_slot: &[u8],
+            destination: &mut [u8],
+        ) -> Result<(), &'static str> {
+            let page_num_bytes = (page_num as u32).to_ne_bytes();
+
+            match self.cipher.decrypt(
+                Nonce::from_slice(&page_slot[..NONCE_SIZE]),
+                Payload {
+                    msg: &page_slot[NONCE_SIZE..],
+                    aad: &page_num_bytes,
+                },
+            ) {
+                Ok(page) => {
+                    destination.copy_from_slice(&page);
+                    Ok(())
+                }
+                Err(_) => Err("Error decrypting page. Corrupt file"),
+            }
+        }
+    }
+}
+
+/**
+ * Stand-in used when qba-db is built without the encryption feature. It can't be
+ * constructed, so opening or creating an encrypted db fails with an error.
+ */
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub struct PageCipher {
+    _private: (),
+}
+
+#[cfg(not(feature = "encryption"))]
+impl PageCipher {
+    const NOT_SUPPORTED: &'static str = "qba-db was built without the encryption feature";
+
+    pub fn create(_file: &File, _passphrase: &str) -> Result<PageCipher, &'static str> {
+        Err(Self::NOT_SUPPORTED)
+    }
+
+    pub fn open(_file: &File, _passphrase: &str) -> Result<PageCipher, &'static str> {
+        Err(Self::NOT_SUPPORTED)
+    }
+
+    pub fn encrypt_page(&self, _page_num: usize, _page: &[u8]) -> Result<Vec<u8>, &'static str> {
+        Err(Self::NOT_SUPPORTED)
+    }
+
+    pub fn decrypt_page(
+        &self,
+        _page_num: usize,
+        _page_slot: &[u8],
+        _destination: &mut [u8],
+    ) -> Result<(), &'static str> {
+        Err(Self::NOT_SUPPORTED)
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+
+/**
+ * Errors returned by the pager and by statements. Converts into the `&'static str` errors
+ * used by the rest of the db, so callers can keep using `?`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbError {
+    PageLimit,
+    PageNotFound(usize),
+    PageRequestedTwice(usize),
+    WrongNodeType(usize),
+    Cancelled,
+    // another connection holds a conflicting lock on the db file
+    Busy,
+    // the write could grow the db file past DbOptions::max_pages
+    QuotaExceeded,
+    Storage(&'static str),
+}
+
+impl DbError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            DbError::PageLimit => "Hit page limit for table",
+            DbError::PageNotFound(_) => "Requested page does not exist",
+            DbError::PageRequestedTwice(_) => "Tried to access same page num twice!",
+            DbError::WrongNodeType(_) => "Requested page holds a different node type",
+            DbError::Cancelled => "Statement was cancelled",
+            DbError::Busy => "Db file is locked by another connection",
+            DbError::QuotaExceeded => "Write would grow the db file past its page quota",
+            DbError::Storage(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::PageLimit
+            | DbError::Cancelled
+            | DbError::Busy
+            | DbError::QuotaExceeded
+            | DbError::Storage(_) => {
+                write!(f, "{}", self.message())
+            }
+            DbError::PageNotFound(page_num)
+            | DbError::PageRequestedTwice(page_num)
+            | DbError::WrongNodeType(page_num) => {
+                write!(f, "{} (page_num: {})", self.message(), page_num)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<&'static str> for DbError {
+    fn from(message: &'static str) -> Self {
+        DbError::Storage(message)
+    }
+}
+
+impl From<DbError> for &'static str {
+    fn from(error: DbError) -> Self {
+        error.message()
+    }
+}
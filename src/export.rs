@@ -0,0 +1,182 @@
+use crate::db::Row;
+
+/**
+ * Streams rows into a Parquet file with typed columns: id as a non-null uint32, username
+ * and email as utf8. Rows are buffered and written as one record batch per BATCH_ROWS rows,
+ * so exporting a table never holds more than one batch in memory.
+ */
+#[cfg(feature = "parquet")]
+pub struct ParquetExporter {
+    writer: parquet::arrow::ArrowWriter<std::fs::File>,
+    schema: arrow_schema::SchemaRef,
+    ids: Vec<u32>,
+    usernames: Vec<String>,
+    emails: Vec<String>,
+}
+
+#[cfg(feature = "parquet")]
+impl ParquetExporter {
+    const BATCH_ROWS: usize = 1024;
+
+    pub fn create(file_path: &str) -> Result<ParquetExporter, &'static str> {
+        use arrow_schema::{DataType, Field, Schema};
+        use std::{fs::File, sync::Arc};
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::UInt32, false),
+            Field::new("username", DataType::Utf8, false),
+            Field::new("email", DataType::Utf8, false),
+        ]));
+
+        let file = match File::create(file_path) {
+            Ok(file) => file,
+            Err(_) => return Err("Error creating export file"),
+        };
+
+        let writer = match parquet::arrow::ArrowWriter::try_new(file, Arc::clone(&schema), None) {
+            Ok(writer) => writer,
+            Err(_) => return Err("Error writing parquet header"),
+        };
+
+        Ok(ParquetExporter {
+            writer,
+            schema,
+            ids: Vec::with_capacity(Self::BATCH_ROWS),
+            usernames: Vec::with_capacity(Self::BATCH_ROWS),
+            emails: Vec::with_capacity(Self::BATCH_ROWS),
+        })
+    }
+
+    pub fn push(&mut self, row: Row) -> Result<(), &'static str> {
+        self.ids.push(row.id);
+        self.usernames.push(row.username);
+        self.emails.push(row.email);
+
+        if self.ids.len() >= Self::BATCH_ROWS {
+            self.write_batch()?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Writes the last batch and the parquet footer
+     */
+    pub fn finish(mut self) -> Result<(), &'static str> {
+        self.write_batch()?;
+
+        match self.writer.close() {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Error writing parquet footer"),
+        }
+    }
+
+    fn write_batch(&mut self) -> Result<(), &'static str> {
+        use arrow_array::{ArrayRef, RecordBatch, StringArray, UInt32Array};
+        use std::{mem, sync::Arc};
+
+        if self.ids.is_empty() {
+            return Ok(());
+        }
+
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt32Array::from(mem::take(&mut self.ids))),
+            Arc::new(StringArray::from(mem::take(&mut self.usernames))),
+            Arc::new(StringArray::from(mem::take(&mut self.emails))),
+        ];
+
+        let batch = match RecordBatch::try_new(Arc::clone(&self.schema), columns) {
+            Ok(batch) => batch,
+            Err(_) => return Err("Error building record batch"),
+        };
+
+        match self.writer.write(&batch) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Error writing record batch"),
+        }
+    }
+}
+
+/**
+ * Stand-in used when qba-db is built without the parquet feature. It can't be constructed,
+ * so exporting fails with an error.
+ */
+#[cfg(not(feature = "parquet"))]
+pub struct ParquetExporter {
+    _private: (),
+}
+
+#[cfg(not(feature = "parquet"))]
+impl ParquetExporter {
+    pub fn create(_file_path: &str) -> Result<ParquetExporter, &'static str> {
+        Err("qba-db was built without the parquet feature")
+    }
+
+    pub fn push(&mut self, _row: Row) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
+
+/*
+ * Snapshot Layout, every integer little endian and every string a u32 length followed by
+ * its utf-8 bytes:
+ * magic | version (u32) | table name | column count (u32) | column names | rows | row count (u32)
+ * where each row is id (u32) | username | email, in key order
+ */
+const SNAPSHOT_MAGIC: &[u8; 8] = b"qba-snap";
+const SNAPSHOT_VERSION: u32 = 1;
+
+/**
+ * Builds the canonical dump of a table returned by Db::export_snapshot. Only the schema and
+ * the row values are written, nothing about the pages holding them.
+ */
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+    rows: u32,
+}
+
+impl SnapshotWriter {
+    pub fn new(table_name: &str, column_names: &[&str]) -> SnapshotWriter {
+        let mut writer = SnapshotWriter {
+            bytes: SNAPSHOT_MAGIC.to_vec(),
+            rows: 0,
+        };
+
+        writer.write_u32(SNAPSHOT_VERSION);
+        writer.write_str(table_name);
+        writer.write_u32(column_names.len() as u32);
+        for name in column_names {
+            writer.write_str(name);
+        }
+
+        writer
+    }
+
+    /**
+     * Rows must be pushed in key order
+     */
+    pub fn push(&mut self, row: &Row) {
+        self.write_u32(row.id);
+        self.write_str(&row.username);
+        self.write_str(&row.email);
+        self.rows += 1;
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.write_u32(self.rows);
+        self.bytes
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_str(&mut self, value: &str) {
+        self.write_u32(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+}
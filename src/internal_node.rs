@@ -1,13 +1,14 @@
-use std::{mem, ptr};
+use std::mem;
 
 use log::info;
 
 use crate::{
-    cursor::Cursor,
+    cursor::{find_last_at_or_before, Cursor},
     db::Table,
+    error::DbError,
     leaf_node::{
-        LeafNode, COMMON_NODE_HEADER_SIZE, IS_ROOT_OFFSET, IS_ROOT_SIZE, NODE_TYPE_OFFSET,
-        NODE_TYPE_SIZE, PARENT_POINTER_OFFSET, PARENT_POINTER_SIZE,
+        read_u32, write_u32, LeafView, COMMON_NODE_HEADER_SIZE, IS_ROOT_OFFSET, NODE_TYPE_OFFSET,
+        PARENT_POINTER_OFFSET,
     },
     pager::{NodeType, PAGE_SIZE},
 };
@@ -19,8 +20,19 @@ const INTERNAL_NODE_NUM_KEYS_OFFSET: usize = COMMON_NODE_HEADER_SIZE;
 const INTERNAL_NODE_RIGHT_CHILD_SIZE: usize = mem::size_of::<u32>();
 const INTERNAL_NODE_RIGHT_CHILD_OFFSET: usize =
     INTERNAL_NODE_NUM_KEYS_OFFSET + INTERNAL_NODE_NUM_KEYS_SIZE;
-const INTERNAL_NODE_HEADER_SIZE: usize =
-    COMMON_NODE_HEADER_SIZE + INTERNAL_NODE_NUM_KEYS_SIZE + INTERNAL_NODE_RIGHT_CHILD_SIZE;
+// largest key in the subtree, so the last key of a table is read without walking to its leaf
+const INTERNAL_NODE_MAX_KEY_SIZE: usize = mem::size_of::<u32>();
+const INTERNAL_NODE_MAX_KEY_OFFSET: usize =
+    INTERNAL_NODE_RIGHT_CHILD_OFFSET + INTERNAL_NODE_RIGHT_CHILD_SIZE;
+// next internal node on the same level, 0 for the last one, like next_leaf for leaves
+const INTERNAL_NODE_NEXT_SIBLING_SIZE: usize = mem::size_of::<u32>();
+const INTERNAL_NODE_NEXT_SIBLING_OFFSET: usize =
+    INTERNAL_NODE_MAX_KEY_OFFSET + INTERNAL_NODE_MAX_KEY_SIZE;
+const INTERNAL_NODE_HEADER_SIZE: usize = COMMON_NODE_HEADER_SIZE
+    + INTERNAL_NODE_NUM_KEYS_SIZE
+    + INTERNAL_NODE_RIGHT_CHILD_SIZE
+    + INTERNAL_NODE_MAX_KEY_SIZE
+    + INTERNAL_NODE_NEXT_SIBLING_SIZE;
 
 /*
 * Internal Node Body Layout
@@ -30,178 +42,316 @@ const INTERNAL_NODE_CHILD_SIZE: usize = mem::size_of::<u32>();
 const INTERNAL_NODE_CELL_SIZE: usize = INTERNAL_NODE_KEY_SIZE + INTERNAL_NODE_CHILD_SIZE;
 
 const INTERNAL_NODE_SPACE_FOR_CELLS: usize = PAGE_SIZE - INTERNAL_NODE_HEADER_SIZE;
-const INTERNAL_NODE_MAX_CELLS: usize = INTERNAL_NODE_SPACE_FOR_CELLS / INTERNAL_NODE_CELL_SIZE;
-
-#[derive(Clone)]
-pub struct InternalNode {
-    pub is_root: bool,
-    pub parent_ptr: u32,
-    // internal_format
-    pub num_keys: u32,
-    pub right_child: u32,
-    pub cells: [(u32, u32); INTERNAL_NODE_MAX_CELLS],
+pub const INTERNAL_NODE_MAX_CELLS: usize = INTERNAL_NODE_SPACE_FOR_CELLS / INTERNAL_NODE_CELL_SIZE;
+
+/**
+ * Typed view over the bytes of an internal node page. Cells are stored as (key, page_num)
+ *
+ * Parent pointers are maintained eagerly: every split that moves a page under another node
+ * rewrites its parent pointer, so delete and split logic can follow them upwards without
+ * descending from the root. `.check` verifies them and Table::repair_parent_pointers
+ * rewrites them from a descent.
+ */
+pub struct InternalView<'a> {
+    data: &'a mut [u8],
 }
 
-impl InternalNode {
-    pub fn new() -> InternalNode {
-        return InternalNode {
-            is_root: false,
-            parent_ptr: 0,
-            num_keys: 0,
-            right_child: 0,
-            cells: [(0, 0); INTERNAL_NODE_MAX_CELLS], // stored as (key, page_num)
-        };
+impl<'a> InternalView<'a> {
+    pub fn new(data: &'a mut [u8]) -> InternalView<'a> {
+        InternalView { data }
     }
 
-    pub fn create_new_root_from_leaf(table: &mut Table, right_page_num: u32) {
+    /**
+     * Formats the page as an empty internal node
+     */
+    pub fn initialize(data: &'a mut [u8]) -> InternalView<'a> {
+        data.fill(0);
+        data[NODE_TYPE_OFFSET] = 0u8;
+
+        InternalView { data }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.data[IS_ROOT_OFFSET] == 1
+    }
+
+    pub fn set_is_root(&mut self, is_root: bool) {
+        self.data[IS_ROOT_OFFSET] = is_root as u8;
+    }
+
+    pub fn parent(&self) -> u32 {
+        read_u32(self.data, PARENT_POINTER_OFFSET)
+    }
+
+    pub fn set_parent(&mut self, parent: u32) {
+        write_u32(self.data, PARENT_POINTER_OFFSET, parent);
+    }
+
+    pub fn num_keys(&self) -> u32 {
+        read_u32(self.data, INTERNAL_NODE_NUM_KEYS_OFFSET)
+    }
+
+    pub fn set_num_keys(&mut self, num_keys: u32) {
+        write_u32(self.data, INTERNAL_NODE_NUM_KEYS_OFFSET, num_keys);
+    }
+
+    pub fn right_child(&self) -> u32 {
+        read_u32(self.data, INTERNAL_NODE_RIGHT_CHILD_OFFSET)
+    }
+
+    pub fn set_right_child(&mut self, right_child: u32) {
+        write_u32(self.data, INTERNAL_NODE_RIGHT_CHILD_OFFSET, right_child);
+    }
+
+    /**
+     * Largest key stored below the node, 0 when every leaf below it is empty
+     */
+    pub fn max_key(&self) -> u32 {
+        read_u32(self.data, INTERNAL_NODE_MAX_KEY_OFFSET)
+    }
+
+    pub fn set_max_key(&mut self, max_key: u32) {
+        write_u32(self.data, INTERNAL_NODE_MAX_KEY_OFFSET, max_key);
+    }
+
+    pub fn next_sibling(&self) -> u32 {
+        read_u32(self.data, INTERNAL_NODE_NEXT_SIBLING_OFFSET)
+    }
+
+    pub fn set_next_sibling(&mut self, next_sibling: u32) {
+        write_u32(self.data, INTERNAL_NODE_NEXT_SIBLING_OFFSET, next_sibling);
+    }
+
+    fn cell_offset(cell_num: u32) -> usize {
+        INTERNAL_NODE_HEADER_SIZE + cell_num as usize * INTERNAL_NODE_CELL_SIZE
+    }
+
+    pub fn cell(&self, cell_num: u32) -> (u32, u32) {
+        let offset = Self::cell_offset(cell_num);
+        (
+            read_u32(self.data, offset),
+            read_u32(self.data, offset + INTERNAL_NODE_KEY_SIZE),
+        )
+    }
+
+    pub fn set_cell(&mut self, cell_num: u32, (key, child): (u32, u32)) {
+        let offset = Self::cell_offset(cell_num);
+        write_u32(self.data, offset, key);
+        write_u32(self.data, offset + INTERNAL_NODE_KEY_SIZE, child);
+    }
+
+    pub fn create_new_root_from_leaf(
+        table: &mut Table,
+        right_page_num: u32,
+    ) -> Result<(), DbError> {
         /*
          * Old root node is the node we split into old_root & right_node
          * nowe we need to move the data from the old "left" node into a new page
          * and change the root back into a regular root node
          */
+        let root_page_num = table.root_page_num as usize;
         let left_child_page_num = table.pager.get_unused_page_num();
-        // info!(
-        //     "Creating node through create internal root node method at {}",
-        //     left_child_page_num
-        // );
-        let old_root_node = table
-            .pager
-            .get_page_leaf(table.root_page_num as usize)
-            .unwrap();
-
-        // write to new node
-        table.pager.pages[left_child_page_num as usize] =
-            (None, Some(Box::new(old_root_node.clone())));
 
-        // let left_child_node = table
-        //     .pager
-        //     .get_page_leaf(left_child_page_num as usize)
-        //     .unwrap();
+        // copy old root into new left node
+        table.pager.ensure_page_leaf(left_child_page_num as usize)?;
+        table
+            .pager
+            .copy_page(root_page_num, left_child_page_num as usize)?;
 
-        let (left_child_node, right_child_node) = table
+        let (mut left_child_node, mut right_child_node) = table
             .pager
-            .get_two_pages_leaf(left_child_page_num as usize, right_page_num as usize)
-            .unwrap();
+            .get_two_pages_leaf(left_child_page_num as usize, right_page_num as usize)?;
 
-        left_child_node.is_root = false;
+        left_child_node.set_is_root(false);
         let left_node_max_key = left_child_node.get_max_key();
+        let right_node_max_key = right_child_node.get_max_key();
 
-        left_child_node.parent = table.root_page_num;
-        right_child_node.parent = table.root_page_num;
+        left_child_node.set_parent(table.root_page_num);
+        right_child_node.set_parent(table.root_page_num);
 
         // make old root page num into internal node
-        table.pager.num_pages = table.pager.num_pages + 1;
-        table.pager.pages[table.root_page_num as usize] =
-            (Some(Box::new(InternalNode::new())), None);
-        let new_root_node = table
-            .pager
-            .get_page_internal(table.root_page_num as usize)
-            .unwrap();
-        new_root_node.is_root = true;
-        new_root_node.num_keys = 1;
+        let mut new_root_node = InternalView::initialize(table.pager.get_page_data(root_page_num)?);
+        new_root_node.set_is_root(true);
+        new_root_node.set_num_keys(1);
 
         // write child into cell for internal node
-        new_root_node.cells[0] = (left_node_max_key, left_child_page_num);
-        new_root_node.right_child = right_page_num;
+        new_root_node.set_cell(0, (left_node_max_key, left_child_page_num));
+        new_root_node.set_right_child(right_page_num);
+        new_root_node.set_max_key(right_node_max_key);
+
+        table.pager.mark_dirty(root_page_num);
+        table.pager.mark_dirty(left_child_page_num as usize);
+        table.pager.mark_dirty(right_page_num as usize);
+
+        Ok(())
     }
 
     pub fn update_internal_node_key(&mut self, old_max: u32, new_key: u32) {
         let old_child_index = self.find_child_index(old_max);
-        let old_tuple = self.cells[old_child_index as usize];
 
-        self.cells[old_child_index as usize] = (new_key, old_tuple.1);
-    }
+        // the right child has no key in its parent
+        if old_child_index == self.num_keys() {
+            return;
+        }
 
-    pub fn internal_node_insert(table: &mut Table, parent_page_num: usize, child_page_num: usize) {
-        let (parent, child, right_child) = table
-            .pager
-            .get_nodes_for_internal_node_insert(parent_page_num, child_page_num)
-            .unwrap();
+        let (_, child) = self.cell(old_child_index);
+        self.set_cell(old_child_index, (new_key, child));
+    }
 
-        let child_max_key = child.get_max_key();
-        let child_index = parent.find_child_index(child_max_key);
+    pub fn internal_node_insert(
+        table: &mut Table,
+        parent_page_num: usize,
+        child_page_num: usize,
+    ) -> Result<(), DbError> {
+        let pager = &mut table.pager;
+
+        let right_child_page_num = pager.get_page_internal(parent_page_num)?.right_child() as usize;
+        pager.mark_dirty(parent_page_num);
+
+        // page 0 is always the root, so 0 means a node that has no children yet
+        if right_child_page_num == 0 {
+            pager
+                .get_page_internal(parent_page_num)?
+                .set_right_child(child_page_num as u32);
+            InternalView::refresh_max_key(table, parent_page_num as u32);
+            return Ok(());
+        }
 
-        let original_num_keys = parent.num_keys;
-        parent.num_keys = original_num_keys + 1;
+        let mut pages = pager
+            .get_pages_mut(&[parent_page_num, child_page_num, right_child_page_num])?
+            .into_iter();
+        let mut parent = pages.next().unwrap().internal()?;
+        let child_max_key = pages.next().unwrap().leaf()?.get_max_key();
+        let right_child_max_key = pages.next().unwrap().leaf()?.get_max_key();
 
+        let original_num_keys = parent.num_keys();
         if original_num_keys as usize >= INTERNAL_NODE_MAX_CELLS {
             panic!("NEED TO IMPLEMENT SPLITTING INTERNAL NODE!!");
         }
 
-        let right_child_page_num = parent.right_child as usize;
-        let right_child_max_key = right_child.get_max_key();
-
-        // info!(
-        //     "child_max_key: {}, right_child_max_key: {}",
-        //     child_max_key, right_child_max_key
-        // );
+        let child_index = parent.find_child_index(child_max_key);
+        parent.set_num_keys(original_num_keys + 1);
 
         if child_max_key > right_child_max_key {
-            // info!("Have to replace right child in parent internal node! Going to set right_child to {}", child_page_num);
             // replace right child
-            parent.cells[original_num_keys as usize] =
-                (right_child_max_key, right_child_page_num as u32);
-            parent.right_child = child_page_num as u32;
-
-            // info!(
-            //     "setting index {} to {:?}",
-            //     original_num_keys,
-            //     (right_child_max_key, right_child_page_num as u32)
-            // );
+            parent.set_cell(
+                original_num_keys,
+                (right_child_max_key, right_child_page_num as u32),
+            );
+            parent.set_right_child(child_page_num as u32);
         } else {
-            // info!("looping from {}..={}.rev()", child_index, original_num_keys);
-
             // make room for new cell
-            for i in (child_index..=original_num_keys).rev() {
-                // info!("setting index {} = {:?}", i, parent.cells[i as usize - 1]);
-                parent.cells[i as usize] = parent.cells[i as usize - 1];
+            for i in (child_index + 1..=original_num_keys).rev() {
+                let cell = parent.cell(i - 1);
+                parent.set_cell(i, cell);
             }
-            parent.cells[child_index as usize] = (child_max_key, child_page_num as u32);
-            // info!(
-            //     "adding new node: setting index {} = {:?}",
-            //     child_index,
-            //     (child_max_key, child_page_num as u32)
-            // );
+            parent.set_cell(child_index, (child_max_key, child_page_num as u32));
         }
+
+        InternalView::refresh_max_key(table, parent_page_num as u32);
+
+        Ok(())
+    }
+
+    /**
+     * Recomputes the max key of the internal node page_num after a leaf below it gained or lost
+     * its last key, and carries the change up to the root. Descends only the rightmost path,
+     * unless deletes emptied leaves on it.
+     */
+    pub(crate) fn refresh_max_key(table: &mut Table, page_num: u32) {
+        let mut page_num = page_num as usize;
+
+        loop {
+            let max_key = find_last_at_or_before(table, page_num as u32, u32::MAX)
+                .map_or(0, |(_, _, key)| key);
+            let node = table.pager.get_page_internal(page_num).unwrap();
+            if node.max_key() == max_key {
+                return;
+            }
+
+            table.pager.mark_dirty(page_num);
+            let mut node = table.pager.get_page_internal(page_num).unwrap();
+            node.set_max_key(max_key);
+            let (is_root, parent) = (node.is_root(), node.parent());
+
+            if is_root {
+                return;
+            }
+            page_num = parent as usize;
+        }
+    }
+
+    /**
+     * Removes the child and its cell. A removed right child is replaced by the child left of
+     * it. Returns false, leaving the node as it is, when child_page_num is not a child or the
+     * only one left.
+     */
+    pub fn remove_child(&mut self, child_page_num: u32) -> bool {
+        let num_keys = self.num_keys();
+        if num_keys == 0 {
+            return false;
+        }
+
+        if self.right_child() == child_page_num {
+            let (_, new_right_child) = self.cell(num_keys - 1);
+            self.set_right_child(new_right_child);
+        } else {
+            let Some(child_index) = (0..num_keys).find(|&i| self.cell(i).1 == child_page_num)
+            else {
+                return false;
+            };
+
+            for i in child_index + 1..num_keys {
+                let cell = self.cell(i);
+                self.set_cell(i - 1, cell);
+            }
+        }
+
+        self.set_num_keys(num_keys - 1);
+        true
     }
 
     pub fn get_child(&self, child_num: u32) -> u32 {
-        let num_keys = self.num_keys;
+        let num_keys = self.num_keys();
         if child_num > num_keys {
             panic!("Trying to access child outside of internal node bounds! child_num: {} > num_keys: {}", child_num, num_keys);
         } else if child_num == num_keys {
-            return self.right_child;
+            return self.right_child();
         }
 
-        self.cells[child_num as usize].1
+        self.cell(child_num).1
     }
 
-    pub fn node_find(table: &mut Table, page_num: u32, key: u32) -> Cursor {
+    pub fn node_find(table: &mut Table, page_num: u32, key: u32) -> Cursor<'_> {
         let node = table.pager.get_page_internal(page_num as usize).unwrap();
 
         let child_index = node.find_child_index(key);
         let child_page_num = node.get_child(child_index);
 
-        match table.pager.get_page_node_type(child_page_num as usize) {
+        match table
+            .pager
+            .get_page_node_type(child_page_num as usize)
+            .unwrap()
+        {
             NodeType::Internal => {
                 info!("Next node is internal");
-                return InternalNode::node_find(table, child_page_num, key);
+                InternalView::node_find(table, child_page_num, key)
             }
             NodeType::Leaf => {
                 info!("Next node is leaf");
-                return LeafNode::node_find(table, child_page_num, key);
+                LeafView::node_find(table, child_page_num, key)
             }
         }
     }
 
-    fn find_child_index(&self, key: u32) -> u32 {
+    pub(crate) fn find_child_index(&self, key: u32) -> u32 {
         // perform binary search on keys to find child index
         let mut min_index = 0;
-        let mut max_index = self.num_keys;
+        let mut max_index = self.num_keys();
 
         while min_index != max_index {
             let index = (min_index + max_index) / 2;
-            let key_to_right = self.cells[index as usize].0;
+            let key_to_right = self.cell(index).0;
 
             if key_to_right >= key {
                 max_index = index;
@@ -212,107 +362,4 @@ impl InternalNode {
 
         min_index
     }
-
-    pub fn deserialize_node(node: &mut InternalNode, destination: *mut u8) {
-        unsafe {
-            // write node type
-            ptr::write_bytes(
-                destination.offset(NODE_TYPE_OFFSET as isize),
-                0u8,
-                NODE_TYPE_SIZE,
-            );
-
-            // pub is_root: bool,
-            ptr::copy_nonoverlapping(
-                &node.is_root,
-                destination.offset(IS_ROOT_OFFSET as isize) as *mut bool,
-                IS_ROOT_SIZE,
-            );
-
-            // pub parent_ptr: u32
-            ptr::copy_nonoverlapping(
-                &node.parent_ptr as *const _ as *const u8,
-                destination.offset(PARENT_POINTER_OFFSET as isize) as *mut u8,
-                PARENT_POINTER_SIZE,
-            );
-
-            // pub num_keys: u32,
-            ptr::copy_nonoverlapping(
-                &node.num_keys as *const _ as *const u8,
-                destination.offset(INTERNAL_NODE_NUM_KEYS_OFFSET as isize) as *mut u8,
-                INTERNAL_NODE_NUM_KEYS_SIZE,
-            );
-
-            // pub right_child: u32
-            ptr::copy_nonoverlapping(
-                &node.right_child as *const _ as *const u8,
-                destination.offset(INTERNAL_NODE_RIGHT_CHILD_OFFSET as isize) as *mut u8,
-                INTERNAL_NODE_RIGHT_CHILD_SIZE,
-            );
-
-            // pub cells: Vec<u8>,
-            ptr::copy_nonoverlapping(
-                &node.cells as *const _ as *const u8,
-                destination.offset(INTERNAL_NODE_HEADER_SIZE as isize) as *mut u8,
-                INTERNAL_NODE_SPACE_FOR_CELLS,
-            );
-        }
-    }
-
-    pub fn serialize_node(source: *mut u8, dest: &mut InternalNode) {
-        unsafe {
-            let node_type_slice = std::slice::from_raw_parts(
-                source.offset(NODE_TYPE_OFFSET as isize),
-                NODE_TYPE_SIZE,
-            );
-            match node_type_slice.get(0) {
-                Some(&1) => panic!("Tried to deserialize leaf node into internal node!"),
-                Some(&0) => {}
-                _ => panic!("Invalid boolean value"),
-            };
-
-            // deserialize is_root
-            let is_root_slice =
-                std::slice::from_raw_parts(source.offset(IS_ROOT_OFFSET as isize), IS_ROOT_SIZE);
-            let is_root = match is_root_slice.get(0) {
-                Some(&0) => false,
-                Some(&1) => true,
-                _ => panic!("Invalid boolean value"),
-            };
-
-            // pub parent_ptr: Option<*mut u8>,
-            let parent_ptr_slice = std::slice::from_raw_parts(
-                source.offset(PARENT_POINTER_OFFSET as isize),
-                PARENT_POINTER_SIZE,
-            );
-            let parent_ptr = u32::from_ne_bytes(parent_ptr_slice.try_into().unwrap());
-
-            // pub num_keys: u32,
-            let num_keys_slice = std::slice::from_raw_parts(
-                source.offset(INTERNAL_NODE_NUM_KEYS_OFFSET as isize),
-                INTERNAL_NODE_NUM_KEYS_SIZE,
-            );
-            let num_keys = u32::from_ne_bytes(num_keys_slice.try_into().unwrap());
-
-            // pub right_child: u32
-            let right_child_slice = std::slice::from_raw_parts(
-                source.offset(INTERNAL_NODE_NUM_KEYS_SIZE as isize),
-                INTERNAL_NODE_RIGHT_CHILD_SIZE,
-            );
-            let right_child = u32::from_ne_bytes(right_child_slice.try_into().unwrap());
-
-            // pub cells: Vec<u8>,
-            let cells_slice = std::slice::from_raw_parts::<(u32, u32)>(
-                source.offset(INTERNAL_NODE_HEADER_SIZE as isize) as *mut (u32, u32),
-                INTERNAL_NODE_SPACE_FOR_CELLS,
-            );
-            let cells: [(u32, u32); INTERNAL_NODE_MAX_CELLS] = cells_slice.try_into().unwrap();
-
-            dest.is_root = is_root;
-            dest.parent_ptr = parent_ptr;
-            dest.num_keys = num_keys;
-            dest.right_child = right_child;
-            dest.cells = cells;
-        }
-    }
 }
@@ -0,0 +1,220 @@
+use std::{
+    mem,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    cursor::Cursor,
+    db::{DbOptions, Row, Table, ROW_SIZE},
+    leaf_node::{DuplicatePolicy, LeafView},
+};
+
+/*
+ * A value is stored in the row slot of its leaf cell:
+ * key (u32, where a row keeps its id) | value length (u16) | value bytes
+ */
+const VALUE_KEY_SIZE: usize = mem::size_of::<u32>();
+const VALUE_LENGTH_OFFSET: usize = VALUE_KEY_SIZE;
+const VALUE_LENGTH_SIZE: usize = mem::size_of::<u16>();
+const VALUE_OFFSET: usize = VALUE_LENGTH_OFFSET + VALUE_LENGTH_SIZE;
+
+pub const KV_MAX_VALUE_SIZE: usize = ROW_SIZE - VALUE_OFFSET;
+
+/**
+ * Ordered key-value store over the B-tree, without the statement parser. A Kv file keeps
+ * values where a db file keeps rows, so it should only be opened through Kv. Every put and
+ * delete locks the file against other connections and is committed before it returns.
+ */
+pub struct Kv {
+    table: Table,
+}
+
+impl Kv {
+    pub fn open(file_path: String) -> Result<Kv, &'static str> {
+        Kv::open_with_options(file_path, &DbOptions::default())
+    }
+
+    pub fn open_with_options(file_path: String, options: &DbOptions) -> Result<Kv, &'static str> {
+        Ok(Kv {
+            table: Table::open(file_path, options)?,
+        })
+    }
+
+    /**
+     * Inserts the value under key, replacing the value already stored there
+     */
+    pub fn put(&mut self, key: u32, value: &[u8]) -> Result<(), &'static str> {
+        if value.len() > KV_MAX_VALUE_SIZE {
+            return Err("Value is too long");
+        }
+
+        self.write(|table| {
+            // the cell is written as an empty row first, so the leaf split logic is shared
+            let placeholder = Row {
+                id: key,
+                username: String::new(),
+                email: String::new(),
+            };
+            let mut cursor = Cursor::table_find(table, key);
+            let is_new_key =
+                LeafView::insert(&mut cursor, key, &placeholder, DuplicatePolicy::Replace)?;
+            let mut cursor = Cursor::table_find(table, key);
+
+            cursor.table.pager.mark_dirty(cursor.page_num as usize);
+            let slot = Cursor::get_cursor_value(&mut cursor)?;
+            slot[VALUE_LENGTH_OFFSET..VALUE_OFFSET]
+                .copy_from_slice(&(value.len() as u16).to_ne_bytes());
+            slot[VALUE_OFFSET..VALUE_OFFSET + value.len()].copy_from_slice(value);
+            slot[VALUE_OFFSET + value.len()..].fill(0);
+
+            if is_new_key {
+                let row_count = table.pager.row_count();
+                table.pager.set_row_count(row_count + 1);
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn get(&mut self, key: u32) -> Result<Option<Vec<u8>>, &'static str> {
+        self.table.pager.lock_file(false)?;
+
+        let mut cursor = Cursor::table_find(&mut self.table, key);
+        let value = match cursor.get_cursor_key() == Some(key) {
+            true => Cursor::get_cursor_value(&mut cursor).map(|slot| Some(read_value(slot))),
+            false => Ok(None),
+        };
+        self.table.pager.unlock_file();
+
+        value
+    }
+
+    /**
+     * Removes key, returning whether it was stored
+     */
+    pub fn delete(&mut self, key: u32) -> Result<bool, &'static str> {
+        self.write(|table| {
+            let mut cursor = Cursor::table_find(table, key);
+
+            if cursor.get_cursor_key() != Some(key) {
+                return Ok(false);
+            }
+
+            LeafView::delete(&mut cursor);
+
+            let row_count = table.pager.row_count();
+            table.pager.set_row_count(row_count - 1);
+
+            Ok(true)
+        })
+    }
+
+    /**
+     * Length of the Kv file, as of the last commit
+     */
+    pub(crate) fn file_length(&self) -> u64 {
+        self.table.pager.file_length
+    }
+
+    /**
+     * Removes every key
+     */
+    pub fn clear(&mut self) -> Result<(), &'static str> {
+        self.write(|table| {
+            table.pager.truncate();
+            Ok(())
+        })
+    }
+
+    /**
+     * Iterates over the keys in range and their values, in key order. The file stays locked
+     * against writes from other connections until the iterator is dropped.
+     */
+    pub fn range(&mut self, range: impl RangeBounds<u32>) -> Result<KvRange<'_>, &'static str> {
+        self.table.pager.lock_file(false)?;
+
+        let (first_key, skip_first_key) = match range.start_bound() {
+            Bound::Included(&key) => (key, false),
+            Bound::Excluded(&key) => (key, true),
+            Bound::Unbounded => (0, false),
+        };
+
+        let mut cursor = Cursor::table_find(&mut self.table, first_key);
+        // the cursor may start past the last cell of a leaf, which is not yet the end of the
+        // table when later leaves hold larger keys
+        if cursor.get_cursor_key().is_none() {
+            cursor.end_of_table = false;
+            cursor.advance_cursor();
+        }
+        if skip_first_key && cursor.get_cursor_key() == Some(first_key) {
+            cursor.advance_cursor();
+        }
+
+        Ok(KvRange {
+            cursor,
+            end: range.end_bound().cloned(),
+        })
+    }
+
+    /**
+     * Runs a write under an exclusive lock on the file, after reloading the pages another
+     * connection changed, and commits it
+     */
+    fn write<T>(
+        &mut self,
+        write: impl FnOnce(&mut Table) -> Result<T, &'static str>,
+    ) -> Result<T, &'static str> {
+        self.table.pager.lock_file(true)?;
+
+        let result = write(&mut self.table).and_then(|value| {
+            self.table.commit()?;
+            Ok(value)
+        });
+        // kept while a failed write left changes behind, until they are flushed
+        self.table.pager.unlock_file();
+        self.table.pager.shrink_cache();
+
+        result
+    }
+}
+
+pub struct KvRange<'a> {
+    cursor: Cursor<'a>,
+    end: Bound<u32>,
+}
+
+impl Iterator for KvRange<'_> {
+    type Item = (u32, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.end_of_table {
+            return None;
+        }
+
+        let key = self.cursor.get_cursor_key()?;
+        let in_range = match self.end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            return None;
+        }
+
+        let value = read_value(Cursor::get_cursor_value(&mut self.cursor).ok()?);
+        self.cursor.advance_cursor();
+
+        Some((key, value))
+    }
+}
+
+impl Drop for KvRange<'_> {
+    fn drop(&mut self) {
+        self.cursor.table.pager.unlock_file();
+    }
+}
+
+fn read_value(slot: &[u8]) -> Vec<u8> {
+    let length = u16::from_ne_bytes(slot[VALUE_LENGTH_OFFSET..VALUE_OFFSET].try_into().unwrap());
+    slot[VALUE_OFFSET..VALUE_OFFSET + length as usize].to_vec()
+}
@@ -1,10 +1,11 @@
 use crate::{
     cursor::Cursor,
     db::{self, serialize_row, Row, Table},
-    internal_node::InternalNode,
+    internal_node::InternalView,
+    metrics::trace_span,
     pager::PAGE_SIZE,
 };
-use std::{mem, ptr};
+use std::mem;
 
 use db::ROW_SIZE;
 use log::info;
@@ -47,247 +48,288 @@ const LEAF_NODE_SPACE_FOR_CELLS: usize = PAGE_SIZE - LEAF_NODE_HEADER_SIZE;
 pub const LEAF_NODE_MAX_CELLS: usize = LEAF_NODE_SPACE_FOR_CELLS / LEAF_NODE_CELL_SIZE;
 
 /**
- * For splitting
+ * Number of cells kept in the old leaf when a leaf holding capacity cells splits to make room
+ * for one more. Appending past the last key of the rightmost leaf is taken as an ascending
+ * insert pattern: the old leaf keeps fill_factor of the cells, since no key will land in it
+ * again. Every other split is 50/50, with the odd cell going left.
  */
-const LEAF_NODE_RIGHT_SPLIT_COUNT: usize = (LEAF_NODE_MAX_CELLS + 1) / 2;
-const LEAF_NODE_LEFT_SPLIT_COUNT: usize = (LEAF_NODE_MAX_CELLS + 1) - LEAF_NODE_RIGHT_SPLIT_COUNT;
-
-#[derive(Clone)]
-pub struct LeafNode {
-    pub is_root: bool,
-    pub parent: u32,
-    // leaf_format
-    pub num_cells: u32,
-    pub next_leaf: u32,
-    pub cells: [u8; LEAF_NODE_SPACE_FOR_CELLS],
+pub fn left_split_count(capacity: usize, is_append: bool, fill_factor: f64) -> usize {
+    let half_count = (capacity + 1) - capacity.div_ceil(2);
+    if !is_append {
+        return half_count;
+    }
+
+    let left_count = ((capacity + 1) as f64 * fill_factor).round() as usize;
+    left_count.clamp(half_count, capacity)
 }
 
-impl LeafNode {
-    pub fn new() -> LeafNode {
-        return LeafNode {
-            is_root: false,
-            parent: 0,
-            next_leaf: 0,
-            num_cells: 0,
-            cells: [0; LEAF_NODE_SPACE_FOR_CELLS],
-        };
+/**
+ * What LeafView::insert does when the key is already in the leaf
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    // fail the insert, leaving the stored row as it is
+    Reject,
+    // overwrite the stored row
+    Replace,
+}
+
+pub fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(
+        data[offset..offset + mem::size_of::<u32>()]
+            .try_into()
+            .unwrap(),
+    )
+}
+
+pub fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + mem::size_of::<u32>()].copy_from_slice(&value.to_ne_bytes());
+}
+
+/**
+ * Typed view over the bytes of a leaf node page
+ */
+pub struct LeafView<'a> {
+    data: &'a mut [u8],
+}
+
+impl<'a> LeafView<'a> {
+    pub fn new(data: &'a mut [u8]) -> LeafView<'a> {
+        LeafView { data }
     }
 
-    fn get_cell(&mut self, cell_num: u32) -> *mut u8 {
-        unsafe {
-            self.cells
-                .as_mut_ptr()
-                .add(cell_num as usize * LEAF_NODE_CELL_SIZE)
-        }
+    /**
+     * Formats the page as an empty leaf node
+     */
+    pub fn initialize(data: &'a mut [u8]) -> LeafView<'a> {
+        data.fill(0);
+        data[NODE_TYPE_OFFSET] = 1u8;
+
+        LeafView { data }
     }
 
-    pub fn get_cell_key(&mut self, cell_num: u32) -> u32 {
-        unsafe {
-            let key_slice = std::slice::from_raw_parts(
-                self.get_cell(cell_num).add(LEAF_NODE_KEY_OFFSET),
-                LEAF_NODE_NUM_CELLS_SIZE,
-            );
-            u32::from_ne_bytes(key_slice.try_into().unwrap())
-        }
+    pub fn is_root(&self) -> bool {
+        self.data[IS_ROOT_OFFSET] == 1
     }
 
-    pub fn get_cell_value(&mut self, cell_num: u32) -> *mut u8 {
-        unsafe { self.get_cell(cell_num).add(LEAF_NODE_VALUE_OFFSET) }
+    pub fn set_is_root(&mut self, is_root: bool) {
+        self.data[IS_ROOT_OFFSET] = is_root as u8;
     }
 
-    pub fn deserialize_node(node: &mut LeafNode, destination: *mut u8) {
-        // write that this node is a leaf node
-        unsafe {
-            // write node type
-            ptr::write_bytes(
-                destination.offset(NODE_TYPE_OFFSET as isize),
-                1u8,
-                NODE_TYPE_SIZE,
-            );
-
-            // pub is_root: bool,
-            // info!("writing is root");
-            ptr::copy_nonoverlapping(
-                &node.is_root,
-                destination.offset(IS_ROOT_OFFSET as isize) as *mut bool,
-                IS_ROOT_SIZE,
-            );
-
-            // pub parent: Option<*mut u8>,
-            // info!("writing parent");
-            ptr::copy_nonoverlapping(
-                &node.parent as *const _ as *const u8,
-                destination.offset(PARENT_POINTER_OFFSET as isize) as *mut u8,
-                PARENT_POINTER_SIZE,
-            );
-
-            // pub num_cells: u32,
-            // info!("writing num_cells");
-            ptr::copy_nonoverlapping(
-                &node.num_cells as *const _ as *const u8,
-                destination.offset(LEAF_NODE_NUM_CELLS_OFFSET as isize) as *mut u8,
-                LEAF_NODE_NUM_CELLS_SIZE,
-            );
-
-            ptr::copy_nonoverlapping(
-                &node.next_leaf as *const _ as *const u8,
-                destination.offset(LEAF_NODE_NEXT_LEAF_OFFSET as isize) as *mut u8,
-                LEAF_NODE_NEXT_LEAF_SIZE,
-            );
-
-            // pub cells: Vec<u8>,
-            // info!("writing cells");
-            ptr::copy_nonoverlapping(
-                &node.cells as *const _ as *const u8,
-                destination.offset(LEAF_NODE_HEADER_SIZE as isize) as *mut u8,
-                LEAF_NODE_SPACE_FOR_CELLS,
-            );
-        }
+    pub fn parent(&self) -> u32 {
+        read_u32(self.data, PARENT_POINTER_OFFSET)
     }
 
-    pub fn serialize_node(source: *mut u8, dest: &mut LeafNode) {
-        unsafe {
-            let node_type_slice = std::slice::from_raw_parts(
-                source.offset(NODE_TYPE_OFFSET as isize),
-                NODE_TYPE_SIZE,
-            );
-            match node_type_slice.get(0) {
-                Some(&0) => panic!("Tried to deserialize internal node into leaf node!"),
-                Some(&1) => {}
-                _ => panic!("Invalid boolean value"),
-            };
+    pub fn set_parent(&mut self, parent: u32) {
+        write_u32(self.data, PARENT_POINTER_OFFSET, parent);
+    }
 
-            // deserialize is_root
-            let is_root_slice =
-                std::slice::from_raw_parts(source.offset(IS_ROOT_OFFSET as isize), IS_ROOT_SIZE);
-            let is_root = match is_root_slice.get(0) {
-                Some(&0) => false,
-                Some(&1) => true,
-                _ => panic!("Invalid boolean value"),
-            };
+    pub fn num_cells(&self) -> u32 {
+        read_u32(self.data, LEAF_NODE_NUM_CELLS_OFFSET)
+    }
 
-            // pub parent: Option<*mut u8>,
-            let parent_slice = std::slice::from_raw_parts(
-                source.offset(PARENT_POINTER_OFFSET as isize),
-                PARENT_POINTER_SIZE,
-            );
-            let parent = u32::from_ne_bytes(parent_slice.try_into().unwrap());
-
-            // pub num_cells: u32,
-            let num_cells_slice = std::slice::from_raw_parts(
-                source.offset(LEAF_NODE_NUM_CELLS_OFFSET as isize),
-                LEAF_NODE_NUM_CELLS_SIZE,
-            );
-            let num_cells = u32::from_ne_bytes(num_cells_slice.try_into().unwrap());
-
-            // pub next_leaf: u32
-            let next_leaf_slice = std::slice::from_raw_parts(
-                source.offset(LEAF_NODE_NEXT_LEAF_OFFSET as isize),
-                LEAF_NODE_NEXT_LEAF_SIZE,
-            );
-            let next_leaf = u32::from_ne_bytes(next_leaf_slice.try_into().unwrap());
-
-            // pub cells: Vec<u8>,
-            let cells_slice = std::slice::from_raw_parts(
-                source.offset(LEAF_NODE_HEADER_SIZE as isize),
-                LEAF_NODE_SPACE_FOR_CELLS,
-            );
-            let cells: [u8; LEAF_NODE_SPACE_FOR_CELLS] = cells_slice.try_into().unwrap();
-
-            dest.is_root = is_root;
-            dest.parent = parent;
-            dest.num_cells = num_cells;
-            dest.next_leaf = next_leaf;
-            dest.cells = cells;
-        }
+    pub fn set_num_cells(&mut self, num_cells: u32) {
+        write_u32(self.data, LEAF_NODE_NUM_CELLS_OFFSET, num_cells);
     }
 
-    pub fn node_find(table: &mut Table, page_num: u32, key: u32) -> Cursor {
-        let node = table.pager.get_page_leaf(page_num as usize).unwrap();
-        let num_cells = node.num_cells;
-        let cell_num: u32 = {
-            let mut min_index = 0;
-            let mut max_index = num_cells;
+    pub fn next_leaf(&self) -> u32 {
+        read_u32(self.data, LEAF_NODE_NEXT_LEAF_OFFSET)
+    }
 
-            while min_index < max_index {
-                let index = (min_index + max_index) / 2;
+    pub fn set_next_leaf(&mut self, next_leaf: u32) {
+        write_u32(self.data, LEAF_NODE_NEXT_LEAF_OFFSET, next_leaf);
+    }
 
-                let key_at_index = node.get_cell_key(index);
+    fn cell_offset(cell_num: u32) -> usize {
+        LEAF_NODE_HEADER_SIZE + cell_num as usize * LEAF_NODE_CELL_SIZE
+    }
 
-                if key == key_at_index {
-                    break;
-                }
+    fn get_cell(&self, cell_num: u32) -> &[u8] {
+        let offset = Self::cell_offset(cell_num);
+        &self.data[offset..offset + LEAF_NODE_CELL_SIZE]
+    }
 
-                if key < key_at_index {
-                    max_index = index;
-                } else {
-                    min_index = index + 1;
-                }
+    fn get_cell_mut(&mut self, cell_num: u32) -> &mut [u8] {
+        let offset = Self::cell_offset(cell_num);
+        &mut self.data[offset..offset + LEAF_NODE_CELL_SIZE]
+    }
+
+    fn move_cell(&mut self, from_cell_num: u32, to_cell_num: u32) {
+        let from_offset = Self::cell_offset(from_cell_num);
+        self.data.copy_within(
+            from_offset..from_offset + LEAF_NODE_CELL_SIZE,
+            Self::cell_offset(to_cell_num),
+        );
+    }
+
+    pub fn get_cell_key(&self, cell_num: u32) -> u32 {
+        read_u32(self.get_cell(cell_num), LEAF_NODE_KEY_OFFSET)
+    }
+
+    /**
+     * Consumes the view so the row bytes can outlive it
+     */
+    pub fn get_cell_value(self, cell_num: u32) -> &'a mut [u8] {
+        let offset = Self::cell_offset(cell_num) + LEAF_NODE_VALUE_OFFSET;
+        &mut self.data[offset..offset + LEAF_NODE_VALUE_SIZE]
+    }
+
+    fn write_cell(destination: &mut [u8], key: u32, row: &Row) -> Result<(), &'static str> {
+        write_u32(destination, LEAF_NODE_KEY_OFFSET, key);
+        serialize_row(
+            row,
+            &mut destination[LEAF_NODE_VALUE_OFFSET..LEAF_NODE_VALUE_OFFSET + LEAF_NODE_VALUE_SIZE],
+        )
+    }
+
+    /**
+     * Position of the first cell with a key not less than key, num_cells if every key is
+     * less. This is the cell holding key if it is stored, or else where it would be inserted.
+     */
+    pub fn lower_bound(&self, key: u32) -> u32 {
+        let mut min_index = 0;
+        let mut max_index = self.num_cells();
+
+        // invariant: keys before min_index are less than key, keys from max_index on are not
+        while min_index < max_index {
+            let index = min_index + (max_index - min_index) / 2;
+
+            if self.get_cell_key(index) < key {
+                min_index = index + 1;
+            } else {
+                max_index = index;
             }
+        }
 
-            (min_index + max_index) / 2
+        min_index
+    }
+
+    /**
+     * Cursor at the lower bound of key in the leaf
+     */
+    pub fn node_find(table: &mut Table, page_num: u32, key: u32) -> Cursor<'_> {
+        let node = table.pager.get_page_leaf(page_num as usize).unwrap();
+        let cell_num = node.lower_bound(key);
+        let end_of_table = node.num_cells() == cell_num;
+        let cursor_key = match end_of_table {
+            true => key,
+            false => node.get_cell_key(cell_num),
         };
-        let end_of_table = node.num_cells == cell_num;
+        let generation = table.pager.page_generation(page_num as usize);
 
         Cursor {
             table,
             page_num,
             cell_num,
             end_of_table,
+            key: cursor_key,
+            generation,
         }
     }
 
-    pub fn insert(cursor: &mut Cursor, key: u32, row: &Row) {
-        let requires_split = LeafNode::requires_split_and_insert(cursor);
+    /**
+     * Inserts the row at the cursor, which must be at the lower bound of key. Returns whether
+     * a cell was added, false when the policy replaced the row already stored under key.
+     */
+    pub fn insert(
+        cursor: &mut Cursor,
+        key: u32,
+        row: &Row,
+        on_duplicate: DuplicatePolicy,
+    ) -> Result<bool, &'static str> {
+        if cursor.get_cursor_key() == Some(key) {
+            return match on_duplicate {
+                DuplicatePolicy::Reject => Err("Duplicate key detected"),
+                DuplicatePolicy::Replace => {
+                    let page_num = cursor.page_num as usize;
+                    cursor.table.pager.mark_dirty(page_num);
+                    let mut node = cursor.table.pager.get_page_leaf(page_num).unwrap();
+                    LeafView::write_cell(node.get_cell_mut(cursor.cell_num), key, row)?;
+                    Ok(false)
+                }
+            };
+        }
+
+        let requires_split = LeafView::requires_split_and_insert(cursor);
 
         if requires_split {
-            return LeafNode::split_and_insert(cursor, key, row);
+            LeafView::split_and_insert(cursor, key, row)?;
+            return Ok(true);
         }
 
         let page_num = cursor.page_num as usize;
-        let node = cursor.table.pager.get_page_leaf(page_num).unwrap();
-        let num_cells = node.num_cells;
+        cursor.table.pager.mark_dirty(page_num);
+        let mut node = cursor.table.pager.get_page_leaf(page_num).unwrap();
+        let num_cells = node.num_cells();
 
         if cursor.cell_num < num_cells {
             // make room for new cell
             for i in (cursor.cell_num + 1..=num_cells).rev() {
-                unsafe {
-                    ptr::copy_nonoverlapping(
-                        node.get_cell(i - 1) as *mut u8,
-                        node.get_cell(i) as *mut u8,
-                        LEAF_NODE_CELL_SIZE,
-                    );
-                }
+                node.move_cell(i - 1, i);
             }
         }
 
-        node.num_cells = num_cells + 1;
-        // save key
-        unsafe {
-            ptr::copy_nonoverlapping(
-                &key as *const _ as *const u8,
-                node.get_cell(cursor.cell_num).add(LEAF_NODE_KEY_OFFSET),
-                LEAF_NODE_KEY_SIZE,
-            );
+        node.set_num_cells(num_cells + 1);
+
+        // save key and row
+        LeafView::write_cell(node.get_cell_mut(cursor.cell_num), key, row)?;
+
+        // a new last key of the leaf may be the new max key of its parents
+        if cursor.cell_num == num_cells && !node.is_root() {
+            let parent_page_num = node.parent();
+            InternalView::refresh_max_key(cursor.table, parent_page_num);
+        }
+        Ok(true)
+    }
+
+    /**
+     * Removes the cell under the cursor. A leaf left empty stays in the tree, cursors skip
+     * over it.
+     */
+    pub fn delete(cursor: &mut Cursor) {
+        let page_num = cursor.page_num as usize;
+        cursor.table.pager.mark_dirty(page_num);
+        let mut node = cursor.table.pager.get_page_leaf(page_num).unwrap();
+        let num_cells = node.num_cells();
+
+        for i in cursor.cell_num + 1..num_cells {
+            node.move_cell(i, i - 1);
+        }
+
+        node.set_num_cells(num_cells - 1);
+
+        if cursor.cell_num == num_cells - 1 && !node.is_root() {
+            let parent_page_num = node.parent();
+            InternalView::refresh_max_key(cursor.table, parent_page_num);
         }
+    }
 
-        // save row
-        match serialize_row(row, node.get_cell_value(cursor.cell_num)) {
-            Ok(_) => {}
-            Err(e) => info!("Could not insert row! {}", e),
+    /**
+     * Removes the cells start..end, moving the cells after them down
+     */
+    pub(crate) fn remove_cells(&mut self, start: u32, end: u32) {
+        let num_cells = self.num_cells();
+
+        for i in end..num_cells {
+            self.move_cell(i, start + i - end);
         }
+
+        self.set_num_cells(num_cells - (end - start));
     }
 
     fn requires_split_and_insert(cursor: &mut Cursor) -> bool {
         let page_num = cursor.page_num as usize;
         let node = cursor.table.pager.get_page_leaf(page_num).unwrap();
-        let num_cells = node.num_cells;
+        let num_cells = node.num_cells();
 
-        return num_cells as usize >= LEAF_NODE_MAX_CELLS;
+        num_cells as usize >= LEAF_NODE_MAX_CELLS
     }
 
-    fn split_and_insert(cursor: &mut Cursor, key: u32, row: &Row) {
+    fn split_and_insert(cursor: &mut Cursor, key: u32, row: &Row) -> Result<(), &'static str> {
+        trace_span!("leaf_split", page_num = cursor.page_num);
+
         let pager = &mut cursor.table.pager;
+        pager.metrics.leaf_splits += 1;
 
         // Get old_node page first and store necessary info, if required
         let old_page_num = cursor.page_num as usize;
@@ -296,85 +338,89 @@ impl LeafNode {
         // ensure both pages exist
         pager.ensure_page_leaf(old_page_num).unwrap();
         pager.ensure_page_leaf(new_page_num).unwrap();
+        pager.mark_dirty(old_page_num);
+        pager.mark_dirty(new_page_num);
 
         let (mut old_node, mut new_node) = pager
             .get_two_pages_leaf(old_page_num, new_page_num)
             .unwrap();
         let old_max = old_node.get_max_key();
 
-        new_node.parent = old_node.parent;
+        let is_append =
+            cursor.cell_num as usize == LEAF_NODE_MAX_CELLS && old_node.next_leaf() == 0;
+        let left_count = left_split_count(LEAF_NODE_MAX_CELLS, is_append, cursor.table.fill_factor);
+        let right_count = LEAF_NODE_MAX_CELLS + 1 - left_count;
+
+        new_node.set_parent(old_node.parent());
 
         info!("old_num: {}, new_num: {}", old_page_num, new_page_num);
 
         // start from right side of leaf node and move cells over to new node
         for i in (0..=LEAF_NODE_MAX_CELLS).rev() {
-            let destination_node = {
-                if i >= LEAF_NODE_LEFT_SPLIT_COUNT {
-                    &mut new_node
-                } else {
-                    &mut old_node
-                }
+            let moves_to_new_node = i >= left_count;
+            let index_within_node = if moves_to_new_node {
+                (i - left_count) as u32
+            } else {
+                i as u32
             };
 
-            let index_within_node = i % LEAF_NODE_LEFT_SPLIT_COUNT;
-            let destination = destination_node.get_cell(index_within_node as u32);
-
             if i == cursor.cell_num as usize {
                 // save to cell
-                unsafe {
-                    ptr::copy_nonoverlapping(
-                        &key as *const _ as *const u8,
-                        destination.add(LEAF_NODE_KEY_OFFSET),
-                        LEAF_NODE_KEY_SIZE,
-                    );
-                    serialize_row(row, destination.add(LEAF_NODE_VALUE_OFFSET)).unwrap();
-                }
+                let destination = if moves_to_new_node {
+                    new_node.get_cell_mut(index_within_node)
+                } else {
+                    old_node.get_cell_mut(index_within_node)
+                };
+                LeafView::write_cell(destination, key, row)?;
             } else {
-                let cell_to_move = {
-                    if i > cursor.cell_num as usize {
-                        old_node.get_cell(i as u32 - 1)
-                    } else {
-                        old_node.get_cell(i as u32)
-                    }
+                let cell_to_move = if i > cursor.cell_num as usize {
+                    i as u32 - 1
+                } else {
+                    i as u32
                 };
 
-                unsafe {
-                    ptr::copy_nonoverlapping(cell_to_move, destination, LEAF_NODE_CELL_SIZE);
+                if moves_to_new_node {
+                    new_node
+                        .get_cell_mut(index_within_node)
+                        .copy_from_slice(old_node.get_cell(cell_to_move));
+                } else {
+                    old_node.move_cell(cell_to_move, index_within_node);
                 }
             }
         }
 
-        old_node.num_cells = LEAF_NODE_LEFT_SPLIT_COUNT as u32;
-        new_node.num_cells = LEAF_NODE_RIGHT_SPLIT_COUNT as u32;
+        old_node.set_num_cells(left_count as u32);
+        new_node.set_num_cells(right_count as u32);
 
-        new_node.next_leaf = old_node.next_leaf;
-        old_node.next_leaf = new_page_num as u32;
+        new_node.set_next_leaf(old_node.next_leaf());
+        old_node.set_next_leaf(new_page_num as u32);
 
-        if old_node.is_root {
-            return InternalNode::create_new_root_from_leaf(cursor.table, new_page_num as u32);
+        if old_node.is_root() {
+            InternalView::create_new_root_from_leaf(cursor.table, new_page_num as u32)?;
         } else {
-            let parent_page_num = old_node.parent;
-            let new_max = new_node.get_max_key();
+            let parent_page_num = old_node.parent();
+            let new_max = old_node.get_max_key();
 
-            let parent = pager.get_page_internal(parent_page_num as usize).unwrap();
+            pager.mark_dirty(parent_page_num as usize);
+            let mut parent = pager.get_page_internal(parent_page_num as usize).unwrap();
 
             parent.update_internal_node_key(old_max, new_max);
-            InternalNode::internal_node_insert(
+            InternalView::internal_node_insert(
                 cursor.table,
                 parent_page_num as usize,
                 new_page_num,
-            );
-
-            return;
+            )?;
         }
+
+        Ok(())
     }
 
-    pub fn get_max_key(&mut self) -> u32 {
-        self.get_cell_key(self.num_cells - 1)
+    pub fn get_max_key(&self) -> u32 {
+        self.get_cell_key(self.num_cells() - 1)
     }
 
-    pub fn print_node(&mut self) {
-        let num_cells = self.num_cells;
+    pub fn print_node(&self) {
+        let num_cells = self.num_cells();
         info!("- leaf (num_cells: {})", num_cells);
 
         for i in 0..num_cells {
@@ -1,5 +1,29 @@
+#[cfg(feature = "async")]
+pub mod async_db;
+mod bloom;
+pub mod cancel;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod changes;
 pub mod cursor;
 pub mod db;
+mod double_write;
+pub mod encryption;
+pub mod error;
+pub mod export;
 pub mod internal_node;
+pub mod kv;
 pub mod leaf_node;
+pub mod metrics;
+pub mod mmap;
 pub mod pager;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod row_meta;
+#[cfg(feature = "serde")]
+pub mod rows;
+pub mod salvage;
+pub mod settings;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod upgrade;
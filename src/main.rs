@@ -1,11 +1,98 @@
-use qba_db::db::Db;
+use std::io::{stdin, IsTerminal};
+use std::process::ExitCode;
 
-fn main() {
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
+use log::{error, info, LevelFilter};
+use qba_db::cancel::ProgressHandler;
+use qba_db::db::{Db, DbOptions};
+use qba_db::salvage;
 
-    let mut db = Db::new(String::from("test.db"));
-    db.run_db();
+// pages between progress lines of the salvage subcommand
+const SALVAGE_PROGRESS_PAGES: u32 = 1000;
 
-    db.close_db().unwrap();
+fn main() -> ExitCode {
+    // RUST_LOG still works, otherwise log at info until changed with .set log_level=<level>
+    let log_level_from_env = std::env::var_os("RUST_LOG").is_some();
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .parse_default_env()
+        .init();
+    if !log_level_from_env {
+        log::set_max_level(LevelFilter::Info);
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // usage: qba-db salvage <db_file> <out_file>
+    if args.first().is_some_and(|arg| arg == "salvage") {
+        let [_, file_path, out_file_path] = &args[..] else {
+            error!("usage: qba-db salvage <db_file> <out_file>");
+            return ExitCode::FAILURE;
+        };
+
+        // a long salvage shows it is still moving
+        let progress_handler = ProgressHandler::new(SALVAGE_PROGRESS_PAGES, |pages| {
+            info!("salvage read {} pages", pages);
+            false
+        });
+        return match salvage::salvage_with_progress(
+            file_path,
+            out_file_path,
+            Some(&progress_handler),
+        ) {
+            Ok(report) => {
+                info!(
+                    "recovered {} rows from {} pages into {}, {} damaged pages and {} duplicate rows skipped",
+                    report.rows,
+                    report.pages,
+                    out_file_path,
+                    report.damaged_pages,
+                    report.duplicate_rows
+                );
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                error!("{}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    // usage: qba-db [--continue-on-error] [db_file]
+    let mut file_path = String::from("test.db");
+    let mut continue_on_error = false;
+    for arg in args {
+        if arg == "--continue-on-error" {
+            continue_on_error = true;
+        } else {
+            file_path = arg;
+        }
+    }
+
+    let mut db = match Db::open(file_path, DbOptions::default()) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Err(e) = db.handle_exit_signals() {
+        error!("Error installing exit signal handlers: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let failed_statements = if stdin().is_terminal() {
+        db.run_db();
+        0
+    } else {
+        db.run_script(stdin().lock(), continue_on_error)
+    };
+
+    // dropping the db saves it to disk
+    drop(db);
+
+    if failed_statements > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
@@ -0,0 +1,40 @@
+/**
+ * Counters kept by the pager since the db was opened. Db::metrics returns a snapshot.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    // page lookups served from the page cache
+    pub cache_hits: u64,
+    // page lookups that had to read the page from the db file
+    pub cache_misses: u64,
+    pub page_reads: u64,
+    pub page_writes: u64,
+    // clean pages dropped from the page cache to keep it within its capacity
+    pub cache_evictions: u64,
+    pub leaf_splits: u64,
+}
+
+impl Metrics {
+    pub fn cache_hit_rate(&self) -> f64 {
+        let lookups = self.cache_hits + self.cache_misses;
+
+        if lookups == 0 {
+            return 0.0;
+        }
+
+        self.cache_hits as f64 / lookups as f64
+    }
+}
+
+/**
+ * Enters a tracing span until the end of the current scope. Compiles to nothing without the
+ * tracing feature.
+ */
+macro_rules! trace_span {
+    ($($span:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($($span)*).entered();
+    };
+}
+
+pub(crate) use trace_span;
@@ -0,0 +1,63 @@
+use std::fs::File;
+
+/**
+ * Read-only memory map of the db file. Pages are read straight out of the mapping instead of
+ * with a read syscall per page; writes still go through the pager's dirty page flush.
+ */
+#[cfg(feature = "mmap")]
+pub struct MappedFile {
+    map: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedFile {
+    pub fn map(file: &File) -> Result<MappedFile, &'static str> {
+        // SAFETY: other connections and processes can write and truncate the db file, so
+        // the map is only read while the pager holds a lock on the file, after
+        // reload_if_changed has mapped it again if its length changed. Connections only write
+        // the file under the exclusive lock, so it can't shrink under a read; a process
+        // writing it without taking the lock can still make a read fault. Every slice of the
+        // map is copied out before the pager's own next write.
+        match unsafe { memmap2::Mmap::map(file) } {
+            Ok(map) => Ok(MappedFile { map }),
+            Err(_) => Err("Error memory mapping db file"),
+        }
+    }
+
+    /**
+     * Returns `None` when the range lies past the end of the mapping, which happens for
+     * pages written after the file was last mapped
+     */
+    pub fn get(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        let start = offset as usize;
+        self.map.get(start..start + len)
+    }
+
+    pub fn mapped_length(&self) -> u64 {
+        self.map.len() as u64
+    }
+}
+
+/**
+ * Stand-in used when qba-db is built without the mmap feature. It can't be constructed, so
+ * asking for a memory mapped db fails with an error.
+ */
+#[cfg(not(feature = "mmap"))]
+pub struct MappedFile {
+    _private: (),
+}
+
+#[cfg(not(feature = "mmap"))]
+impl MappedFile {
+    pub fn map(_file: &File) -> Result<MappedFile, &'static str> {
+        Err("qba-db was built without the mmap feature")
+    }
+
+    pub fn get(&self, _offset: u64, _len: usize) -> Option<&[u8]> {
+        None
+    }
+
+    pub fn mapped_length(&self) -> u64 {
+        0
+    }
+}
@@ -1,21 +1,186 @@
 use std::{
-    fs::File,
-    io::{Read, Seek},
+    collections::BTreeSet,
+    fs::{File, TryLockError},
+    mem,
+    os::unix::fs::FileExt,
     path::Path,
+    thread,
+    time::{Duration, Instant},
 };
 
 use log::info;
 
-use crate::{internal_node::InternalNode, leaf_node::LeafNode};
+use crate::{
+    db::DbOptions,
+    double_write::DoubleWriteBuffer,
+    encryption::{self, PageCipher, ENCRYPTED_FILE_HEADER_SIZE, ENCRYPTED_PAGE_OVERHEAD},
+    error::DbError,
+    internal_node::InternalView,
+    leaf_node::{LeafView, LEAF_NODE_MAX_CELLS, NODE_TYPE_OFFSET},
+    metrics::{trace_span, Metrics},
+    mmap::MappedFile,
+    upgrade,
+};
 
 pub const PAGE_SIZE: usize = 150;
 pub const TABLE_MAX_PAGES: usize = 100;
+// how often a busy db file lock is tried again
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+/*
+* Table Header Layout. Stored before the pages, after the encryption header in encrypted files
+*/
+const TABLE_HEADER_MAGIC: &[u8; 7] = b"qba-db\0";
+const TABLE_HEADER_MAGIC_OFFSET: usize = 0;
+const FORMAT_VERSION_OFFSET: usize = TABLE_HEADER_MAGIC_OFFSET + TABLE_HEADER_MAGIC.len();
+const FORMAT_VERSION_SIZE: usize = mem::size_of::<u8>();
+const ROW_COUNT_OFFSET: usize = FORMAT_VERSION_OFFSET + FORMAT_VERSION_SIZE;
+const ROW_COUNT_SIZE: usize = mem::size_of::<u32>();
+// bumped by every flush, so other connections know their cached pages are stale
+const CHANGE_COUNTER_OFFSET: usize = ROW_COUNT_OFFSET + ROW_COUNT_SIZE;
+const CHANGE_COUNTER_SIZE: usize = mem::size_of::<u32>();
+pub const TABLE_HEADER_SIZE: usize = 16;
+// authenticates the encrypted table header separately from every page
+const TABLE_HEADER_PAGE_NUM: usize = u32::MAX as usize;
+
+/*
+ * On-disk format versions:
+ * 0: pages from the start of the file, without a table header. Upgraded when opened
+ * 1: table header before the pages. Headers written before the version was recorded hold 0
+ *    in its place
+ */
+pub const FORMAT_VERSION: u8 = 1;
+
+/**
+ * A cached page. The bytes are kept in their on-disk format and read through
+ * LeafView or InternalView depending on the node type byte.
+ */
+pub struct Page {
+    pub data: [u8; PAGE_SIZE],
+}
+
+impl Page {
+    fn new() -> Page {
+        Page {
+            data: [0u8; PAGE_SIZE],
+        }
+    }
+
+    pub fn node_type(&self) -> Result<NodeType, &'static str> {
+        match self.data[NODE_TYPE_OFFSET] {
+            0 => Ok(NodeType::Internal),
+            1 => Ok(NodeType::Leaf),
+            _ => Err("Invalid node type saved in db file. Corrupt file"),
+        }
+    }
+
+    pub fn leaf_view(&mut self) -> Option<LeafView<'_>> {
+        match self.node_type() {
+            Ok(NodeType::Leaf) => Some(LeafView::new(&mut self.data)),
+            _ => None,
+        }
+    }
+
+    pub fn internal_view(&mut self) -> Option<InternalView<'_>> {
+        match self.node_type() {
+            Ok(NodeType::Internal) => Some(InternalView::new(&mut self.data)),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Mutable borrow of one cached page, handed out by Pager::get_pages_mut
+ */
+pub struct PageMut<'a> {
+    pub page_num: usize,
+    page: &'a mut Page,
+}
+
+impl<'a> PageMut<'a> {
+    pub fn leaf(self) -> Result<LeafView<'a>, DbError> {
+        self.page
+            .leaf_view()
+            .ok_or(DbError::WrongNodeType(self.page_num))
+    }
+
+    pub fn internal(self) -> Result<InternalView<'a>, DbError> {
+        self.page
+            .internal_view()
+            .ok_or(DbError::WrongNodeType(self.page_num))
+    }
+}
 
 pub struct Pager {
     pub file_descriptor: File,
     pub file_length: u64,
     pub num_pages: u32,
-    pub pages: Vec<(Option<Box<InternalNode>>, Option<Box<LeafNode>>)>,
+    pub pages: Vec<Option<Box<Page>>>,
+    // pages changed since the last flush
+    pub dirty_pages: BTreeSet<u32>,
+    // set for encrypted db files
+    cipher: Option<PageCipher>,
+    // set when pages are read through a memory map of the db file
+    file_map: Option<MappedFile>,
+    // number of rows in the table, saved in the table header
+    row_count: u32,
+    // row count changed since the last flush
+    header_dirty: bool,
+    // the table was truncated since the last flush
+    truncate_pending: bool,
+    // clean pages past this many are evicted by shrink_cache
+    cache_capacity: usize,
+    // lookup counter value at the last lookup of each page, the oldest clean pages are evicted
+    last_used: Vec<u64>,
+    // bumped whenever a page changes, so cursors on it know their cell may have moved
+    generations: Vec<u32>,
+    // change counter from the table header when it was last read or written
+    change_counter: u32,
+    // lock held on the db file, shared with other connections to the same file
+    file_lock: FileLock,
+    // how long lock_file waits for a conflicting lock, from DbOptions::busy_timeout
+    busy_timeout: Duration,
+    // pages unlinked from the tree, handed out again before the file grows. Not saved in the
+    // file, pages freed before the db was closed stay unused
+    free_pages: Vec<u32>,
+    // set when flushed pages go through a double write buffer before the db file
+    double_write: Option<DoubleWriteBuffer>,
+    // bumped whenever the cached pages are dropped because another connection changed the
+    // db file
+    reloads: u32,
+    recovery: Recovery,
+    // most pages the db file may hold, from DbOptions::max_pages
+    max_pages: Option<u32>,
+    pub metrics: Metrics,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileLock {
+    Unlocked,
+    // held while reading, by any number of connections
+    Shared,
+    // held while writing until the changes are flushed, by a single connection
+    Exclusive,
+}
+
+/**
+ * What opening the db file repaired before it could be read
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Recovery {
+    // slots copied from the double write buffer over pages a crash may have torn
+    pub restored_slots: usize,
+    // rows copied out of a format version 0 file into the current format
+    pub upgraded_rows: u32,
+}
+
+// gathered while walking the tree for b_tree_lines
+#[derive(Default)]
+struct TreeSummary {
+    // levels of the tree, 1 for a root that is a leaf
+    height: u32,
+    leaves: u32,
+    keys: u32,
 }
 
 pub enum NodeType {
@@ -24,472 +189,1079 @@ pub enum NodeType {
 }
 
 impl Pager {
-    pub fn open_file(file_path: String) -> Result<Pager, &'static str> {
+    pub fn open_file(file_path: String, options: &DbOptions) -> Result<Pager, DbError> {
         // check if file exists
         let file_exists = Path::new(&file_path).exists();
 
-        if file_exists {
+        let file = if file_exists {
             match File::options()
                 .read(true)
                 .write(true)
                 .open(file_path.as_str())
             {
-                Ok(file) => {
-                    let meta = file.metadata().unwrap();
-                    let mut pages: Vec<(Option<Box<InternalNode>>, Option<Box<LeafNode>>)> =
-                        vec![(None, None); TABLE_MAX_PAGES];
-                    let file_length = meta.len();
-
-                    if file_length % PAGE_SIZE as u64 != 0 {
-                        return Err("Db file length is not a valid number of pages. Corrupt file");
-                    }
+                Ok(file) => file,
+                Err(_) => return Err("Error opening file".into()),
+            }
+        } else {
+            match File::create_new(&file_path) {
+                Ok(file) => file,
+                Err(_) => return Err("Error creating file".into()),
+            }
+        };
 
-                    // if file is empty, init root node
-                    if file_length == 0 {
-                        let mut root_node = LeafNode::new();
-                        root_node.is_root = true;
+        // opening may write the file below, which must not happen in the middle of another
+        // connection's flush. The lock goes with the file if opening fails.
+        wait_for_lock(&file, true, options.busy_timeout)?;
+
+        // pages torn by a crash during the last flush are whole again before anything is read
+        let (double_write, restored_slots) = match options.double_write {
+            true => {
+                trace_span!("restore_double_write");
+                let mut double_write = DoubleWriteBuffer::open(&file_path)?;
+                let restored_slots = double_write.restore(&file)?;
+                if restored_slots > 0 {
+                    info!(
+                        "restored {} slots from the double write buffer",
+                        restored_slots
+                    );
+                }
+                (Some(double_write), restored_slots)
+            }
+            false => (None, 0),
+        };
 
-                        pages[0] = (None, Some(Box::new(root_node)));
+        let mut file_length = match file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Err("Error reading file metadata".into()),
+        };
+        let is_encrypted = file_length > 0 && encryption::is_encrypted_file(&file);
+
+        let cipher = match (is_encrypted, options.passphrase.as_deref()) {
+            (true, Some(passphrase)) => Some(PageCipher::open(&file, passphrase)?),
+            (true, None) => return Err("Db file is encrypted but no passphrase was given".into()),
+            (false, Some(passphrase)) if file_length == 0 => {
+                let cipher = PageCipher::create(&file, passphrase)?;
+                file_length = ENCRYPTED_FILE_HEADER_SIZE as u64;
+                Some(cipher)
+            }
+            (false, Some(_)) => {
+                return Err("Db file is not encrypted but a passphrase was given".into())
+            }
+            (false, None) => None,
+        };
 
-                        return Ok(Pager {
-                            file_descriptor: file,
-                            file_length,
-                            num_pages: 1,
-                            pages,
-                        });
-                    }
+        if cipher.is_none() && file_length > 0 && !has_table_header(&file) {
+            let upgraded_rows = upgrade::upgrade_headerless_file(
+                &file_path,
+                &file,
+                file_length,
+                options.progress_handler.as_ref(),
+            )?;
+            // the file opened above was replaced by its upgraded copy, which is locked again
+            drop(file);
+            let mut pager = Pager::open_file(file_path, options)?;
+            pager.recovery = Recovery {
+                restored_slots,
+                upgraded_rows,
+            };
+            return Ok(pager);
+        }
 
-                    return Ok(Pager {
-                        file_descriptor: file,
-                        file_length,
-                        num_pages: (file_length as usize / PAGE_SIZE) as u32,
-                        pages,
-                    });
-                }
-                Err(_) => return Err("Error opening file"),
-            }
+        let mut pager = Pager {
+            file_descriptor: file,
+            file_length,
+            num_pages: 0,
+            pages: (0..TABLE_MAX_PAGES).map(|_| None).collect(),
+            dirty_pages: BTreeSet::new(),
+            cipher,
+            file_map: None,
+            row_count: 0,
+            header_dirty: false,
+            truncate_pending: false,
+            cache_capacity: options.cache_size.unwrap_or(TABLE_MAX_PAGES),
+            last_used: vec![0; TABLE_MAX_PAGES],
+            generations: vec![0; TABLE_MAX_PAGES],
+            change_counter: 0,
+            file_lock: FileLock::Unlocked,
+            busy_timeout: options.busy_timeout,
+            free_pages: vec![],
+            double_write,
+            reloads: 0,
+            recovery: Recovery {
+                restored_slots,
+                upgraded_rows: 0,
+            },
+            max_pages: options.max_pages,
+            metrics: Metrics::default(),
+        };
+
+        if pager.file_length == pager.table_header_offset() {
+            pager.write_table_header()?;
         } else {
-            let file = File::create_new(file_path).unwrap();
-            let meta = file.metadata().unwrap();
-            let mut pages: Vec<(Option<Box<InternalNode>>, Option<Box<LeafNode>>)> =
-                vec![(None, None); TABLE_MAX_PAGES];
+            pager.read_table_header()?;
+        }
 
-            let mut root_node = LeafNode::new();
-            root_node.is_root = true;
+        if pager.file_length < pager.header_size() {
+            return Err("Db file is too short for its header. Corrupt file".into());
+        }
+        let pages_length = pager.file_length - pager.header_size();
+        if !pages_length.is_multiple_of(pager.page_slot_size()) {
+            return Err("Db file length is not a valid number of pages. Corrupt file".into());
+        }
+        pager.num_pages = (pages_length / pager.page_slot_size()) as u32;
 
-            pages[0] = (None, Some(Box::new(root_node)));
+        // if file is empty, init root node
+        if pager.num_pages == 0 {
+            pager.init_root();
+        }
 
-            return Ok(Pager {
-                file_descriptor: file,
-                file_length: meta.len(),
-                num_pages: 1,
-                pages,
-            });
+        if options.mmap {
+            pager.file_map = Some(MappedFile::map(&pager.file_descriptor)?);
         }
+        // unlocked like after a commit, statements lock the file again
+        let _ = pager.file_descriptor.unlock();
+
+        Ok(pager)
     }
 
-    pub fn get_page_node_type(&mut self, page_num: usize) -> NodeType {
-        if self.pages[page_num].0.is_some() {
-            return NodeType::Internal;
+    /**
+     * Opens a second pager over the same db file with its own page cache, for reading the
+     * table from another thread. Every change must be flushed first, and the db file must not
+     * be written while the reader is in use.
+     */
+    pub fn reader(&self) -> Result<Pager, &'static str> {
+        if self.has_unflushed_changes() {
+            return Err("Pager has unflushed changes");
         }
 
-        if self.pages[page_num].1.is_some() {
-            return NodeType::Leaf;
-        }
+        let file_descriptor = match self.file_descriptor.try_clone() {
+            Ok(file) => file,
+            Err(_) => return Err("Error opening file"),
+        };
+
+        let file_map = match self.file_map {
+            Some(_) => Some(MappedFile::map(&file_descriptor)?),
+            None => None,
+        };
 
-        panic!("Trying to get node type for non-existent page!")
+        Ok(Pager {
+            file_descriptor,
+            file_length: self.file_length,
+            num_pages: self.num_pages,
+            pages: (0..TABLE_MAX_PAGES).map(|_| None).collect(),
+            dirty_pages: BTreeSet::new(),
+            cipher: self.cipher.clone(),
+            file_map,
+            row_count: self.row_count,
+            header_dirty: false,
+            truncate_pending: false,
+            cache_capacity: self.cache_capacity,
+            last_used: vec![0; TABLE_MAX_PAGES],
+            generations: vec![0; TABLE_MAX_PAGES],
+            change_counter: self.change_counter,
+            file_lock: FileLock::Unlocked,
+            busy_timeout: self.busy_timeout,
+            free_pages: vec![],
+            double_write: None,
+            reloads: 0,
+            recovery: Recovery::default(),
+            max_pages: self.max_pages,
+            metrics: Metrics::default(),
+        })
     }
 
-    /*
-    LEAF NODE METHODS
-    */
+    fn init_root(&mut self) {
+        let mut root_page = Box::new(Page::new());
+        LeafView::initialize(&mut root_page.data).set_is_root(true);
 
-    pub fn get_page_leaf(&mut self, page_num: usize) -> Result<&mut LeafNode, &str> {
-        if page_num > TABLE_MAX_PAGES {
-            return Err("Hit page limit for table");
+        self.pages[0] = Some(root_page);
+        self.num_pages = 1;
+        self.mark_dirty(0);
+    }
+
+    /**
+     * Drops every page and starts over with an empty root leaf. The db file is cut back to
+     * its header on the next flush.
+     */
+    pub fn truncate(&mut self) {
+        self.pages.iter_mut().for_each(|page| *page = None);
+        self.dirty_pages.clear();
+        self.free_pages.clear();
+        self.invalidate_cursors();
+
+        // pages past the header are gone, even before the file itself is cut
+        self.file_length = self.header_size();
+        self.truncate_pending = true;
+
+        self.init_root();
+        self.set_row_count(0);
+    }
+
+    fn table_header_offset(&self) -> u64 {
+        match self.cipher {
+            Some(_) => ENCRYPTED_FILE_HEADER_SIZE as u64,
+            None => 0,
         }
+    }
 
-        // self.check_page_leaf(page_num).unwrap();
+    fn table_header_slot_size(&self) -> u64 {
+        match self.cipher {
+            Some(_) => (TABLE_HEADER_SIZE + ENCRYPTED_PAGE_OVERHEAD) as u64,
+            None => TABLE_HEADER_SIZE as u64,
+        }
+    }
 
-        match &mut self.pages[page_num].1 {
-            Some(page) => {
-                return Ok(page);
-            }
-            None => {
-                return Err("Error fetching page! Leaf node does not exist at page_num");
-            }
-        };
+    fn header_size(&self) -> u64 {
+        self.table_header_offset() + self.table_header_slot_size()
     }
 
-    pub fn ensure_page_leaf(&mut self, page_num: usize) -> Result<(), &str> {
-        // check leaf node exists
-        if self.pages[page_num].1.is_none() {
-            // make sure we dont overwrite an internal node
-            if self.pages[page_num].0.is_some() {
-                return Err("Trying to check leaf node at page num where internal node exists");
+    fn read_table_header(&mut self) -> Result<(), &'static str> {
+        let mut header_slot = vec![0u8; self.table_header_slot_size() as usize];
+        if self
+            .file_descriptor
+            .read_exact_at(&mut header_slot, self.table_header_offset())
+            .is_err()
+        {
+            return Err("Error reading table header from db file");
+        }
+
+        let mut header = [0u8; TABLE_HEADER_SIZE];
+        match &self.cipher {
+            Some(cipher) => {
+                cipher.decrypt_page(TABLE_HEADER_PAGE_NUM, &header_slot, &mut header)?
             }
+            None => header.copy_from_slice(&header_slot),
+        }
 
-            info!("adding new page for leafnode at index {}", page_num);
-            let mut new_node = Box::new(LeafNode::new());
-            let file_pages = self.file_length as usize / PAGE_SIZE;
+        if &header[TABLE_HEADER_MAGIC_OFFSET..FORMAT_VERSION_OFFSET] != TABLE_HEADER_MAGIC {
+            return Err("Db file has no table header. Unsupported file format");
+        }
+        if header[FORMAT_VERSION_OFFSET].max(1) > FORMAT_VERSION {
+            return Err(
+                "Db file was written by a newer version of qba-db. Unsupported file format",
+            );
+        }
 
-            if page_num < file_pages {
-                let mut raw_data = [0u8; PAGE_SIZE];
+        self.row_count = u32::from_ne_bytes(
+            header[ROW_COUNT_OFFSET..ROW_COUNT_OFFSET + ROW_COUNT_SIZE]
+                .try_into()
+                .unwrap(),
+        );
+        self.change_counter = u32::from_ne_bytes(
+            header[CHANGE_COUNTER_OFFSET..CHANGE_COUNTER_OFFSET + CHANGE_COUNTER_SIZE]
+                .try_into()
+                .unwrap(),
+        );
 
-                match self
-                    .file_descriptor
-                    .seek(std::io::SeekFrom::Start((page_num * PAGE_SIZE) as u64))
-                {
-                    Ok(_) => {
-                        // save buffer in pages
+        Ok(())
+    }
 
-                        self.file_descriptor.read_exact(&mut raw_data).unwrap();
-                    }
-                    Err(_) => return Err("Error trying to reach page from file"),
-                }
+    fn write_table_header(&mut self) -> Result<(), &'static str> {
+        let header_slot = self.table_header_slot()?;
 
-                LeafNode::serialize_node(raw_data.as_mut_ptr(), &mut new_node);
-            }
+        if self
+            .file_descriptor
+            .write_all_at(&header_slot, self.table_header_offset())
+            .is_err()
+        {
+            return Err("Error writing table header to db file");
+        }
+
+        self.file_length = self.file_length.max(self.header_size());
+        self.header_dirty = false;
+
+        Ok(())
+    }
 
-            self.pages[page_num] = (None, Some(new_node));
-            self.num_pages = self.num_pages + 1;
+    /**
+     * Table header as it is written to the db file, encrypted for encrypted db files
+     */
+    fn table_header_slot(&self) -> Result<Vec<u8>, &'static str> {
+        let mut header = [0u8; TABLE_HEADER_SIZE];
+        header[TABLE_HEADER_MAGIC_OFFSET..FORMAT_VERSION_OFFSET]
+            .copy_from_slice(TABLE_HEADER_MAGIC);
+        header[FORMAT_VERSION_OFFSET] = FORMAT_VERSION;
+        header[ROW_COUNT_OFFSET..ROW_COUNT_OFFSET + ROW_COUNT_SIZE]
+            .copy_from_slice(&self.row_count.to_ne_bytes());
+        header[CHANGE_COUNTER_OFFSET..CHANGE_COUNTER_OFFSET + CHANGE_COUNTER_SIZE]
+            .copy_from_slice(&self.change_counter.to_ne_bytes());
+
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt_page(TABLE_HEADER_PAGE_NUM, &header),
+            None => Ok(header.to_vec()),
         }
+    }
+
+    pub fn row_count(&self) -> u32 {
+        self.row_count
+    }
+
+    pub fn set_row_count(&mut self, row_count: u32) {
+        self.row_count = row_count;
+        self.header_dirty = true;
+    }
+
+    /**
+     * Locks the db file against writes from other connections, or against every other
+     * connection when exclusive, retrying for up to busy_timeout while another connection
+     * holds a conflicting lock. Cached pages are dropped if the file was written since the
+     * lock was last held.
+     */
+    pub fn lock_file(&mut self, exclusive: bool) -> Result<(), DbError> {
+        let lock = if exclusive {
+            FileLock::Exclusive
+        } else {
+            FileLock::Shared
+        };
+        if self.file_lock == FileLock::Exclusive || self.file_lock == lock {
+            return Ok(());
+        }
+        // converting a lock may drop it while waiting, so a shared lock is released first
+        self.unlock_file();
+
+        wait_for_lock(&self.file_descriptor, exclusive, self.busy_timeout)?;
+        self.file_lock = lock;
+
+        self.reload_if_changed()?;
         Ok(())
     }
 
-    pub fn get_two_pages_leaf(
-        &mut self,
-        first_page_num: usize,
-        second_page_num: usize,
-    ) -> Result<(&mut LeafNode, &mut LeafNode), &str> {
-        if first_page_num == second_page_num {
-            return Err("Tried to access same page num twice!");
+    /**
+     * Releases the lock on the db file. An exclusive lock is kept until its changes are
+     * flushed.
+     */
+    pub fn unlock_file(&mut self) {
+        if self.file_lock == FileLock::Unlocked
+            || (self.file_lock == FileLock::Exclusive && self.has_unflushed_changes())
+        {
+            return;
         }
 
-        if first_page_num > TABLE_MAX_PAGES || second_page_num > TABLE_MAX_PAGES {
-            return Err("Hit page limit for table");
+        // the lock is also dropped when the file is closed, so a failed unlock is not fatal
+        let _ = self.file_descriptor.unlock();
+        self.file_lock = FileLock::Unlocked;
+    }
+
+    fn has_unflushed_changes(&self) -> bool {
+        !self.dirty_pages.is_empty() || self.header_dirty || self.truncate_pending
+    }
+
+    /**
+     * Drops every cached page if another connection flushed changes to the db file since
+     * the table header was last read
+     */
+    fn reload_if_changed(&mut self) -> Result<(), &'static str> {
+        if self.has_unflushed_changes() {
+            return Ok(());
         }
 
-        // self.check_page_leaf(first_page_num).unwrap();
-        // self.check_page_leaf(second_page_num).unwrap();
+        let change_counter = self.change_counter;
+        self.read_table_header()?;
+        if self.change_counter == change_counter {
+            return self.remap_if_resized();
+        }
 
-        let (lower, higher) = {
-            if first_page_num < second_page_num {
-                (first_page_num, second_page_num)
-            } else {
-                (second_page_num, first_page_num)
-            }
+        self.file_length = match self.file_descriptor.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Err("Error reading db file length"),
         };
+        self.num_pages =
+            (self.file_length.saturating_sub(self.header_size()) / self.page_slot_size()) as u32;
+        self.pages.iter_mut().for_each(|page| *page = None);
+        // another connection may have used the freed pages since
+        self.free_pages.clear();
+        self.invalidate_cursors();
+        self.reloads = self.reloads.wrapping_add(1);
+
+        if self.file_map.is_some() {
+            self.file_map = Some(MappedFile::map(&self.file_descriptor)?);
+        }
+        if self.num_pages == 0 {
+            self.init_root();
+        }
 
-        let (a, b) = self.pages.split_at_mut(higher);
+        Ok(())
+    }
 
-        // Get mutable references to the page contents, handling cases where they might be None
-        let lower_page_ref = match a[lower].1.as_mut() {
-            Some(page) => page,
-            None => return Err("Requested page does not exist"),
-        };
-        let higher_page_ref = match b[0].1.as_mut() {
-            Some(page) => page,
-            None => return Err("Requested page does not exist"),
+    /**
+     * Maps the db file again when its length no longer matches the map, so reads never touch
+     * the map past the end of a file that was truncated without a change to its header
+     */
+    fn remap_if_resized(&mut self) -> Result<(), &'static str> {
+        let Some(file_map) = &self.file_map else {
+            return Ok(());
         };
 
-        if first_page_num == lower {
-            Ok((lower_page_ref, higher_page_ref))
-        } else {
-            Ok((higher_page_ref, lower_page_ref))
+        let file_length = match self.file_descriptor.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Err("Error reading db file length"),
+        };
+        if file_map.mapped_length() != file_length {
+            self.file_map = Some(MappedFile::map(&self.file_descriptor)?);
         }
+
+        Ok(())
     }
 
-    pub fn get_internal_and_leaf(
-        &mut self,
-        internal_page_num: usize,
-        leaf_page_num: usize,
-    ) -> Result<(&mut InternalNode, &mut LeafNode), &str> {
-        if internal_page_num == leaf_page_num {
-            return Err("Tried to access same page num twice!");
-        }
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
 
-        if internal_page_num > TABLE_MAX_PAGES || leaf_page_num > TABLE_MAX_PAGES {
-            return Err("Hit page limit for table");
+    pub fn is_mapped(&self) -> bool {
+        self.file_map.is_some()
+    }
+
+    fn page_slot_size(&self) -> u64 {
+        match self.cipher {
+            Some(_) => (PAGE_SIZE + ENCRYPTED_PAGE_OVERHEAD) as u64,
+            None => PAGE_SIZE as u64,
         }
+    }
+
+    fn page_offset(&self, page_num: usize) -> u64 {
+        self.header_size() + page_num as u64 * self.page_slot_size()
+    }
+
+    fn read_page(&self, page_num: usize, destination: &mut [u8]) -> Result<(), &'static str> {
+        trace_span!("page_read", page_num);
+
+        let page_slot_size = self.page_slot_size() as usize;
+        let page_offset = self.page_offset(page_num);
 
-        let (lower, higher) = {
-            if internal_page_num < leaf_page_num {
-                (internal_page_num, leaf_page_num)
-            } else {
-                (leaf_page_num, internal_page_num)
+        let mapped_page_slot = self
+            .file_map
+            .as_ref()
+            .and_then(|file_map| file_map.get(page_offset, page_slot_size));
+
+        let mut read_page_slot = vec![];
+        let page_slot = match mapped_page_slot {
+            Some(page_slot) => page_slot,
+            None => {
+                read_page_slot.resize(page_slot_size, 0u8);
+                if self
+                    .file_descriptor
+                    .read_exact_at(&mut read_page_slot, page_offset)
+                    .is_err()
+                {
+                    return Err("Error trying to reach page from file");
+                }
+                &read_page_slot
             }
         };
 
-        let (a, b) = self.pages.split_at_mut(higher);
+        match &self.cipher {
+            Some(cipher) => cipher.decrypt_page(page_num, page_slot, destination),
+            None => {
+                destination.copy_from_slice(page_slot);
+                Ok(())
+            }
+        }
+    }
 
-        // Get mutable references to the page contents, handling cases where they might be None
-        let lower_page_ref = match a[lower].0.as_mut() {
-            Some(page) => page,
-            None => return Err("Requested page does not exist"),
-        };
-        let higher_page_ref = match b[0].1.as_mut() {
-            Some(page) => page,
-            None => return Err("Requested page does not exist"),
-        };
+    fn write_page(&mut self, page_num: usize, source: &[u8]) -> Result<(), &'static str> {
+        trace_span!("page_write", page_num);
+        self.metrics.page_writes += 1;
 
-        Ok((lower_page_ref, higher_page_ref))
+        let page_slot = self.page_slot(page_num, source)?;
+
+        if self
+            .file_descriptor
+            .write_all_at(&page_slot, self.page_offset(page_num))
+            .is_err()
+        {
+            return Err("Error saving db to file!");
+        }
+
+        self.file_length = self
+            .file_length
+            .max(self.page_offset(page_num) + self.page_slot_size());
+
+        Ok(())
     }
 
     /**
-     * RETURNS NODES FOR internal_node_insert
-     * (parent, child, right_node of parent)
+     * Page as it is written to its slot in the db file, encrypted for encrypted db files
      */
-    pub fn get_nodes_for_internal_node_insert(
-        &mut self,
-        parent_page_num: usize,
-        child_page_num: usize,
-    ) -> Result<(&mut InternalNode, &mut LeafNode, &mut Box<LeafNode>), &str> {
-        if parent_page_num > TABLE_MAX_PAGES || child_page_num > TABLE_MAX_PAGES {
+    fn page_slot(&self, page_num: usize, source: &[u8]) -> Result<Vec<u8>, &'static str> {
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt_page(page_num, source),
+            None => Ok(source.to_vec()),
+        }
+    }
+
+    pub fn get_page_node_type(&mut self, page_num: usize) -> Result<NodeType, DbError> {
+        self.load_page(page_num)?;
+
+        match &self.pages[page_num] {
+            Some(page) => Ok(page.node_type()?),
+            None => Err(DbError::PageNotFound(page_num)),
+        }
+    }
+
+    /**
+     * Reads a page from the db file into the cache if it is saved on disk but not loaded yet
+     */
+    fn load_page(&mut self, page_num: usize) -> Result<(), &'static str> {
+        if page_num >= TABLE_MAX_PAGES {
             return Err("Hit page limit for table");
         }
 
-        let tmp_parent = self.get_page_internal(parent_page_num).unwrap();
-        let right_child_page_num = tmp_parent.right_child as usize;
-        let does_need_right_child = right_child_page_num != 0;
+        let file_pages = ((self.file_length - self.header_size()) / self.page_slot_size()) as usize;
+        let is_cached = self.pages[page_num].is_some();
+        self.last_used[page_num] = self.metrics.cache_hits + self.metrics.cache_misses;
 
-        info!(
-            "parent: {}, child: {}, right: {}",
-            parent_page_num, child_page_num, right_child_page_num
-        );
+        if is_cached {
+            self.metrics.cache_hits += 1;
+            return Ok(());
+        }
 
-        // now perform two split_at_muts to get all nodes
-        if does_need_right_child {
-            // ensure leaf nodes exist
-            self.ensure_page_leaf(child_page_num).unwrap();
-            self.ensure_page_leaf(right_child_page_num).unwrap();
-
-            let (lower_idx, middle_idx, upper_idx) = if parent_page_num < child_page_num {
-                if child_page_num < right_child_page_num {
-                    (parent_page_num, child_page_num, right_child_page_num)
-                } else if parent_page_num < right_child_page_num {
-                    (parent_page_num, right_child_page_num, child_page_num)
-                } else {
-                    (right_child_page_num, parent_page_num, child_page_num)
-                }
-            } else {
-                if parent_page_num < right_child_page_num {
-                    (child_page_num, parent_page_num, right_child_page_num)
-                } else if child_page_num < right_child_page_num {
-                    (child_page_num, right_child_page_num, parent_page_num)
-                } else {
-                    (right_child_page_num, child_page_num, parent_page_num)
-                }
-            };
+        if page_num >= file_pages {
+            return Ok(());
+        }
 
-            // always 0
-            let relative_middle_idx = 0;
-            let relative_upper_idx = 0;
+        self.metrics.cache_misses += 1;
+        self.metrics.page_reads += 1;
 
-            let (lower, middle, upper) = {
-                let (first, rest) = self.pages.split_at_mut(middle_idx);
+        let mut page = Box::new(Page::new());
+        self.read_page(page_num, &mut page.data)?;
+        page.node_type()?;
 
-                let (second, third) = rest.split_at_mut(upper_idx - middle_idx);
+        self.pages[page_num] = Some(page);
 
-                (first, second, third)
-            };
+        Ok(())
+    }
 
-            // TODO: refactor below
-            // parent node first
-            if lower_idx == parent_page_num {
-                let parent_node_ref = match lower[lower_idx].0.as_mut() {
-                    Some(page) => page,
-                    None => return Err("Requested page does not exist for parent 1"),
-                };
+    pub fn cached_pages(&self) -> usize {
+        self.pages.iter().filter(|page| page.is_some()).count()
+    }
 
-                // if statement for child and right child is other
-                if middle_idx == child_page_num {
-                    let child_node_ref = match middle[relative_middle_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 2"),
-                    };
-
-                    let right_node_ref = match upper[relative_upper_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 3"),
-                    };
-
-                    return Ok((parent_node_ref, child_node_ref, right_node_ref));
-                } else {
-                    // upper == child
-                    let child_node_ref = match upper[relative_upper_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 4"),
-                    };
-
-                    let right_node_ref = match middle[relative_middle_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 5"),
-                    };
-
-                    return Ok((parent_node_ref, child_node_ref, right_node_ref));
-                }
-            } else if middle_idx == parent_page_num {
-                let parent_node_ref = match middle[relative_middle_idx].0.as_mut() {
-                    Some(page) => page,
-                    None => return Err("Requested page does not exist for parent 6"),
-                };
+    pub fn cache_capacity(&self) -> usize {
+        self.cache_capacity
+    }
 
-                // if statement for child and right child is other
-                if lower_idx == child_page_num {
-                    let child_node_ref = match lower[lower_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 7"),
-                    };
-
-                    let right_node_ref = match upper[relative_upper_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 8"),
-                    };
-
-                    return Ok((parent_node_ref, child_node_ref, right_node_ref));
-                } else {
-                    // upper == child
-                    let child_node_ref = match upper[relative_upper_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 9"),
-                    };
-
-                    let right_node_ref = match lower[lower_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 10"),
-                    };
-
-                    return Ok((parent_node_ref, child_node_ref, right_node_ref));
-                }
-            } else {
-                let parent_node_ref = match upper[relative_upper_idx].0.as_mut() {
-                    Some(page) => page,
-                    None => return Err("Requested page does not exist for parent 11"),
-                };
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache_capacity = capacity;
+        self.shrink_cache();
+    }
 
-                // if statement for child and right child is other
-                if lower_idx == child_page_num {
-                    let child_node_ref = match lower[lower_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 12"),
-                    };
-
-                    let right_node_ref = match middle[relative_middle_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 13"),
-                    };
-
-                    return Ok((parent_node_ref, child_node_ref, right_node_ref));
-                } else {
-                    // middle == child
-                    let child_node_ref = match middle[relative_middle_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 14"),
-                    };
-
-                    let right_node_ref = match lower[lower_idx].1.as_mut() {
-                        Some(page) => page,
-                        None => return Err("Requested page does not exist for mid 15"),
-                    };
-
-                    return Ok((parent_node_ref, child_node_ref, right_node_ref));
-                }
+    /**
+     * Evicts the least recently used clean pages until the cache fits its capacity. Dirty
+     * pages stay cached until they are flushed, so the cache can still go past its capacity
+     * between commits. Only called between statements, while no page is borrowed.
+     */
+    pub fn shrink_cache(&mut self) {
+        let cached_pages = self.cached_pages();
+        if cached_pages <= self.cache_capacity {
+            return;
+        }
+
+        let mut clean_pages: Vec<usize> = (0..self.pages.len())
+            .filter(|&page_num| {
+                self.pages[page_num].is_some() && !self.dirty_pages.contains(&(page_num as u32))
+            })
+            .collect();
+        clean_pages.sort_by_key(|&page_num| self.last_used[page_num]);
+
+        for page_num in clean_pages
+            .into_iter()
+            .take(cached_pages - self.cache_capacity)
+        {
+            self.pages[page_num] = None;
+            self.metrics.cache_evictions += 1;
+        }
+    }
+
+    /**
+     * Evicts every clean page, they are read from the db file again on their next lookup
+     */
+    pub fn clear_cache(&mut self) {
+        let capacity = mem::replace(&mut self.cache_capacity, 0);
+        self.shrink_cache();
+        self.cache_capacity = capacity;
+    }
+
+    pub fn mark_dirty(&mut self, page_num: usize) {
+        self.dirty_pages.insert(page_num as u32);
+        self.generations[page_num] = self.generations[page_num].wrapping_add(1);
+    }
+
+    // every page may hold other cells than before
+    fn invalidate_cursors(&mut self) {
+        self.generations
+            .iter_mut()
+            .for_each(|generation| *generation = generation.wrapping_add(1));
+    }
+
+    /**
+     * Number of flushes the db file has seen, saved in the table header
+     */
+    pub fn change_counter(&self) -> u32 {
+        self.change_counter
+    }
+
+    /**
+     * What was repaired when the db file was opened
+     */
+    pub fn recovery(&self) -> Recovery {
+        self.recovery
+    }
+
+    /**
+     * Number of times the cached pages were dropped because another connection changed the
+     * db file, anything read from the tree before may be out of date
+     */
+    pub(crate) fn reloads(&self) -> u32 {
+        self.reloads
+    }
+
+    /**
+     * Changes whenever the page is marked dirty, or may have been replaced by truncate or by
+     * another connection's writes
+     */
+    pub fn page_generation(&self, page_num: usize) -> u32 {
+        self.generations[page_num]
+    }
+
+    /**
+     * Writes every page changed since the last flush back to its slot in the db file,
+     * along with the table header if the row count changed, and syncs the file
+     */
+    pub fn flush(&mut self) -> Result<(), &'static str> {
+        if self.dirty_pages.is_empty() && !self.header_dirty {
+            return Ok(());
+        }
+
+        self.change_counter = self.change_counter.wrapping_add(1);
+        if self.double_write.is_some() {
+            self.save_double_write()?;
+        }
+        self.write_table_header()?;
+
+        if self.truncate_pending {
+            if self.file_descriptor.set_len(self.header_size()).is_err() {
+                return Err("Error truncating db file!");
+            }
+            self.truncate_pending = false;
+        }
+
+        for page_num in mem::take(&mut self.dirty_pages) {
+            let page_num = page_num as usize;
+            let page_to_write = match &self.pages[page_num] {
+                Some(page) => page.data,
+                None => continue,
             };
-        } else {
-            panic!("How is right_child 0??");
+
+            info!("saving node at page_num: {} to file", page_num);
+            self.write_page(page_num, &page_to_write)?;
+        }
+
+        if self.file_descriptor.sync_data().is_err() {
+            return Err("Error syncing db file!");
+        }
+        if let Some(double_write) = &mut self.double_write {
+            double_write.clear()?;
+        }
+
+        // remap so pages appended by this flush can be read from the map too
+        if self.file_map.is_some() {
+            self.file_map = Some(MappedFile::map(&self.file_descriptor)?);
         }
+
+        Ok(())
     }
 
-    pub fn get_page_internal(&mut self, page_num: usize) -> Result<&mut InternalNode, &str> {
-        if page_num > TABLE_MAX_PAGES {
-            return Err("Hit page limit for table");
+    /**
+     * Saves the table header and every dirty page to the double write buffer, as they are
+     * about to be written to the db file
+     */
+    fn save_double_write(&mut self) -> Result<(), &'static str> {
+        let mut slots = vec![(self.table_header_offset(), self.table_header_slot()?)];
+        for &page_num in &self.dirty_pages {
+            let page_num = page_num as usize;
+            if let Some(page) = &self.pages[page_num] {
+                slots.push((
+                    self.page_offset(page_num),
+                    self.page_slot(page_num, &page.data)?,
+                ));
+            }
+        }
+
+        // a truncated file is cut back to its header, then grows with the pages written
+        let file_length = slots
+            .iter()
+            .map(|(offset, slot)| offset + slot.len() as u64)
+            .fold(self.file_length, u64::max);
+
+        match &mut self.double_write {
+            Some(double_write) => double_write.save(file_length, &slots),
+            None => Ok(()),
+        }
+    }
+
+    /*
+    LEAF NODE METHODS
+    */
+
+    pub fn get_page_leaf(&mut self, page_num: usize) -> Result<LeafView<'_>, &'static str> {
+        self.load_page(page_num)?;
+
+        match self.pages[page_num]
+            .as_deref_mut()
+            .and_then(Page::leaf_view)
+        {
+            Some(node) => Ok(node),
+            None => Err("Error fetching page! Leaf node does not exist at page_num"),
         }
+    }
+
+    pub fn ensure_page_leaf(&mut self, page_num: usize) -> Result<(), &'static str> {
+        self.load_page(page_num)?;
 
-        match &mut self.pages[page_num].0 {
-            Some(page) => {
-                return Ok(page);
+        // check leaf node exists
+        if let Some(page) = &self.pages[page_num] {
+            // make sure we dont overwrite an internal node
+            if let Ok(NodeType::Internal) = page.node_type() {
+                return Err("Trying to check leaf node at page num where internal node exists");
             }
-            None => {
-                return Err("Error fetching page! Internal node does not exist at page_num");
+        } else {
+            info!("adding new page for leafnode at index {}", page_num);
+            let mut page = Box::new(Page::new());
+            LeafView::initialize(&mut page.data);
+            self.pages[page_num] = Some(page);
+            self.mark_dirty(page_num);
+
+            if page_num as u32 >= self.num_pages {
+                self.num_pages = page_num as u32 + 1;
             }
+        }
+        Ok(())
+    }
+
+    pub fn get_two_pages_leaf(
+        &mut self,
+        first_page_num: usize,
+        second_page_num: usize,
+    ) -> Result<(LeafView<'_>, LeafView<'_>), &'static str> {
+        let mut pages = self
+            .get_pages_mut(&[first_page_num, second_page_num])?
+            .into_iter();
+
+        let first_node = pages.next().unwrap().leaf()?;
+        let second_node = pages.next().unwrap().leaf()?;
+
+        Ok((first_node, second_node))
+    }
+
+    /**
+     * Borrows several distinct pages at once, returned in the order they were requested.
+     * Pages saved on disk are loaded first
+     */
+    pub fn get_pages_mut(&mut self, page_nums: &[usize]) -> Result<Vec<PageMut<'_>>, DbError> {
+        for (i, &page_num) in page_nums.iter().enumerate() {
+            if page_num >= TABLE_MAX_PAGES {
+                return Err(DbError::PageLimit);
+            }
+
+            if page_nums[..i].contains(&page_num) {
+                return Err(DbError::PageRequestedTwice(page_num));
+            }
+
+            self.load_page(page_num)?;
+        }
+
+        let mut pages: Vec<Option<PageMut>> = page_nums.iter().map(|_| None).collect();
+        for (page_num, page) in self.pages.iter_mut().enumerate() {
+            if let Some(index) = page_nums.iter().position(|&num| num == page_num) {
+                let page = page.as_deref_mut().ok_or(DbError::PageNotFound(page_num))?;
+                pages[index] = Some(PageMut { page_num, page });
+            }
+        }
+
+        Ok(pages.into_iter().flatten().collect())
+    }
+
+    pub fn get_page_internal(&mut self, page_num: usize) -> Result<InternalView<'_>, &'static str> {
+        self.load_page(page_num)?;
+
+        match self.pages[page_num]
+            .as_deref_mut()
+            .and_then(Page::internal_view)
+        {
+            Some(node) => Ok(node),
+            None => Err("Error fetching page! Internal node does not exist at page_num"),
+        }
+    }
+
+    /**
+     * Raw bytes of a cached page, used to reformat a page as another node type
+     */
+    pub fn get_page_data(&mut self, page_num: usize) -> Result<&mut [u8], &'static str> {
+        self.load_page(page_num)?;
+
+        match self.pages[page_num].as_deref_mut() {
+            Some(page) => Ok(&mut page.data),
+            None => Err("Error fetching page! Page does not exist at page_num"),
+        }
+    }
+
+    pub fn copy_page(
+        &mut self,
+        from_page_num: usize,
+        to_page_num: usize,
+    ) -> Result<(), &'static str> {
+        self.load_page(from_page_num)?;
+
+        let data = match &self.pages[from_page_num] {
+            Some(page) => page.data,
+            None => return Err("Error fetching page! Page does not exist at page_num"),
         };
+        self.get_page_data(to_page_num)?.copy_from_slice(&data);
+
+        Ok(())
+    }
+
+    /**
+     * Page for a new node, a freed page if there is one, otherwise the page past the last
+     */
+    pub fn get_unused_page_num(&mut self) -> u32 {
+        self.free_pages.pop().unwrap_or(self.num_pages)
     }
 
-    pub fn get_unused_page_num(&self) -> u32 {
-        return self.num_pages;
+    pub fn max_pages(&self) -> Option<u32> {
+        self.max_pages
+    }
+
+    /**
+     * Fails with DbError::QuotaExceeded when handing out new_pages more pages would grow the
+     * db file past its quota. Freed pages are handed out first and don't count.
+     */
+    pub fn check_quota(&self, new_pages: u32) -> Result<(), DbError> {
+        let Some(max_pages) = self.max_pages else {
+            return Ok(());
+        };
+
+        let grown_pages = new_pages.saturating_sub(self.free_pages.len() as u32);
+        match self.num_pages + grown_pages > max_pages {
+            true => Err(DbError::QuotaExceeded),
+            false => Ok(()),
+        }
+    }
+
+    /**
+     * Clears a page unlinked from the tree and hands it out again for the next new node
+     */
+    pub fn free_page(&mut self, page_num: u32) -> Result<(), &'static str> {
+        self.mark_dirty(page_num as usize);
+        LeafView::initialize(self.get_page_data(page_num as usize)?);
+        self.free_pages.push(page_num);
+
+        Ok(())
     }
 
     fn indent(level: u32) -> String {
         let mut res = String::from("");
 
         for _ in 0..level {
-            res = res + "\t";
+            res += "\t";
         }
 
         res
     }
 
-    pub fn print_b_tree(&mut self, page_num: usize, indent_level: u32) {
-        match self.get_page_node_type(page_num) {
+    /**
+     * The tree below page_num, one line per node and key, followed by a summary of its
+     * height, leaves, how full the leaves are on average and the keys they hold
+     */
+    pub fn b_tree_lines(&mut self, page_num: usize) -> Vec<String> {
+        let mut lines = vec![];
+        let mut summary = TreeSummary::default();
+        self.add_tree_lines(page_num, 0, &mut lines, &mut summary);
+
+        let capacity = summary.leaves as usize * LEAF_NODE_MAX_CELLS;
+        let average_fill = match capacity {
+            0 => 0.0,
+            _ => summary.keys as f64 * 100.0 / capacity as f64,
+        };
+        lines.push(format!(
+            "height: {}, leaves: {}, average fill: {:.0}%, keys: {}",
+            summary.height, summary.leaves, average_fill, summary.keys
+        ));
+
+        lines
+    }
+
+    fn add_tree_lines(
+        &mut self,
+        page_num: usize,
+        indent_level: u32,
+        lines: &mut Vec<String>,
+        summary: &mut TreeSummary,
+    ) {
+        summary.height = summary.height.max(indent_level + 1);
+
+        match self.get_page_node_type(page_num).unwrap() {
             NodeType::Leaf => {
                 let node = self.get_page_leaf(page_num).unwrap();
 
-                let num_cells = node.num_cells;
-                info!(
+                let num_cells = node.num_cells();
+                lines.push(format!(
                     "{}- leaf @page_num={} (num_cells: {})",
                     Self::indent(indent_level),
                     page_num,
                     num_cells
-                );
+                ));
 
                 for i in 0..num_cells {
                     let cell_key = node.get_cell_key(i);
-                    info!("{}- {}", Self::indent(indent_level), cell_key);
-
-                    // let cell_value = node.get_cell_value(i);
-                    // let mut row_data = Row {
-                    //     id: 123,
-                    //     email: String::from("123"),
-                    //     username: String::from("!@3"),
-                    // };
-                    // deserialize_row(cell_value, &mut row_data).unwrap();
-                    // info!(
-                    //     "{}- id  {}, username: {}, email: {}",
-                    //     Self::indent(indent_level),
-                    //     row_data.id,
-                    //     row_data.username,
-                    //     row_data.email
-                    // );
+                    lines.push(format!("{}- {}", Self::indent(indent_level), cell_key));
                 }
+
+                summary.leaves += 1;
+                summary.keys += num_cells;
             }
             NodeType::Internal => {
                 let node = self.get_page_internal(page_num).unwrap();
 
-                let num_keys = node.num_keys;
-                info!(
+                let num_keys = node.num_keys();
+                lines.push(format!(
                     "{}- internal @page_num={} (num_childs: {})",
                     Self::indent(indent_level),
                     page_num,
                     num_keys + 1
-                );
+                ));
 
                 let mut child_nums: Vec<(i32, u32)> = vec![];
                 for i in 0..num_keys {
-                    let elem = node.cells[i as usize];
+                    let elem = node.cell(i);
                     child_nums.push((elem.0 as i32, elem.1));
                 }
-                child_nums.push((-1, node.right_child));
+                child_nums.push((-1, node.right_child()));
 
-                let mut index = 0;
-                for child in child_nums {
-                    let key: i32 = child.0 as i32;
+                for (index, child) in child_nums.into_iter().enumerate() {
+                    let key: i32 = child.0;
                     let num = child.1;
-                    info!("Index: {} || key < {}", index, key);
-                    self.print_b_tree(num as usize, indent_level + 1);
-                    index = index + 1;
+                    lines.push(format!(
+                        "{}Index: {} || key < {}",
+                        Self::indent(indent_level),
+                        index,
+                        key
+                    ));
+                    self.add_tree_lines(num as usize, indent_level + 1, lines, summary);
                 }
             }
         }
     }
+
+    /**
+     * The tree below page_num as a Graphviz DOT graph. Nodes are labeled with their page
+     * number and the keys below them, solid edges lead to children and dashed ones follow
+     * next_leaf.
+     */
+    pub fn b_tree_dot(&mut self, page_num: usize) -> String {
+        let mut nodes = vec![];
+        let mut edges = vec![];
+        self.add_dot_node(page_num, &mut nodes, &mut edges);
+
+        let mut dot = String::from("digraph btree {\n    node [shape=box];\n");
+        for line in nodes.iter().chain(&edges) {
+            dot.push_str(&format!("    {}\n", line));
+        }
+        dot.push_str("}\n");
+
+        dot
+    }
+
+    /**
+     * Adds the node at page_num and everything below it to the graph. Returns the first and
+     * last key below the node, None when it holds no keys.
+     */
+    fn add_dot_node(
+        &mut self,
+        page_num: usize,
+        nodes: &mut Vec<String>,
+        edges: &mut Vec<String>,
+    ) -> Option<(u32, u32)> {
+        // the parent is listed before its children
+        let node_index = nodes.len();
+        nodes.push(String::new());
+
+        let (kind, key_range) = match self.get_page_node_type(page_num).unwrap() {
+            NodeType::Leaf => {
+                let node = self.get_page_leaf(page_num).unwrap();
+                let num_cells = node.num_cells();
+                let key_range = num_cells
+                    .checked_sub(1)
+                    .map(|last_cell| (node.get_cell_key(0), node.get_cell_key(last_cell)));
+
+                if node.next_leaf() != 0 {
+                    edges.push(format!(
+                        "page{} -> page{} [style=dashed, constraint=false];",
+                        page_num,
+                        node.next_leaf()
+                    ));
+                }
+                ("leaf", key_range)
+            }
+            NodeType::Internal => {
+                let node = self.get_page_internal(page_num).unwrap();
+                let children: Vec<u32> = match node.right_child() {
+                    0 => vec![],
+                    _ => (0..=node.num_keys()).map(|i| node.get_child(i)).collect(),
+                };
+
+                let mut key_range: Option<(u32, u32)> = None;
+                for child in children {
+                    edges.push(format!("page{} -> page{};", page_num, child));
+                    if let Some((first, last)) = self.add_dot_node(child as usize, nodes, edges) {
+                        key_range = Some((key_range.map_or(first, |(first, _)| first), last));
+                    }
+                }
+                ("internal", key_range)
+            }
+        };
+
+        let keys = match key_range {
+            Some((first, last)) if first == last => format!("key {}", first),
+            Some((first, last)) => format!("keys {}..{}", first, last),
+            None => String::from("no keys"),
+        };
+        nodes[node_index] = format!(
+            "page{} [label=\"{} page {}\\n{}\"];",
+            page_num, kind, page_num, keys
+        );
+
+        key_range
+    }
+}
+
+/**
+ * Whether the file starts with a table header, false for files of format version 0
+ */
+pub(crate) fn has_table_header(file: &File) -> bool {
+    let mut magic = [0u8; TABLE_HEADER_MAGIC.len()];
+    file.read_exact_at(&mut magic, TABLE_HEADER_MAGIC_OFFSET as u64)
+        .is_ok_and(|_| &magic == TABLE_HEADER_MAGIC)
+}
+
+/**
+ * Locks file against writes from other connections, or against every other connection when
+ * exclusive, retrying for up to busy_timeout while another connection holds a conflicting lock
+ */
+fn wait_for_lock(file: &File, exclusive: bool, busy_timeout: Duration) -> Result<(), DbError> {
+    let started_at = Instant::now();
+    loop {
+        let result = match exclusive {
+            true => file.try_lock(),
+            false => file.try_lock_shared(),
+        };
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) if started_at.elapsed() < busy_timeout => {
+                thread::sleep(BUSY_RETRY_INTERVAL)
+            }
+            Err(TryLockError::WouldBlock) => return Err(DbError::Busy),
+            Err(TryLockError::Error(_)) => return Err("Error locking db file".into()),
+        }
+    }
 }
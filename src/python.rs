@@ -0,0 +1,206 @@
+use pyo3::{
+    create_exception,
+    exceptions::PyException,
+    prelude::*,
+    types::{PyString, PyTuple},
+};
+
+use crate::{
+    cancel::CancellationToken,
+    db::{Column, Db, DbOptions},
+};
+
+/*
+ * Python bindings in the style of DB-API 2.0. Build the extension module with
+ * `cargo rustc --lib --release --features python,pyo3/extension-module --crate-type cdylib`
+ * and import the library as qba_db (libqba_db.so renamed to qba_db.so).
+ */
+
+create_exception!(qba_db, DatabaseError, PyException);
+
+/**
+ * Open db. Statements run through Db::execute, so the same text works from Python and the
+ * REPL.
+ */
+#[pyclass(module = "qba_db")]
+pub struct Connection {
+    // None once closed
+    db: Option<Db>,
+}
+
+#[pymethods]
+impl Connection {
+    pub fn cursor(slf: Py<Self>) -> Cursor {
+        Cursor {
+            connection: slf,
+            rows: vec![],
+            next_row: 0,
+            description: None,
+        }
+    }
+
+    /**
+     * Shortcut creating a cursor and executing sql on it, like sqlite3.Connection.execute
+     */
+    pub fn execute(slf: Py<Self>, py: Python<'_>, sql: &str) -> PyResult<Cursor> {
+        let mut cursor = Connection::cursor(slf);
+        cursor.run(py, sql)?;
+        Ok(cursor)
+    }
+
+    /**
+     * Writes are saved when they execute, so there is nothing left to commit
+     */
+    pub fn commit(&self) {}
+
+    pub fn close(&mut self) -> PyResult<()> {
+        match self.db.take() {
+            Some(mut db) => db.close_db().map_err(DatabaseError::new_err),
+            None => Ok(()),
+        }
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<()> {
+        self.close()
+    }
+}
+
+/**
+ * Rows of the last statement executed on the cursor. Ids are returned as int and every
+ * other column as str.
+ */
+#[pyclass(module = "qba_db")]
+pub struct Cursor {
+    connection: Py<Connection>,
+    rows: Vec<Py<PyTuple>>,
+    next_row: usize,
+    // (name, None, None, None, None, None, None) per column of the last select
+    description: Option<Vec<Py<PyTuple>>>,
+}
+
+impl Cursor {
+    fn run(&mut self, py: Python<'_>, sql: &str) -> PyResult<()> {
+        let mut connection = self.connection.borrow_mut(py);
+        let Some(db) = connection.db.as_mut() else {
+            return Err(DatabaseError::new_err(
+                "Cannot operate on a closed connection",
+            ));
+        };
+
+        let mut columns: Option<Vec<Column>> = None;
+        let mut rows = vec![];
+        let mut on_row = |values: &[(Column, String)]| {
+            columns.get_or_insert_with(|| values.iter().map(|(column, _)| *column).collect());
+            rows.push(values.to_vec());
+        };
+
+        db.execute_with_rows(sql, &CancellationToken::new(), &mut on_row)
+            .map_err(DatabaseError::new_err)?;
+
+        self.description = match columns {
+            Some(columns) => Some(column_descriptions(py, columns)?),
+            None => None,
+        };
+        self.rows = rows
+            .into_iter()
+            .map(|row| {
+                let values = row
+                    .into_iter()
+                    .map(|value| column_value(py, value))
+                    .collect::<PyResult<Vec<PyObject>>>()?;
+                Ok(PyTuple::new(py, values)?.unbind())
+            })
+            .collect::<PyResult<_>>()?;
+        self.next_row = 0;
+
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl Cursor {
+    pub fn execute<'py>(mut slf: PyRefMut<'py, Self>, sql: &str) -> PyResult<PyRefMut<'py, Self>> {
+        let py = slf.py();
+        slf.run(py, sql)?;
+        Ok(slf)
+    }
+
+    pub fn fetchone(&mut self, py: Python<'_>) -> Option<Py<PyTuple>> {
+        let row = self.rows.get(self.next_row)?.clone_ref(py);
+        self.next_row += 1;
+        Some(row)
+    }
+
+    pub fn fetchall(&mut self, py: Python<'_>) -> Vec<Py<PyTuple>> {
+        let rows = self.rows[self.next_row..]
+            .iter()
+            .map(|row| row.clone_ref(py))
+            .collect();
+        self.next_row = self.rows.len();
+        rows
+    }
+
+    #[getter]
+    pub fn description(&self, py: Python<'_>) -> Option<Vec<Py<PyTuple>>> {
+        self.description
+            .as_ref()
+            .map(|columns| columns.iter().map(|column| column.clone_ref(py)).collect())
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> Option<Py<PyTuple>> {
+        self.fetchone(py)
+    }
+}
+
+fn column_value(py: Python<'_>, (column, value): (Column, String)) -> PyResult<PyObject> {
+    match (column, value.parse::<u32>()) {
+        (Column::Id, Ok(id)) => Ok(id.into_pyobject(py)?.into_any().unbind()),
+        _ => Ok(PyString::new(py, &value).into_any().unbind()),
+    }
+}
+
+/**
+ * Cursor.description entries, DB-API leaves every field but the name optional
+ */
+fn column_descriptions(py: Python<'_>, columns: Vec<Column>) -> PyResult<Vec<Py<PyTuple>>> {
+    columns
+        .iter()
+        .map(|column| {
+            let mut fields = vec![PyString::new(py, column.name()).into_any()];
+            fields.extend((0..6).map(|_| py.None().into_bound(py)));
+            Ok(PyTuple::new(py, fields)?.unbind())
+        })
+        .collect()
+}
+
+/**
+ * Opens or creates the db file at path
+ */
+#[pyfunction]
+pub fn connect(path: String) -> PyResult<Connection> {
+    let db =
+        Db::open(path, DbOptions::default()).map_err(|e| DatabaseError::new_err(e.to_string()))?;
+    Ok(Connection { db: Some(db) })
+}
+
+#[pymodule]
+pub fn qba_db(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(connect, m)?)?;
+    m.add_class::<Connection>()?;
+    m.add_class::<Cursor>()?;
+    m.add("DatabaseError", m.py().get_type::<DatabaseError>())?;
+    Ok(())
+}
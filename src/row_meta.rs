@@ -0,0 +1,200 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{db::DbOptions, kv::Kv};
+
+/*
+ * Meta value layout, every field a u64 and 0 when unset. Values written before a field was
+ * added are shorter and read the missing fields as 0:
+ * expires_at (unix time in milliseconds) | created_at | updated_at | txn_id | version
+ */
+const META_FIELD_SIZE: usize = mem::size_of::<u64>();
+const EXPIRES_AT_OFFSET: usize = 0;
+const CREATED_AT_OFFSET: usize = EXPIRES_AT_OFFSET + META_FIELD_SIZE;
+const UPDATED_AT_OFFSET: usize = CREATED_AT_OFFSET + META_FIELD_SIZE;
+const TXN_ID_OFFSET: usize = UPDATED_AT_OFFSET + META_FIELD_SIZE;
+const VERSION_OFFSET: usize = TXN_ID_OFFSET + META_FIELD_SIZE;
+const META_VALUE_SIZE: usize = VERSION_OFFSET + META_FIELD_SIZE;
+
+/**
+ * Hidden columns of a row, maintained by the engine instead of set by statements. Times are
+ * unix times in milliseconds.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RowMeta {
+    // None for rows that never expire
+    pub expires_at: Option<u64>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // change counter of the db file once the commit that last wrote the row is saved. Rows
+    // written by the same commit share it.
+    pub txn_id: u64,
+    // starts at 1 and goes up by one with every update of the row, 0 for rows written
+    // before versions were kept
+    pub version: u64,
+}
+
+impl RowMeta {
+    fn from_value(value: &[u8]) -> RowMeta {
+        let mut padded = [0u8; META_VALUE_SIZE];
+        let length = value.len().min(META_VALUE_SIZE);
+        padded[..length].copy_from_slice(&value[..length]);
+        let field = |offset: usize| {
+            u64::from_ne_bytes(padded[offset..offset + META_FIELD_SIZE].try_into().unwrap())
+        };
+
+        RowMeta {
+            expires_at: Some(field(EXPIRES_AT_OFFSET)).filter(|&millis| millis != 0),
+            created_at: field(CREATED_AT_OFFSET),
+            updated_at: field(UPDATED_AT_OFFSET),
+            txn_id: field(TXN_ID_OFFSET),
+            version: field(VERSION_OFFSET),
+        }
+    }
+
+    fn to_value(self) -> [u8; META_VALUE_SIZE] {
+        let mut value = [0u8; META_VALUE_SIZE];
+        let fields = [
+            (EXPIRES_AT_OFFSET, self.expires_at.unwrap_or(0)),
+            (CREATED_AT_OFFSET, self.created_at),
+            (UPDATED_AT_OFFSET, self.updated_at),
+            (TXN_ID_OFFSET, self.txn_id),
+            (VERSION_OFFSET, self.version),
+        ];
+        for (offset, field) in fields {
+            value[offset..offset + META_FIELD_SIZE].copy_from_slice(&field.to_ne_bytes());
+        }
+
+        value
+    }
+}
+
+/**
+ * Hidden columns of every row of a db file, kept under the row's id in a Kv file next to it
+ * (<db file>.meta), since cells have no room left past the row. Only rows written while the
+ * store was open have an entry.
+ */
+pub(crate) struct RowMetaStore {
+    kv: Kv,
+    // rows expire this long after they were inserted, None for rows that never expire
+    ttl: Option<Duration>,
+}
+
+impl RowMetaStore {
+    /**
+     * Opens the store of the db file at db_file_path, whose writes wait up to busy_timeout
+     * for other connections to the db like its statements do
+     */
+    pub(crate) fn open(
+        db_file_path: &str,
+        ttl: Option<Duration>,
+        busy_timeout: Duration,
+    ) -> Result<RowMetaStore, &'static str> {
+        let options = DbOptions {
+            busy_timeout,
+            ..Default::default()
+        };
+
+        Ok(RowMetaStore {
+            kv: Kv::open_with_options(format!("{}.meta", db_file_path), &options)?,
+            ttl,
+        })
+    }
+
+    /**
+     * Hidden columns of every row that has them, by key
+     */
+    pub(crate) fn all(&mut self) -> Result<HashMap<u32, RowMeta>, &'static str> {
+        Ok(self
+            .kv
+            .range(..)?
+            .map(|(key, value)| (key, RowMeta::from_value(&value)))
+            .collect())
+    }
+
+    /**
+     * Hidden columns of the row with key, None when it has none
+     */
+    pub(crate) fn get(&mut self, key: u32) -> Result<Option<RowMeta>, &'static str> {
+        Ok(self.kv.get(key)?.map(|value| RowMeta::from_value(&value)))
+    }
+
+    /**
+     * Sets the hidden columns of a newly inserted row
+     */
+    pub(crate) fn record_insert(&mut self, key: u32, txn_id: u64) -> Result<(), &'static str> {
+        let now = now_millis();
+        let meta = RowMeta {
+            expires_at: self
+                .ttl
+                .map(|ttl| now.saturating_add(ttl.as_millis() as u64)),
+            created_at: now,
+            updated_at: now,
+            txn_id,
+            version: 1,
+        };
+
+        self.kv.put(key, &meta.to_value())
+    }
+
+    /**
+     * Moves the hidden columns of an updated row on to its next version. Returns the new
+     * version.
+     */
+    pub(crate) fn record_update(&mut self, key: u32, txn_id: u64) -> Result<u64, &'static str> {
+        let mut meta = self.get(key)?.unwrap_or_default();
+        meta.updated_at = now_millis();
+        meta.txn_id = txn_id;
+        meta.version += 1;
+
+        self.kv.put(key, &meta.to_value())?;
+        Ok(meta.version)
+    }
+
+    /**
+     * Drops the hidden columns of the rows with keys first..=last
+     */
+    pub(crate) fn remove_range(&mut self, first: u32, last: u32) -> Result<(), &'static str> {
+        let keys: Vec<u32> = self.kv.range(first..=last)?.map(|(key, _)| key).collect();
+        for key in keys {
+            self.kv.delete(key)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn file_length(&self) -> u64 {
+        self.kv.file_length()
+    }
+
+    pub(crate) fn clear(&mut self) -> Result<(), &'static str> {
+        self.kv.clear()
+    }
+
+    /**
+     * Keys of the rows whose ttl ran out by now
+     */
+    pub(crate) fn expired_keys(&mut self) -> Result<HashSet<u32>, &'static str> {
+        let now = now_millis();
+
+        Ok(self
+            .kv
+            .range(..)?
+            .filter(|(_, value)| {
+                RowMeta::from_value(value)
+                    .expires_at
+                    .is_some_and(|expires_at| expires_at <= now)
+            })
+            .map(|(key, _)| key)
+            .collect())
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
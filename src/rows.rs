@@ -0,0 +1,88 @@
+use std::marker::PhantomData;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    cursor::Cursor,
+    db::{insert_row, read_row, Column, Row, Table},
+};
+
+/**
+ * Iterator over the rows of a table, deserialized into T by column name
+ */
+pub struct RowsAs<'a, T> {
+    cursor: Cursor<'a>,
+    row_type: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Iterator for RowsAs<'_, T> {
+    type Item = Result<T, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursor.end_of_table {
+            return None;
+        }
+
+        let row = Cursor::get_cursor_value(&mut self.cursor).and_then(|value| read_row(value));
+        self.cursor.advance_cursor();
+
+        Some(row.and_then(|row| {
+            serde_json::from_value(row_to_value(row))
+                .map_err(|_| "Row does not match the fields of the target type")
+        }))
+    }
+}
+
+impl Table {
+    /**
+     * Reads every row in key order into the caller's own type. Fields are matched to columns
+     * by name, so T can leave out columns it does not need.
+     */
+    pub fn rows_as<T: DeserializeOwned>(&mut self) -> RowsAs<'_, T> {
+        RowsAs {
+            cursor: Cursor::table_start(self),
+            row_type: PhantomData,
+        }
+    }
+
+    /**
+     * Inserts a value whose fields are named after the columns. It is saved with the next
+     * commit of the db, like the inserts run through Db::execute.
+     */
+    pub fn insert_serialized<T: Serialize>(&mut self, value: &T) -> Result<(), &'static str> {
+        let Ok(Value::Object(fields)) = serde_json::to_value(value) else {
+            return Err("Value does not serialize to named fields");
+        };
+
+        let text = |column: Column| match fields.get(column.name()) {
+            Some(Value::String(text)) => Ok(text.clone()),
+            _ => Err("Value is missing a text column"),
+        };
+
+        let id = match fields.get(Column::Id.name()).and_then(Value::as_u64) {
+            Some(id) => u32::try_from(id).map_err(|_| "Id does not fit in u32")?,
+            None => return Err("Value is missing the id column"),
+        };
+
+        let row = Row {
+            id,
+            username: text(Column::Username)?,
+            email: text(Column::Email)?,
+        };
+
+        insert_row(self, &row)
+    }
+}
+
+fn row_to_value(row: Row) -> Value {
+    let mut fields = Map::new();
+    fields.insert(Column::Id.name().to_string(), Value::from(row.id));
+    fields.insert(
+        Column::Username.name().to_string(),
+        Value::from(row.username),
+    );
+    fields.insert(Column::Email.name().to_string(), Value::from(row.email));
+
+    Value::Object(fields)
+}
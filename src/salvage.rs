@@ -0,0 +1,195 @@
+use std::{fs::File, os::unix::fs::FileExt, path::Path};
+
+use crate::{
+    cancel::ProgressHandler,
+    db::{self, DbOptions, Row, Table},
+    encryption,
+    leaf_node::{LeafView, LEAF_NODE_MAX_CELLS},
+    metrics::trace_span,
+    pager::{self, NodeType, Page, PAGE_SIZE, TABLE_HEADER_SIZE},
+};
+
+/**
+ * What salvage found in a damaged db file
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SalvageReport {
+    pub pages: u32,
+    // pages that are neither a readable leaf nor an internal node, skipped
+    pub damaged_pages: u32,
+    // rows written to the new db file
+    pub rows: u32,
+    // rows whose key was already recovered from another leaf, dropped
+    pub duplicate_rows: u32,
+}
+
+/**
+ * Rows read from the leaf pages of a db file without following the tree
+ */
+pub(crate) struct LeafScan {
+    pub(crate) pages: u32,
+    pub(crate) damaged_pages: u32,
+    pub(crate) rows: Vec<Row>,
+}
+
+/**
+ * Recovers the rows of a db file whose tree is damaged into a new db file at
+ * out_file_path. Leaf pages are read one by one, so rows survive broken internal nodes,
+ * parent pointers and leaf links. Only plaintext db files can be salvaged.
+ */
+pub fn salvage(file_path: &str, out_file_path: &str) -> Result<SalvageReport, &'static str> {
+    salvage_with_progress(file_path, out_file_path, None)
+}
+
+/**
+ * Salvages like salvage, reporting the pages read so far to progress_handler. Returning
+ * true from its callback aborts the salvage before the new db file is created. A file whose
+ * header is damaged is read twice, each read reports its own pages.
+ */
+pub fn salvage_with_progress(
+    file_path: &str,
+    out_file_path: &str,
+    progress_handler: Option<&ProgressHandler>,
+) -> Result<SalvageReport, &'static str> {
+    trace_span!("salvage");
+    if Path::new(out_file_path).exists() {
+        return Err("Salvage output file already exists");
+    }
+
+    let file = match File::open(file_path) {
+        Ok(file) => file,
+        Err(_) => return Err("Error opening file"),
+    };
+    let file_length = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return Err("Error reading file metadata"),
+    };
+    if file_length > 0 && encryption::is_encrypted_file(&file) {
+        return Err("Encrypted db files can't be salvaged");
+    }
+
+    // with the header damaged, pages could start past it or at the start of a version 0
+    // file, whichever reads more rows is taken
+    let scan = if pager::has_table_header(&file) {
+        scan_leaf_rows(
+            &file,
+            TABLE_HEADER_SIZE as u64,
+            file_length,
+            progress_handler,
+        )?
+    } else {
+        let headerless = scan_leaf_rows(&file, 0, file_length, progress_handler)?;
+        let headered = scan_leaf_rows(
+            &file,
+            TABLE_HEADER_SIZE as u64,
+            file_length,
+            progress_handler,
+        )?;
+        match headered.rows.len() > headerless.rows.len() {
+            true => headered,
+            false => headerless,
+        }
+    };
+
+    let (rows, duplicate_rows) = load_rows(out_file_path, scan.rows)?;
+
+    Ok(SalvageReport {
+        pages: scan.pages,
+        damaged_pages: scan.damaged_pages,
+        rows,
+        duplicate_rows,
+    })
+}
+
+/**
+ * Reads the cells of every leaf page from pages_offset to the end of the file. A trailing
+ * piece shorter than a page counts as a damaged page.
+ */
+pub(crate) fn scan_leaf_rows(
+    file: &File,
+    pages_offset: u64,
+    file_length: u64,
+    progress_handler: Option<&ProgressHandler>,
+) -> Result<LeafScan, &'static str> {
+    let pages_length = file_length.saturating_sub(pages_offset);
+    let mut scan = LeafScan {
+        pages: pages_length.div_ceil(PAGE_SIZE as u64) as u32,
+        damaged_pages: 0,
+        rows: vec![],
+    };
+
+    for page_num in 0..pages_length / PAGE_SIZE as u64 {
+        if progress_handler.is_some_and(|handler| handler.report(page_num as u32 + 1)) {
+            return Err("Recovery aborted by the progress handler");
+        }
+
+        let mut page = Page {
+            data: [0u8; PAGE_SIZE],
+        };
+        if file
+            .read_exact_at(&mut page.data, pages_offset + page_num * PAGE_SIZE as u64)
+            .is_err()
+        {
+            return Err("Error trying to reach page from file");
+        }
+
+        match page.node_type() {
+            Ok(NodeType::Internal) => continue,
+            Ok(NodeType::Leaf) => {}
+            Err(_) => {
+                scan.damaged_pages += 1;
+                continue;
+            }
+        }
+
+        match leaf_rows(&mut page) {
+            Some(rows) => scan.rows.extend(rows),
+            None => scan.damaged_pages += 1,
+        }
+    }
+    if !pages_length.is_multiple_of(PAGE_SIZE as u64) {
+        scan.damaged_pages += 1;
+    }
+
+    Ok(scan)
+}
+
+/**
+ * Rows of a leaf page, None when its cell count or any of its cells can't be right
+ */
+fn leaf_rows(page: &mut Page) -> Option<Vec<Row>> {
+    let num_cells = LeafView::new(&mut page.data).num_cells();
+    if num_cells as usize > LEAF_NODE_MAX_CELLS {
+        return None;
+    }
+
+    (0..num_cells)
+        .map(|cell_num| {
+            let key = LeafView::new(&mut page.data).get_cell_key(cell_num);
+            let value = LeafView::new(&mut page.data).get_cell_value(cell_num);
+            db::read_row(value).ok().filter(|row| row.id == key)
+        })
+        .collect()
+}
+
+/**
+ * Inserts rows into a new db file in key order, skipping rows whose key is already taken.
+ * Returns the number of inserted and of skipped rows.
+ */
+pub(crate) fn load_rows(file_path: &str, mut rows: Vec<Row>) -> Result<(u32, u32), &'static str> {
+    rows.sort_by_key(|row| row.id);
+
+    let mut table = Table::open(file_path.to_string(), &DbOptions::default())?;
+    let (mut inserted_rows, mut duplicate_rows) = (0, 0);
+    for row in &rows {
+        if table.contains_key(row.id) {
+            duplicate_rows += 1;
+            continue;
+        }
+        db::insert_row(&mut table, row)?;
+        inserted_rows += 1;
+    }
+    table.commit()?;
+
+    Ok((inserted_rows, duplicate_rows))
+}
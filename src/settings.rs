@@ -0,0 +1,86 @@
+use log::LevelFilter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    // id: 1, username: a, email: b
+    Text,
+    // {"id":1,"username":"a","email":"b"}
+    Json,
+}
+
+/**
+ * Settings of one db session, changed with `.set name=value` and listed with `.show`
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub output: OutputMode,
+    // print run time and pages touched after each statement
+    pub timer: bool,
+    // the log crate has a single max level, so this applies to the whole process
+    pub log_level: LevelFilter,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            output: OutputMode::Text,
+            timer: false,
+            log_level: log::max_level(),
+        }
+    }
+}
+
+impl Settings {
+    /**
+     * Applies a `name=value` assignment
+     */
+    pub fn set(&mut self, assignment: &str) -> Result<(), String> {
+        let (name, value) = match assignment.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Err(format!("Expected name=value in setting {}", assignment)),
+        };
+
+        match name {
+            "output" => {
+                self.output = match value {
+                    "text" => OutputMode::Text,
+                    "json" => OutputMode::Json,
+                    _ => return Err(format!("Invalid output mode {}", value)),
+                };
+            }
+            "timer" => {
+                self.timer = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("Expected on or off for timer, got {}", value)),
+                };
+            }
+            "log_level" => {
+                self.log_level = match value.parse::<LevelFilter>() {
+                    Ok(level) => level,
+                    Err(_) => return Err(format!("Invalid log level {}", value)),
+                };
+                log::set_max_level(self.log_level);
+            }
+            _ => return Err(format!("Unknown setting {}", name)),
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Every setting as (name, value), in the order `.show` prints them
+     */
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let output = match self.output {
+            OutputMode::Text => "text",
+            OutputMode::Json => "json",
+        };
+
+        vec![
+            ("output", output.to_string()),
+            ("timer", String::from(if self.timer { "on" } else { "off" })),
+            ("log_level", self.log_level.to_string().to_lowercase()),
+        ]
+    }
+}
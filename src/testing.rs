@@ -0,0 +1,100 @@
+use std::{
+    env, fs,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::db::{Db, DbOptions};
+
+// tells apart the files of test dbs opened by the same process
+static NEXT_TEST_DB: AtomicU32 = AtomicU32::new(0);
+
+/**
+ * Db over a db file of its own in the temp directory, removed again on drop. Every test can
+ * open one without clobbering the files of tests running in parallel. Needs the testing
+ * feature.
+ */
+pub struct TestDb {
+    // None only while reopening and dropping
+    db: Option<Db>,
+    file_path: PathBuf,
+    options: fn() -> DbOptions,
+}
+
+impl TestDb {
+    /**
+     * Opens a new db, name is only used to recognize the file
+     */
+    pub fn new(name: &str) -> TestDb {
+        TestDb::with_options(name, DbOptions::default)
+    }
+
+    /**
+     * Opens a new db, and reopens it later, with the options returned by options
+     */
+    pub fn with_options(name: &str, options: fn() -> DbOptions) -> TestDb {
+        let file_path = env::temp_dir().join(format!(
+            "qba-db-{}-{}-{}.db",
+            name,
+            process::id(),
+            NEXT_TEST_DB.fetch_add(1, Ordering::Relaxed)
+        ));
+        // left over from an earlier process that had the same id
+        remove_db_files(&file_path);
+
+        TestDb {
+            db: Some(Db::new_with_options(
+                file_path.to_str().unwrap().to_string(),
+                options(),
+            )),
+            file_path,
+            options,
+        }
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    /**
+     * Closes the db and opens its file again, to check what was saved
+     */
+    pub fn reopen(&mut self) {
+        drop(self.db.take());
+        self.db = Some(Db::new_with_options(
+            self.file_path.to_str().unwrap().to_string(),
+            (self.options)(),
+        ));
+    }
+}
+
+impl Deref for TestDb {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        self.db.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for TestDb {
+    fn deref_mut(&mut self) -> &mut Db {
+        self.db.as_mut().unwrap()
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        // the db is saved when dropped, so it has to be closed before its file is removed
+        drop(self.db.take());
+        remove_db_files(&self.file_path);
+    }
+}
+
+// the db file and the row meta and double write files next to it
+fn remove_db_files(file_path: &Path) {
+    let _ = fs::remove_file(file_path);
+    let _ = fs::remove_file(format!("{}.meta", file_path.display()));
+    let _ = fs::remove_file(format!("{}.dwb", file_path.display()));
+}
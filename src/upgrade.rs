@@ -0,0 +1,46 @@
+use std::fs::{self, File};
+
+use log::info;
+
+use crate::{cancel::ProgressHandler, metrics::trace_span, salvage};
+
+/**
+ * Rewrites a db file of format version 0, from before the table header, in the current
+ * format. Rows are read from its leaf pages, whose layout is unchanged since, and inserted
+ * into a new file next to it, which then replaces it. Internal nodes gained fields since
+ * version 0, so the tree above the leaves is rebuilt by the inserts instead of copied.
+ * The pages read so far are reported to the progress handler, which can abort the upgrade
+ * and leave the file as it was. Returns the number of upgraded rows.
+ */
+pub(crate) fn upgrade_headerless_file(
+    file_path: &str,
+    file: &File,
+    file_length: u64,
+    progress_handler: Option<&ProgressHandler>,
+) -> Result<u32, &'static str> {
+    trace_span!("upgrade");
+    // version 0 never freed pages, so every leaf page is part of the tree
+    let scan = salvage::scan_leaf_rows(file, 0, file_length, progress_handler)?;
+    if scan.damaged_pages > 0 {
+        return Err("Db file has no table header. Unsupported file format");
+    }
+
+    let upgraded_file_path = format!("{}.upgrade", file_path);
+    // left over from an upgrade that was interrupted before the copy replaced the file
+    let _ = fs::remove_file(&upgraded_file_path);
+    let (rows, duplicate_rows) = salvage::load_rows(&upgraded_file_path, scan.rows)?;
+    if duplicate_rows > 0 {
+        let _ = fs::remove_file(&upgraded_file_path);
+        return Err("Duplicate key detected. Corrupt file");
+    }
+
+    if fs::rename(&upgraded_file_path, file_path).is_err() {
+        return Err("Error replacing db file with its upgraded copy");
+    }
+    info!(
+        "upgraded {} from format version 0, {} rows",
+        file_path, rows
+    );
+
+    Ok(rows)
+}
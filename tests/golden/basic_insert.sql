@@ -0,0 +1,3 @@
+insert 1 test_user test_email
+insert 2 test_user_2 test_email_2
+select
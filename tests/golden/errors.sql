@@ -0,0 +1,6 @@
+delete 1
+insert one alice alice@example.com
+insert 1 alice
+select password
+select from orders
+.unknown
@@ -0,0 +1,36 @@
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+insert 1 test_user test_email
+select
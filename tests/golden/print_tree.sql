@@ -0,0 +1,8 @@
+-- the tree as rows, with its summary last
+print_tree
+insert 1 user1 person1@example.com
+insert 2 user2 person2@example.com
+insert 3 user3 person3@example.com
+print_tree
+.set output=json
+print_tree
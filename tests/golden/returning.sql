@@ -0,0 +1,14 @@
+-- writes echoing the rows they stored
+insert 1 user1 person1@example.com returning id, username
+insert 2 user2 person2@example.com returning *
+insert 2 user2 person2@example.com returning id
+insert 3 user3 person3@example.com returning password
+insert 3 user3 person3@example.com returning email, id
+insert 4 user4 person4@example.com
+-- updates return the row after the change, deletes the rows they removed
+update users set username = renamed where id = 2 returning id, username
+update users set username = renamed where id = 9 returning id
+delete from users where id between 2 and 3 returning id, username
+.set output=json
+delete from users where id between 0 and 100 returning *
+select
@@ -0,0 +1,9 @@
+-- catalog and status as rows, for clients without the meta commands
+insert 1 user1 person1@example.com
+insert 2 user2 person2@example.com
+show tables
+show indexes
+show status
+.set output=json
+show tables
+show columns
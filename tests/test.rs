@@ -1,76 +1,2431 @@
-use qba_db::db::Db;
+use qba_db::{
+    cancel::{CancellationToken, ProgressHandler},
+    changes::{ChangeEvent, ChangeOp},
+    cursor::Cursor,
+    db::{deserialize_column, Column, Db, DbOptions, Row, TABLE_NAME},
+    error::DbError,
+    internal_node::InternalView,
+    kv::{Kv, KV_MAX_VALUE_SIZE},
+    leaf_node::{left_split_count, DuplicatePolicy, LeafView, LEAF_NODE_MAX_CELLS},
+    pager::{FORMAT_VERSION, PAGE_SIZE, TABLE_HEADER_SIZE},
+    salvage,
+    settings::OutputMode,
+    testing::TestDb,
+};
 
 #[cfg(test)]
 mod tests {
-    use std::{fs, path::Path};
+    use std::{
+        fs,
+        io::BufReader,
+        path::Path,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
 
     use super::*;
 
     fn init() {
-        // delete test.db on each run
-        let file_exists = Path::exists(Path::new("test.db"));
-        if file_exists {
-            fs::remove_file("test.db").unwrap();
+        std::env::set_var("RUST_LOG", "info");
+        let _ = env_logger::builder().is_test(true).try_init();
+    }
+
+    fn table_keys(db: &Db) -> Vec<u32> {
+        let mut table = db.table.lock().unwrap();
+        Cursor::table_start(&mut table).keys().collect()
+    }
+
+    /**
+     * Runs every script in tests/golden and compares its transcript with the .out file next
+     * to it. Set QBA_BLESS=1 to write the transcripts as the new expected output instead.
+     */
+    #[test]
+    fn golden_scripts_test() {
+        init();
+        let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+        let bless = std::env::var_os("QBA_BLESS").is_some();
+
+        let mut scripts: Vec<_> = fs::read_dir(&golden_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "sql" || extension == "txt")
+            })
+            .collect();
+        scripts.sort();
+        assert!(!scripts.is_empty());
+
+        let mut failed_scripts = vec![];
+        for script in scripts {
+            let name = script.file_stem().unwrap().to_str().unwrap().to_string();
+            let mut db = TestDb::new(&format!("golden_{}", name));
+            let transcript =
+                db.run_script_transcript(BufReader::new(fs::File::open(&script).unwrap()));
+
+            let expected_file = script.with_extension("out");
+            if bless {
+                fs::write(&expected_file, &transcript).unwrap();
+                continue;
+            }
+
+            let expected = fs::read_to_string(&expected_file).unwrap_or_default();
+            if transcript != expected {
+                failed_scripts.push(format!(
+                    "{}\n--- expected\n{}--- actual\n{}",
+                    name, expected, transcript
+                ));
+            }
+        }
+
+        assert!(
+            failed_scripts.is_empty(),
+            "golden output differs, rerun with QBA_BLESS=1 if the change is intended:\n{}",
+            failed_scripts.join("\n")
+        );
+    }
+
+    #[test]
+    fn select_columns_test() {
+        init();
+        let mut db = TestDb::new("select");
+
+        db.run_db_test(String::from("insert 1 test_user test_email"));
+
+        let outcome = db.execute_for_test("select id, email");
+        assert_eq!(
+            outcome.rows,
+            vec![vec![
+                (Column::Id, String::from("1")),
+                (Column::Email, String::from("test_email"))
+            ]]
+        );
+        assert_eq!(
+            db.execute_for_test("select *").values(),
+            vec![vec!["1", "test_user", "test_email"]]
+        );
+        assert!(db.execute_for_test("select id, password").is_err());
+        assert!(db.execute_for_test("select id,").is_err());
+
+        let long_username = "a".repeat(65);
+        let outcome = db.execute_for_test(&format!("insert 3 {} test_email", long_username));
+        assert!(outcome.error().unwrap().starts_with("String is too long"));
+
+        db.run_db_test(String::from("insert 2 test_user test_email_2"));
+        assert_eq!(
+            db.execute_for_test("select distinct username").values(),
+            vec![vec!["test_user"]]
+        );
+        assert_eq!(db.execute_for_test("select distinct").rows.len(), 2);
+        assert!(db.execute_for_test("select distinct password").is_err());
+
+        assert_eq!(
+            db.execute_for_test("select id where id in (2, 1, 7)")
+                .values(),
+            vec![vec!["1"], vec!["2"]]
+        );
+        assert_eq!(
+            db.execute_for_test("select username where id in (select id where id in (1))")
+                .values(),
+            vec![vec!["test_user"]]
+        );
+        assert!(db
+            .execute_for_test("select where id in (select id)")
+            .is_ok());
+        assert!(db
+            .execute_for_test("select * where id in (select email)")
+            .is_err());
+        assert!(db.execute_for_test("select * where id in (1, x)").is_err());
+        assert!(db.execute_for_test("select * where email in (1)").is_err());
+    }
+
+    #[test]
+    fn out_of_order_insert_test() {
+        init();
+        let mut db = TestDb::new("order");
+
+        for id in [5, 3, 8, 1, 9, 2, 7, 4, 6] {
+            db.run_db_test(format!("insert {} user_{} email_{}", id, id, id));
+        }
+
+        assert_eq!(table_keys(&db), (1..=9).collect::<Vec<u32>>());
+        assert_eq!(
+            db.execute_for_test("select count(*)").values(),
+            vec![vec!["9"]]
+        );
+        assert!(db.execute_for_test("insert 4 user_4 email_4").is_err());
+
+        // wide key lists follow next_leaf, sparse ones seek each key
+        assert_eq!(
+            db.execute_for_test("explain select id where id in (2, 3, 4, 5, 6, 7, 12)")
+                .values(),
+            vec![vec!["scan leaves from key 2 to key 7 for 6 keys"]]
+        );
+        assert_eq!(
+            db.execute_for_test("select id where id in (2, 3, 4, 5, 6, 7, 12)")
+                .values()
+                .len(),
+            6
+        );
+        assert_eq!(
+            db.execute_for_test("explain select * where id in (1, 9)")
+                .values(),
+            vec![vec!["seek 2 keys from the root"]]
+        );
+        assert_eq!(
+            db.execute_for_test("select id where id in (1, 9)").values(),
+            vec![vec!["1"], vec!["9"]]
+        );
+        assert_eq!(
+            db.execute_for_test("explain select").values(),
+            vec![vec!["scan table"]]
+        );
+        assert!(db
+            .execute_for_test("explain insert 10 user_10 email_10")
+            .is_err());
+    }
+
+    #[test]
+    fn leaf_search_orderings_test() {
+        init();
+
+        fn permutations(keys: &[u32]) -> Vec<Vec<u32>> {
+            if keys.len() <= 1 {
+                return vec![keys.to_vec()];
+            }
+            let mut orderings = vec![];
+            for (index, key) in keys.iter().enumerate() {
+                let mut rest = keys.to_vec();
+                rest.remove(index);
+                for mut ordering in permutations(&rest) {
+                    ordering.insert(0, *key);
+                    orderings.push(ordering);
+                }
+            }
+            orderings
+        }
+
+        let keys = [1, 3, 5, 7, 9];
+        for ordering in permutations(&keys) {
+            let mut db = TestDb::new("orderings");
+            for key in &ordering {
+                assert!(db
+                    .execute_for_test(&format!("insert {} user_{} email_{}", key, key, key))
+                    .is_ok());
+            }
+            assert_eq!(table_keys(&db), keys, "insert order {:?}", ordering);
+
+            // duplicates are rejected whichever leaf holds them
+            for key in &ordering {
+                let outcome = db.execute_for_test(&format!("insert {} other other", key));
+                assert_eq!(outcome.error(), Some("Duplicate key detected"));
+            }
+            assert_eq!(db.table.lock().unwrap().pager.row_count(), 5);
+
+            // every key finds its lower bound: itself, or else the next larger key
+            let mut table = db.table.lock().unwrap();
+            for key in 0..=10 {
+                let mut cursor = Cursor::table_find(&mut table, key);
+                if cursor.get_cursor_key().is_none() {
+                    cursor.advance_cursor();
+                }
+                let found_key = match cursor.end_of_table {
+                    true => None,
+                    false => cursor.get_cursor_key(),
+                };
+                assert_eq!(
+                    found_key,
+                    keys.iter().copied().find(|stored_key| *stored_key >= key),
+                    "key {} after insert order {:?}",
+                    key,
+                    ordering
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_reposition_test() {
+        init();
+        let mut db = TestDb::new("reposition");
+        for key in [1, 3, 5, 7] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+
+        let row = |id: u32| Row {
+            id,
+            username: format!("user_{}", id),
+            email: format!("email_{}", id),
+        };
+        let mut table = db.table.lock().unwrap();
+
+        // leaf splits move the cell under the cursor
+        let mut cursor = Cursor::table_find(&mut table, 3);
+        for key in [2, 4] {
+            let mut insert_cursor = Cursor::table_find(cursor.table, key);
+            LeafView::insert(&mut insert_cursor, key, &row(key), DuplicatePolicy::Reject).unwrap();
+        }
+        assert_eq!(cursor.get_cursor_key(), Some(3));
+        assert_eq!(
+            deserialize_column(
+                Cursor::get_cursor_value(&mut cursor).unwrap(),
+                Column::Username
+            )
+            .unwrap(),
+            "user_3"
+        );
+        assert_eq!(cursor.keys().collect::<Vec<u32>>(), vec![3, 4, 5, 7]);
+
+        // advancing from a deleted key lands on the key after it
+        let mut cursor = Cursor::table_find(&mut table, 4);
+        let mut delete_cursor = Cursor::table_find(cursor.table, 4);
+        LeafView::delete(&mut delete_cursor);
+        cursor.advance_cursor();
+        assert_eq!(cursor.get_cursor_key(), Some(5));
+
+        // reading a deleted key reads the key after it
+        let mut delete_cursor = Cursor::table_find(cursor.table, 5);
+        LeafView::delete(&mut delete_cursor);
+        assert_eq!(cursor.get_cursor_key(), Some(7));
+        assert_eq!(cursor.keys().collect::<Vec<u32>>(), vec![7]);
+    }
+
+    #[test]
+    fn cursor_seek_test() {
+        init();
+        let mut db = TestDb::new("seek");
+        for key in [2, 4, 6, 8, 10] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+
+        {
+            let mut table = db.table.lock().unwrap();
+            // leaves the leaf of key 6 empty
+            LeafView::delete(&mut Cursor::table_find(&mut table, 6));
+
+            let mut cursor = Cursor::seek(&mut table, 5);
+            assert_eq!(cursor.get_cursor_key(), Some(8));
+            cursor.prev();
+            assert_eq!(cursor.get_cursor_key(), Some(4));
+            cursor.next();
+            assert_eq!(cursor.get_cursor_key(), Some(8));
+            assert!(!Cursor::seek(&mut table, 11).is_valid());
+
+            let mut cursor = Cursor::seek_for_prev(&mut table, 7);
+            assert_eq!(cursor.get_cursor_key(), Some(4));
+            cursor.next();
+            assert_eq!(cursor.get_cursor_key(), Some(8));
+            assert!(!Cursor::seek_for_prev(&mut table, 1).is_valid());
+
+            let mut cursor = Cursor::seek_for_prev(&mut table, u32::MAX);
+            let mut keys = Vec::new();
+            while cursor.is_valid() {
+                keys.push(cursor.get_cursor_key().unwrap());
+                cursor.prev();
+            }
+            assert_eq!(keys, vec![10, 8, 4, 2]);
+        }
+
+        assert_eq!(
+            db.execute_for_test("select id from users order by id desc limit 3")
+                .values(),
+            vec![vec!["10"], vec!["8"], vec!["4"]]
+        );
+        assert_eq!(
+            db.execute_for_test(
+                "select id from users where id in (10, 4, 8, 5) order by id limit 2"
+            )
+            .values(),
+            vec![vec!["4"], vec!["8"]]
+        );
+        assert_eq!(
+            db.execute_for_test(
+                "select id from users where id in (select id from users order by id desc limit 2)"
+            )
+            .values(),
+            vec![vec!["8"], vec!["10"]]
+        );
+        assert_eq!(
+            db.execute_for_test("select id from users where id in (2, 10, 4) order by id desc")
+                .values(),
+            vec![vec!["10"], vec!["4"], vec!["2"]]
+        );
+        assert!(db
+            .execute_for_test("select id from users limit 0")
+            .values()
+            .is_empty());
+        assert!(db.execute_for_test("select id from users limit x").is_err());
+        assert!(db
+            .execute_for_test("select id from users order by email")
+            .is_err());
+    }
+
+    #[test]
+    fn contains_key_test() {
+        init();
+        let mut db = TestDb::new("contains_key");
+        for key in [3, 1, 4, 5, 9, 2, 6] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        assert_eq!(
+            db.execute_for_test("insert 4 user_4 email_4").error(),
+            Some("Duplicate key detected")
+        );
+
+        let mut table = db.table.lock().unwrap();
+        for key in 0..=10 {
+            assert_eq!(
+                table.contains_key(key),
+                [1, 2, 3, 4, 5, 6, 9].contains(&key),
+                "key {}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn max_key_test() {
+        init();
+        let mut db = TestDb::new("max_key");
+        assert_eq!(db.table.lock().unwrap().max_key(), None);
+
+        let mut max_key = 0;
+        for key in [5, 2, 8, 1, 9, 3, 12, 7] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+            max_key = max_key.max(key);
+            assert_eq!(db.table.lock().unwrap().max_key(), Some(max_key));
+        }
+
+        db.reopen();
+        let mut table = db.table.lock().unwrap();
+        assert_eq!(table.max_key(), Some(12));
+
+        // deleting the last keys empties the rightmost leaves
+        LeafView::delete(&mut Cursor::table_find(&mut table, 12));
+        assert_eq!(table.max_key(), Some(9));
+        LeafView::delete(&mut Cursor::table_find(&mut table, 9));
+        assert_eq!(table.max_key(), Some(8));
+        LeafView::delete(&mut Cursor::table_find(&mut table, 3));
+        assert_eq!(table.max_key(), Some(8));
+    }
+
+    #[test]
+    fn key_range_test() {
+        init();
+        let mut db = TestDb::new("key_range");
+        assert_eq!(db.table.lock().unwrap().key_range(), None);
+        assert_eq!(db.table.lock().unwrap().approx_row_count(), 0);
+
+        for key in [5, 2, 8, 1, 9, 3, 12, 7] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        assert_eq!(db.table.lock().unwrap().key_range(), Some((1, 12)));
+        assert_eq!(db.table.lock().unwrap().approx_row_count(), 8);
+
+        // the first leaves are left empty
+        assert!(db
+            .execute_for_test("delete from users where id between 0 and 2")
+            .is_ok());
+        db.reopen();
+        assert_eq!(db.table.lock().unwrap().key_range(), Some((3, 12)));
+        assert_eq!(db.table.lock().unwrap().approx_row_count(), 6);
+
+        assert!(db
+            .execute_for_test("delete from users where id between 0 and 100")
+            .is_ok());
+        assert_eq!(db.table.lock().unwrap().key_range(), None);
+        assert_eq!(db.table.lock().unwrap().approx_row_count(), 0);
+    }
+
+    #[test]
+    fn internal_node_without_right_child_test() {
+        init();
+        let mut db = TestDb::new("no_right_child");
+        for key in [1, 2] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+
+        let mut table = db.table.lock().unwrap();
+        let mut root = table.pager.get_page_internal(0).unwrap();
+        let (_, left_page_num) = root.cell(0);
+        let right_page_num = root.right_child();
+
+        // strip the root of its children, like a freshly created internal node
+        root.set_num_keys(0);
+        root.set_right_child(0);
+
+        // the first child becomes the right child, the next one gets a cell
+        InternalView::internal_node_insert(&mut table, 0, right_page_num as usize).unwrap();
+        InternalView::internal_node_insert(&mut table, 0, left_page_num as usize).unwrap();
+
+        let root = table.pager.get_page_internal(0).unwrap();
+        assert_eq!(root.right_child(), right_page_num);
+        assert_eq!(root.cell(0), (1, left_page_num));
+        assert_eq!(
+            Cursor::table_start(&mut table).keys().collect::<Vec<u32>>(),
+            vec![1, 2]
+        );
+
+        // pages past the page limit are an error instead of a panic
+        assert!(table.pager.get_page_node_type(1000).is_err());
+    }
+
+    #[test]
+    fn page_inspection_test() {
+        init();
+        let mut db = TestDb::new("page_inspection");
+        for key in 1..=3 {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+
+        // the internal root and one of its leaves
+        assert!(db.execute_for_test(".page 0").is_ok());
+        assert!(db.execute_for_test(".page 1").is_ok());
+        assert!(db
+            .execute_for_test(".page 40")
+            .error()
+            .unwrap()
+            .starts_with("Invalid page number"));
+        assert!(db.execute_for_test(".page first").is_err());
+
+        assert!(db.execute_for_test(".tree").is_ok());
+        assert!(db.execute_for_test(".tree dot").is_ok());
+        assert_eq!(
+            db.table.lock().unwrap().pager.b_tree_dot(0),
+            "digraph btree {
+    node [shape=box];
+    page0 [label=\"internal page 0\\nkeys 1..3\"];
+    page2 [label=\"leaf page 2\\nkey 1\"];
+    page1 [label=\"leaf page 1\\nkey 2\"];
+    page3 [label=\"leaf page 3\\nkey 3\"];
+    page0 -> page2;
+    page2 -> page1 [style=dashed, constraint=false];
+    page0 -> page1;
+    page1 -> page3 [style=dashed, constraint=false];
+    page0 -> page3;
+}
+"
+        );
+
+        // pages with an unknown node type are still dumped
+        db.table.lock().unwrap().pager.get_page_data(1).unwrap()[0] = 7;
+        assert!(db.execute_for_test(".page 1").is_ok());
+    }
+
+    #[test]
+    fn peek_test() {
+        init();
+        let mut db = TestDb::new("peek");
+        assert!(db.execute_for_test(".peek users").is_ok());
+        for key in 1..=10 {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+
+        assert!(db.execute_for_test(".peek users").is_ok());
+        assert!(db.execute_for_test(".peek main.users 3").is_ok());
+        assert!(db.execute_for_test(".peek users 20").is_ok());
+        assert_eq!(
+            db.execute_for_test(".peek users 0").error(),
+            Some("Invalid row count 0")
+        );
+        assert_eq!(
+            db.execute_for_test(".peek accounts").error(),
+            Some("Unknown table accounts")
+        );
+    }
+
+    #[test]
+    fn parent_pointer_test() {
+        init();
+        let mut db = TestDb::new("parent_pointer");
+        for key in [5, 1, 9, 3, 7, 2, 8] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        // every split so far kept the parent pointers
+        assert!(db.execute_for_test(".check").is_ok());
+
+        {
+            let mut table = db.table.lock().unwrap();
+            let leaf_page_num = table.pager.get_page_internal(0).unwrap().cell(0).1;
+            table
+                .pager
+                .get_page_leaf(leaf_page_num as usize)
+                .unwrap()
+                .set_parent(leaf_page_num);
+        }
+        assert!(db
+            .execute_for_test(".check")
+            .error()
+            .unwrap()
+            .starts_with("Parent pointer mismatch"));
+
+        assert_eq!(db.table.lock().unwrap().repair_parent_pointers(), 1);
+        assert_eq!(db.table.lock().unwrap().repair_parent_pointers(), 0);
+        assert!(db.execute_for_test(".check").is_ok());
+    }
+
+    #[test]
+    fn sibling_link_test() {
+        init();
+        let mut db = TestDb::new("sibling_link");
+        {
+            let mut table = db.table.lock().unwrap();
+            assert!(table.internal_level(0).is_empty());
+        }
+
+        for key in [4, 2, 6, 1, 3] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        assert!(db.execute_for_test(".check").is_ok());
+
+        let leaf_page_num = {
+            let mut table = db.table.lock().unwrap();
+            assert_eq!(table.internal_level(0), vec![0]);
+            // the level below the root holds leaves
+            assert!(table.internal_level(1).is_empty());
+
+            let leaf_page_num = table.pager.get_page_internal(0).unwrap().cell(0).1;
+            table
+                .pager
+                .get_page_internal(0)
+                .unwrap()
+                .set_next_sibling(leaf_page_num);
+            leaf_page_num
+        };
+        assert_eq!(
+            db.execute_for_test(".check").error(),
+            Some(
+                format!(
+                    "Sibling link mismatch at depth 0: links give pages [0, {}] but the level holds [0]",
+                    leaf_page_num
+                )
+                .as_str()
+            )
+        );
+    }
+
+    #[test]
+    fn delete_key_range_test() {
+        init();
+        let mut db = TestDb::new("delete_range");
+        for key in 1..=12 {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        let num_pages = db.table.lock().unwrap().pager.num_pages;
+
+        assert!(db
+            .execute_for_test("delete from users where id between 3 and 8")
+            .is_ok());
+        assert_eq!(
+            db.execute_for_test("select id from users").values(),
+            vec![
+                vec!["1"],
+                vec!["2"],
+                vec!["9"],
+                vec!["10"],
+                vec!["11"],
+                vec!["12"]
+            ]
+        );
+        assert_eq!(db.table.lock().unwrap().pager.row_count(), 6);
+        assert!(db.execute_for_test(".check").is_ok());
+
+        // the unlinked leaves are used again before the file grows
+        for key in 3..=8 {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        assert_eq!(db.table.lock().unwrap().pager.num_pages, num_pages);
+        assert!(db.execute_for_test(".check").is_ok());
+
+        assert!(db
+            .execute_for_test("delete from users where id between 0 and 100")
+            .is_ok());
+        assert!(db
+            .execute_for_test("select id from users")
+            .values()
+            .is_empty());
+        assert!(db.execute_for_test(".check").is_ok());
+
+        db.reopen();
+        assert!(db
+            .execute_for_test("select id from users")
+            .values()
+            .is_empty());
+        assert!(db.execute_for_test("insert 5 user_5 email_5").is_ok());
+        assert_eq!(
+            db.execute_for_test("select id from users").values(),
+            vec![vec!["5"]]
+        );
+
+        assert!(db
+            .execute_for_test("delete from users where id = 5")
+            .is_err());
+        assert!(db.execute_for_test("delete from users").is_err());
+    }
+
+    #[test]
+    fn ttl_test() {
+        init();
+        let mut db = TestDb::with_options("ttl", || DbOptions {
+            ttl: Some(Duration::from_millis(200)),
+            ..Default::default()
+        });
+        for key in [1, 2] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        assert_eq!(
+            db.execute_for_test("select id from users").values(),
+            vec![vec!["1"], vec!["2"]]
+        );
+
+        thread::sleep(Duration::from_millis(300));
+        db.run_db_test(String::from("insert 3 user_3 email_3"));
+        // expired rows are hidden before they are purged
+        assert_eq!(
+            db.execute_for_test("select id from users").values(),
+            vec![vec!["3"]]
+        );
+        assert_eq!(db.scan_parallel(2).unwrap().len(), 1);
+        let mut live_db = TestDb::new("ttl_live");
+        live_db.run_db_test(String::from("insert 3 user_3 email_3"));
+        assert_eq!(
+            db.export_snapshot().unwrap(),
+            live_db.export_snapshot().unwrap()
+        );
+        assert_eq!(db.table.lock().unwrap().pager.row_count(), 3);
+
+        assert_eq!(db.purge_expired().unwrap(), 2);
+        assert_eq!(db.purge_expired().unwrap(), 0);
+        assert_eq!(db.table.lock().unwrap().pager.row_count(), 1);
+        assert!(db.execute_for_test(".check").is_ok());
+
+        // the expiry times are saved next to the db file
+        db.reopen();
+        assert_eq!(
+            db.execute_for_test("select id from users").values(),
+            vec![vec!["3"]]
+        );
+        thread::sleep(Duration::from_millis(300));
+        assert!(db
+            .execute_for_test("select id from users")
+            .values()
+            .is_empty());
+        assert_eq!(db.purge_expired().unwrap(), 1);
+
+        // a delete hands back only the rows a select would see
+        db.run_db_test(String::from("insert 4 user_4 email_4"));
+        thread::sleep(Duration::from_millis(300));
+        db.run_db_test(String::from("insert 5 user_5 email_5"));
+        assert_eq!(
+            db.execute_for_test("delete from users where id between 4 and 5 returning id")
+                .values(),
+            vec![vec!["5"]]
+        );
+        assert!(db.execute_for_test("select").values().is_empty());
+    }
+
+    #[test]
+    fn row_metadata_test() {
+        init();
+        let mut db = TestDb::with_options("row_metadata", || DbOptions {
+            row_metadata: true,
+            ..Default::default()
+        });
+        let now = || {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64
+        };
+
+        let before = now();
+        for key in [2, 1] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        let after = now();
+        // every insert was committed on its own
+        let change_counter = db.table.lock().unwrap().pager.change_counter();
+
+        let outcome = db.execute_for_test(
+            "select id, _meta(created_at), _meta(updated_at), _meta(txn_id), _meta(expires_at) from users",
+        );
+        let rows = outcome.values();
+        assert_eq!(rows.len(), 2);
+        for (row, txn_id) in rows.iter().zip([change_counter, change_counter - 1]) {
+            let created_at: u64 = row[1].parse().unwrap();
+            assert!(before <= created_at && created_at <= after);
+            assert_eq!(row[2], row[1]);
+            assert_eq!(row[3], txn_id.to_string());
+            assert_eq!(row[4], "");
+        }
+
+        assert!(db.execute_for_test("select _meta(id) from users").is_err());
+        assert_eq!(
+            db.execute_for_test("select * from users where id in (1)")
+                .values(),
+            vec![vec!["1", "user_1", "email_1"]]
+        );
+    }
+
+    #[test]
+    fn row_version_test() {
+        init();
+        let mut db = TestDb::with_options("row_version", || DbOptions {
+            row_metadata: true,
+            ..Default::default()
+        });
+        db.run_db_test("insert 1 user_1 email_1".to_string());
+        assert_eq!(
+            db.execute_for_test("select _meta(version) from users")
+                .values(),
+            vec![vec!["1"]]
+        );
+
+        // two writers both read version 1, only the first one's update goes through
+        assert!(db
+            .execute_for_test("update users set username = first where id = 1 and _version = 1")
+            .is_ok());
+        assert_eq!(db.rows_affected(), 1);
+        assert!(db
+            .execute_for_test("update users set username = second where id = 1 and _version = 1")
+            .is_ok());
+        assert_eq!(db.rows_affected(), 0);
+
+        assert!(db
+            .execute_for_test(
+                "update users set username = second, email = other where id = 1 and _version = 2"
+            )
+            .is_ok());
+        assert_eq!(db.rows_affected(), 1);
+        assert_eq!(
+            db.execute_for_test("select id, username, email, _meta(version) from users")
+                .values(),
+            vec![vec!["1", "second", "other", "3"]]
+        );
+
+        // without a version check the update always applies, missing rows are not updated
+        assert!(db
+            .execute_for_test("update users set email = last where id = 1")
+            .is_ok());
+        assert_eq!(db.rows_affected(), 1);
+        assert!(db
+            .execute_for_test("update users set email = last where id = 2")
+            .is_ok());
+        assert_eq!(db.rows_affected(), 0);
+
+        assert!(db
+            .execute_for_test("update users set id = 5 where id = 1")
+            .is_err());
+        assert!(db
+            .execute_for_test("update users set email = last")
+            .is_err());
+
+        // a returning clause reads the hidden columns of the row it just stored
+        assert_eq!(
+            db.execute_for_test("insert 2 user_2 email_2 returning id, _meta(version)")
+                .values(),
+            vec![vec!["2", "1"]]
+        );
+    }
+
+    #[test]
+    fn bloom_filter_test() {
+        init();
+        let mut db = TestDb::with_options("bloom_filter", || DbOptions {
+            bloom_filter: true,
+            ..Default::default()
+        });
+        for key in [2, 4, 6, 8] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        db.reopen();
+
+        // absent keys are ruled out without reading a page
+        {
+            let mut table = db.table.lock().unwrap();
+            let metrics_before = table.pager.metrics;
+            for key in [1, 3, 5, 7] {
+                assert!(!table.contains_key(key));
+            }
+            assert_eq!(table.pager.metrics, metrics_before);
+            assert!(table.contains_key(4));
+        }
+        assert_eq!(
+            db.execute_for_test("select id where id in (3, 4, 5)")
+                .values(),
+            vec![vec!["4"]]
+        );
+        assert_eq!(
+            db.execute_for_test("insert 6 user_6 email_6").error(),
+            Some("Duplicate key detected")
+        );
+
+        // keys inserted through another connection are found once the filter is rebuilt
+        let mut other_db = Db::new(db.file_path().to_str().unwrap().to_string());
+        other_db.run_db_test(String::from("insert 5 user_5 email_5"));
+        assert_eq!(
+            db.execute_for_test("select id where id in (5)").values(),
+            vec![vec!["5"]]
+        );
+        drop(other_db);
+
+        assert!(db.execute_for_test("truncate").is_ok());
+        assert!(db
+            .execute_for_test("select id where id in (4)")
+            .rows
+            .is_empty());
+        assert!(db.execute_for_test("insert 4 user_4 email_4").is_ok());
+        assert_eq!(
+            db.execute_for_test("select id where id in (4)").values(),
+            vec![vec!["4"]]
+        );
+    }
+
+    #[test]
+    fn append_insert_test() {
+        init();
+        let mut db = TestDb::new("append_insert");
+        for key in 1..=8 {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        db.reopen();
+
+        let page_lookups = |db: &mut TestDb, key: u32| {
+            let metrics_before = db.metrics();
+            assert!(db
+                .execute_for_test(&format!("insert {} user_{} email_{}", key, key, key))
+                .is_ok());
+            let metrics = db.metrics();
+            metrics.cache_hits + metrics.cache_misses
+                - metrics_before.cache_hits
+                - metrics_before.cache_misses
+        };
+        // the first append after opening descends from the root, the next one doesn't
+        let descending_lookups = page_lookups(&mut db, 9);
+        let appending_lookups = page_lookups(&mut db, 10);
+        assert!(
+            appending_lookups < descending_lookups,
+            "{} lookups appending, {} descending",
+            appending_lookups,
+            descending_lookups
+        );
+
+        // keys that aren't appended still descend and find duplicates
+        assert_eq!(
+            db.execute_for_test("insert 10 user_10 email_10").error(),
+            Some("Duplicate key detected")
+        );
+        assert!(db
+            .execute_for_test("delete from users where id between 9 and 10")
+            .is_ok());
+        assert!(db.execute_for_test("insert 9 user_9 email_9").is_ok());
+        assert!(db.execute_for_test("insert 11 user_11 email_11").is_ok());
+        assert_eq!(table_keys(&db), [1, 2, 3, 4, 5, 6, 7, 8, 9, 11]);
+        assert!(db.execute_for_test(".check").is_ok());
+    }
+
+    #[test]
+    fn insert_batch_test() {
+        init();
+        let mut db = TestDb::new("insert_batch");
+        for key in [4, 8, 12] {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+
+        let rows = |keys: &[u32]| -> Vec<Row> {
+            keys.iter()
+                .map(|&key| Row {
+                    id: key,
+                    username: format!("user_{}", key),
+                    email: format!("email_{}", key),
+                })
+                .collect()
+        };
+        {
+            let mut table = db.table.lock().unwrap();
+            assert_eq!(table.insert_batch(rows(&[10, 1, 6, 14, 2, 9])), Ok(6));
+            assert_eq!(table.insert_batch(vec![]), Ok(0));
+
+            // nothing is inserted when the batch itself is invalid
+            assert_eq!(
+                table.insert_batch(rows(&[3, 5, 3])),
+                Err("Duplicate key detected")
+            );
+            let mut long_row = rows(&[5]);
+            long_row[0].email = "e".repeat(65);
+            assert_eq!(table.insert_batch(long_row), Err("String is too long"));
+            assert_eq!(table.approx_row_count(), 9);
+
+            // and when a key is already in the table
+            assert_eq!(
+                table.insert_batch(rows(&[3, 4, 5])),
+                Err("Duplicate key detected")
+            );
+            assert_eq!(table.approx_row_count(), 9);
+            assert_eq!(table.insert_batch(rows(&[3])), Ok(1));
+        }
+        assert_eq!(table_keys(&db), [1, 2, 3, 4, 6, 8, 9, 10, 12, 14]);
+        assert!(db.execute_for_test(".check").is_ok());
+
+        db.reopen();
+        assert_eq!(
+            db.execute_for_test("select * where id in (9)").values(),
+            vec![vec!["9", "user_9", "email_9"]]
+        );
+        assert_eq!(table_keys(&db), [1, 2, 3, 4, 6, 8, 9, 10, 12, 14]);
+    }
+
+    #[test]
+    fn cloned_handles_test() {
+        init();
+        let mut db = TestDb::new("cloned_handles");
+        db.run_db_test(String::from("insert 1 user_1 email_1"));
+
+        // one handle per thread, writing to the same table
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let mut handle = Db::clone(&db);
+                thread::spawn(move || {
+                    for key in [2, 3, 4].map(|key| worker * 3 + key) {
+                        handle
+                            .execute(&format!("insert {} user_{} email_{}", key, key, key))
+                            .unwrap();
+                        assert_eq!(handle.rows_affected(), 1);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(table_keys(&db), (1..=13).collect::<Vec<u32>>());
+
+        // settings belong to each handle
+        let mut handle = Db::clone(&db);
+        handle.execute(".set output=json").unwrap();
+        assert_eq!(handle.settings().output, OutputMode::Json);
+        assert_eq!(db.settings().output, OutputMode::Text);
+        drop(handle);
+
+        db.reopen();
+        assert_eq!(table_keys(&db), (1..=13).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn select_for_delete_test() {
+        init();
+        let mut db = TestDb::new("select_for_delete");
+        for key in 1..=12 {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        assert_eq!(
+            db.execute_for_test("select id, username for delete limit 2")
+                .values(),
+            vec![vec!["1", "user_1"], vec!["2", "user_2"]]
+        );
+        assert_eq!(db.rows_affected(), 2);
+        assert!(db.execute_for_test("select for delete").is_err());
+        assert!(db
+            .execute_for_test("select where id in (5) for delete limit 1")
+            .is_err());
+
+        // handles taking rows at the same time never get the same row
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let mut handle = Db::clone(&db);
+                thread::spawn(move || {
+                    let mut taken = vec![];
+                    loop {
+                        let outcome = handle.execute_for_test("select id for delete limit 2");
+                        assert!(outcome.is_ok());
+                        if outcome.rows.is_empty() {
+                            return taken;
+                        }
+                        taken.extend(outcome.values().concat().into_iter().map(String::from));
+                    }
+                })
+            })
+            .collect();
+        let mut taken: Vec<u32> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .map(|key| key.parse().unwrap())
+            .collect();
+        taken.sort();
+        assert_eq!(taken, (3..=12).collect::<Vec<u32>>());
+
+        db.reopen();
+        assert!(table_keys(&db).is_empty());
+    }
+
+    #[test]
+    fn fill_factor_test() {
+        // a leaf of 9 cells splits 10 cells: appends keep 90% on the left, other splits 50%
+        assert_eq!(left_split_count(9, true, 0.9), 9);
+        assert_eq!(left_split_count(9, false, 0.9), 5);
+        assert_eq!(left_split_count(9, true, 0.5), 5);
+        // the new leaf always gets at least one cell, the old one at least half
+        assert_eq!(left_split_count(9, true, 1.0), 9);
+        assert_eq!(left_split_count(9, true, 0.1), 5);
+        // an odd number of cells leaves the extra one on the left
+        assert_eq!(left_split_count(4, false, 0.9), 3);
+        assert_eq!(
+            left_split_count(LEAF_NODE_MAX_CELLS, true, 0.9),
+            LEAF_NODE_MAX_CELLS
+        );
+    }
+
+    #[test]
+    fn cancel_statement_test() {
+        init();
+        let cancel_db = "test_cancel.db";
+        if Path::exists(Path::new(cancel_db)) {
+            fs::remove_file(cancel_db).unwrap();
+        }
+        let mut db = Db::new_with_options(
+            String::from(cancel_db),
+            DbOptions {
+                max_statement_duration: Some(Duration::ZERO),
+                ..Default::default()
+            },
+        );
+
+        // single leaf scans never reach a cancellation check
+        db.run_db_test(String::from("insert 1 test_user test_email"));
+        assert!(db.execute("select").is_ok());
+
+        // scans over several leaves time out
+        db.run_db_test(String::from("insert 2 test_user test_email"));
+        assert_eq!(db.execute("select"), Err(DbError::Cancelled.to_string()));
+        drop(db);
+
+        let mut db = Db::new(String::from(cancel_db));
+        let token = CancellationToken::new();
+        assert!(db.execute_with_cancel("select", &token).is_ok());
+
+        token.cancel();
+        assert_eq!(
+            db.execute_with_cancel("select", &token),
+            Err(DbError::Cancelled.to_string())
+        );
+
+        drop(db);
+        fs::remove_file(cancel_db).unwrap();
+    }
+
+    #[test]
+    fn progress_handler_test() {
+        init();
+        let progress_db = "test_progress.db";
+        if Path::exists(Path::new(progress_db)) {
+            fs::remove_file(progress_db).unwrap();
+        }
+
+        let progress_calls = Arc::new(AtomicU32::new(0));
+        let abort = Arc::new(AtomicU32::new(0));
+        let handler = {
+            let progress_calls = Arc::clone(&progress_calls);
+            let abort = Arc::clone(&abort);
+            ProgressHandler::new(2, move |_| {
+                progress_calls.fetch_add(1, Ordering::Relaxed);
+                abort.load(Ordering::Relaxed) == 1
+            })
+        };
+        let mut db = Db::new_with_options(
+            String::from(progress_db),
+            DbOptions {
+                progress_handler: Some(handler),
+                ..Default::default()
+            },
+        );
+
+        for id in 1..=5 {
+            db.run_db_test(format!("insert {} test_user test_email", id));
+        }
+
+        // five leaves means four page changes, so the handler runs twice
+        assert!(db.execute("select").is_ok());
+        assert_eq!(progress_calls.load(Ordering::Relaxed), 2);
+
+        abort.store(1, Ordering::Relaxed);
+        assert!(db.execute("select").is_err());
+
+        drop(db);
+        fs::remove_file(progress_db).unwrap();
+    }
+
+    #[test]
+    fn metrics_test() {
+        init();
+        let metrics_db = "test_metrics.db";
+        if Path::exists(Path::new(metrics_db)) {
+            fs::remove_file(metrics_db).unwrap();
+        }
+
+        {
+            let mut db = Db::new(String::from(metrics_db));
+            for id in 1..=3 {
+                db.run_db_test(format!("insert {} test_user test_email", id));
+            }
+
+            let metrics = db.metrics();
+            assert_eq!(metrics.leaf_splits, 2);
+            assert_eq!(metrics.cache_misses, 0);
+            assert!(metrics.page_writes > 0);
+        }
+
+        // pages of a reopened db are read once and cached after that
+        let mut db = Db::new(String::from(metrics_db));
+        db.run_db_test(String::from("select"));
+        let metrics = db.metrics();
+        assert_eq!(metrics.page_reads, metrics.cache_misses);
+        assert!(metrics.cache_misses > 0);
+
+        db.run_db_test(String::from("select"));
+        assert_eq!(db.metrics().cache_misses, metrics.cache_misses);
+        assert!(db.metrics().cache_hit_rate() > metrics.cache_hit_rate());
+
+        drop(db);
+        fs::remove_file(metrics_db).unwrap();
+    }
+
+    #[test]
+    fn cache_size_test() {
+        init();
+        let cache_db = "test_cache.db";
+        if Path::exists(Path::new(cache_db)) {
+            fs::remove_file(cache_db).unwrap();
+        }
+        let mut db = Db::new_with_options(
+            String::from(cache_db),
+            DbOptions {
+                cache_size: Some(2),
+                ..Default::default()
+            },
+        );
+        let cached_pages = |db: &Db| db.table.lock().unwrap().pager.cached_pages();
+
+        for id in [4, 1, 6, 2, 5, 3] {
+            db.run_db_test(format!("insert {} test_user test_email", id));
+            assert!(cached_pages(&db) <= 2);
+        }
+
+        // evicted pages are read back from the db file
+        assert_eq!(table_keys(&db), (1..=6).collect::<Vec<u32>>());
+        assert!(db.execute(".check").is_ok());
+        assert!(db.execute(".cache").is_ok());
+        assert!(db.metrics().cache_evictions > 0);
+
+        db.clear_cache();
+        assert_eq!(cached_pages(&db), 0);
+
+        let misses_before = db.metrics().cache_misses;
+        db.set_cache_size(100);
+        db.run_db_test(String::from("select"));
+        assert!(db.metrics().cache_misses > misses_before);
+        assert!(cached_pages(&db) > 2);
+
+        db.set_cache_size(1);
+        assert_eq!(cached_pages(&db), 1);
+
+        drop(db);
+        fs::remove_file(cache_db).unwrap();
+    }
+
+    #[test]
+    fn settings_test() {
+        init();
+        let mut db = TestDb::new("settings");
+        db.run_db_test(String::from("insert 1 test_user test_email"));
+
+        assert_eq!(db.settings().output, OutputMode::Text);
+        assert!(db.execute_for_test(".set output=json").is_ok());
+        assert_eq!(db.settings().output, OutputMode::Json);
+        assert!(db.execute_for_test("select").is_ok());
+        assert!(db.execute_for_test(".show").is_ok());
+
+        assert!(db.execute_for_test(".set output=xml").is_err());
+        assert!(db.execute_for_test(".set colour=on").is_err());
+        assert!(db.execute_for_test(".set output").is_err());
+        assert_eq!(db.settings().output, OutputMode::Json);
+
+        assert!(db.execute_for_test(".timer on").is_ok());
+        assert!(db.settings().timer);
+        assert!(db.execute_for_test("select").is_ok());
+        assert!(db.execute_for_test(".timer maybe").is_err());
+        assert!(db.execute_for_test(".set timer=off").is_ok());
+        assert!(!db.settings().timer);
+    }
+
+    #[test]
+    fn run_script_test() {
+        init();
+        let mut db = TestDb::new("script");
+
+        let script = "insert 1 test_user test_email\nbogus\ninsert 2 test_user_2 test_email_2\n";
+        assert_eq!(db.run_script(script.as_bytes(), false), 1);
+        assert_eq!(db.run_script(script.as_bytes(), true), 2);
+
+        let script = "select\n.exit\nbogus\n";
+        assert_eq!(db.run_script(script.as_bytes(), false), 0);
+    }
+
+    #[test]
+    fn script_comments_test() {
+        init();
+        let mut db = TestDb::new("comments");
+
+        let script = "-- seed users\n\
+            insert 1 test_user test_email -- first user\n\
+            /* the second user\n\
+            insert 99 skipped skipped\n\
+            */ insert 2 test--user test_email\n\
+            insert /* inline */ 3 test_user test_email\n\
+            select\n";
+        assert_eq!(db.run_script(script.as_bytes(), false), 0);
+        assert_eq!(table_keys(&db), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reopen_db_test() {
+        init();
+        let mut db = TestDb::new("reopen");
+        for id in 1..=3 {
+            db.run_db_test(format!("insert {} test_user test_email", id));
         }
+        // closing the db saves it
+        db.reopen();
 
-        std::env::set_var("RUST_LOG", "info");
-        let _ = env_logger::builder().is_test(true).try_init();
+        assert_eq!(table_keys(&db), vec![1, 2, 3]);
+        assert_eq!(db.table.lock().unwrap().pager.row_count(), 3);
+        assert!(db.execute_for_test(".check").is_ok());
+
+        // a failed insert doesn't change the row count
+        assert!(db
+            .execute_for_test("insert 2 test_user test_email")
+            .is_err());
+        assert_eq!(db.table.lock().unwrap().pager.row_count(), 3);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_db_test() {
+        use qba_db::{async_db::AsyncDb, db::ExecuteResponse};
+
+        init();
+        let async_db_file = "test_async.db";
+        if Path::exists(Path::new(async_db_file)) {
+            fs::remove_file(async_db_file).unwrap();
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let db = AsyncDb::open(String::from(async_db_file)).await.unwrap();
+
+            let response = db.execute("insert 1 test_user test_email").await;
+            assert!(matches!(response, Ok(ExecuteResponse::Success)));
+
+            let response = db.execute("insert 1 test_user test_email").await;
+            assert!(response.is_err());
+
+            db.close().await.unwrap();
+        });
+
+        fs::remove_file(async_db_file).unwrap();
+    }
+
+    #[test]
+    fn change_stream_test() {
+        init();
+        let mut db = TestDb::new("changes");
+
+        // writes before subscribing are not reported
+        db.run_db_test(String::from("insert 1 first first@mail"));
+        let changes = db.subscribe_changes();
+
+        db.run_db_test(String::from("insert 2 second second@mail"));
+        assert!(db.execute_for_test("insert 2 second second@mail").is_err());
+        assert!(db.execute_for_test("select *").is_ok());
+
+        let row = |id: u32, name: &str| Row {
+            id,
+            username: name.to_string(),
+            email: format!("{}@mail", name),
+        };
+
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            ChangeEvent {
+                table: TABLE_NAME,
+                op: ChangeOp::Insert,
+                key: 2,
+                before: None,
+                after: Some(row(2, "second")),
+            }
+        );
+        assert!(changes.try_recv().is_err());
+
+        assert!(db.execute_for_test("truncate").is_ok());
+        let deleted: Vec<ChangeEvent> = changes.try_iter().collect();
+        assert_eq!(
+            deleted.iter().map(|event| event.op).collect::<Vec<_>>(),
+            vec![ChangeOp::Delete, ChangeOp::Delete]
+        );
+        assert_eq!(deleted[0].before, Some(row(1, "first")));
+        assert_eq!(deleted[1].key, 2);
+
+        drop(changes);
+        db.run_db_test(String::from("insert 3 third third@mail"));
+    }
+
+    #[test]
+    fn scan_parallel_test() {
+        init();
+        let mut db = TestDb::new("parallel");
+
+        assert!(db.scan_parallel(4).unwrap().is_empty());
+
+        for id in [5, 3, 8, 1, 9, 2, 7, 4, 6, 10] {
+            db.run_db_test(format!("insert {} user_{} email_{}", id, id, id));
+        }
+
+        for n_threads in [0, 1, 3, 4, 64] {
+            let rows = db.scan_parallel(n_threads).unwrap();
+            assert_eq!(
+                rows.iter().map(|row| row.id).collect::<Vec<u32>>(),
+                (1..=10).collect::<Vec<u32>>()
+            );
+            assert_eq!(rows[6].username, "user_7");
+        }
+
+        // writes after a scan still go through
+        db.run_db_test(String::from("insert 11 user_11 email_11"));
+        assert_eq!(db.scan_parallel(2).unwrap().len(), 11);
+    }
+
+    #[test]
+    fn table_sizes_test() {
+        init();
+        let other_db = "test_table_sizes_other.db";
+        let _ = fs::remove_file(other_db);
+        let mut db = TestDb::with_options("table_sizes", || DbOptions {
+            row_metadata: true,
+            ..Default::default()
+        });
+        assert!(db
+            .execute_for_test(&format!("attach '{}' as other", other_db))
+            .is_ok());
+        for key in 1..=6 {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+        assert!(db
+            .execute_for_test("insert into other.users 1 user_1 email_1")
+            .is_ok());
+
+        // an internal root over one leaf per row, and a lone root leaf
+        let sizes = db.table_sizes().unwrap();
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[0].name, "users");
+        assert_eq!(sizes[0].tree_pages, 7);
+        assert_eq!(sizes[0].unused_pages, 0);
+        assert_eq!(
+            sizes[0].file_bytes as usize,
+            TABLE_HEADER_SIZE + 7 * PAGE_SIZE
+        );
+        assert!(sizes[0].row_meta_bytes > 0);
+        assert_eq!(sizes[1].name, "other.users");
+        assert_eq!(sizes[1].tree_pages, 1);
+        // attached with the options of the db, so with row metadata too
+        assert!(sizes[1].row_meta_bytes > 0);
+
+        // leaves emptied by a delete are unlinked, their pages stay in the file
+        assert!(db
+            .execute_for_test("delete from users where id between 2 and 3")
+            .is_ok());
+        let sizes = db.table_sizes().unwrap();
+        assert_eq!(sizes[0].tree_pages, 5);
+        assert_eq!(sizes[0].unused_pages, 2);
+        assert!(db.execute_for_test(".dbinfo").is_ok());
+
+        drop(db);
+        fs::remove_file(other_db).unwrap();
+        fs::remove_file(format!("{}.meta", other_db)).unwrap();
+    }
+
+    #[test]
+    fn quota_test() {
+        init();
+        let mut db = TestDb::with_options("quota", || DbOptions {
+            max_pages: Some(6),
+            ..Default::default()
+        });
+        for key in 1..=3 {
+            db.run_db_test(format!("insert {} user_{} email_{}", key, key, key));
+        }
+
+        // the next insert could split the leaf, the internal root and add a new root
+        let quota_error = DbError::QuotaExceeded.to_string();
+        assert_eq!(
+            db.execute_for_test("insert 4 user_4 email_4").error(),
+            Some(quota_error.as_str())
+        );
+        // a duplicate key at the quota is still reported as a duplicate
+        assert_eq!(
+            db.execute_for_test("insert 2 user_2 email_2").error(),
+            Some("Duplicate key detected")
+        );
+        let batch = vec![Row {
+            id: 4,
+            username: String::from("user_4"),
+            email: String::from("email_4"),
+        }];
+        assert_eq!(
+            db.table.lock().unwrap().insert_batch(batch),
+            Err(quota_error.as_str())
+        );
+        assert_eq!(db.execute_for_test("select id").values().len(), 3);
+
+        // pages freed by a delete are handed out again without growing the file
+        assert!(db
+            .execute_for_test("delete from users where id between 2 and 3")
+            .is_ok());
+        assert!(db.execute_for_test("insert 4 user_4 email_4").is_ok());
+        assert_eq!(
+            db.execute_for_test("select id").values(),
+            vec![vec!["1"], vec!["4"]]
+        );
+        db.reopen();
+        assert!(
+            db.table_sizes().unwrap()[0].file_bytes <= (TABLE_HEADER_SIZE + 6 * PAGE_SIZE) as u64
+        );
+    }
+
+    #[test]
+    fn attach_test() {
+        init();
+        let main_db = "test_attach_main.db";
+        let other_db = "test_attach_other.db";
+        for file in [main_db, other_db] {
+            if Path::exists(Path::new(file)) {
+                fs::remove_file(file).unwrap();
+            }
+        }
+
+        fn select(db: &mut Db, statement: &str) -> Vec<String> {
+            let outcome = db.execute_for_test(statement);
+            assert!(outcome.is_ok());
+            outcome.values().iter().map(|row| row.join(" ")).collect()
+        }
+
+        {
+            let mut db = Db::new(String::from(main_db));
+            db.execute(&format!("attach '{}' as other", other_db))
+                .unwrap();
+
+            for id in 1..=4 {
+                db.execute(&format!("insert {} main_{} main_{}@a.com", id, id, id))
+                    .unwrap();
+            }
+            for id in [3, 4, 5] {
+                db.execute(&format!(
+                    "insert into other.users {} other_{} other_{}@b.com",
+                    id, id, id
+                ))
+                .unwrap();
+            }
+
+            assert_eq!(select(&mut db, "select id from users").len(), 4);
+            assert_eq!(
+                select(&mut db, "select id, username from other.users"),
+                vec!["3 other_3", "4 other_4", "5 other_5"]
+            );
+            assert_eq!(
+                select(
+                    &mut db,
+                    "select username from main.users where id in (3, 5)"
+                ),
+                vec!["main_3"]
+            );
+
+            // keys selected from one database, rows read from the other
+            assert_eq!(
+                select(
+                    &mut db,
+                    "select username from users where id in (select id from other.users)"
+                ),
+                vec!["main_3", "main_4"]
+            );
+            assert_eq!(
+                select(
+                    &mut db,
+                    "select username from other.users where id in (select id from users where id in (1, 4))"
+                ),
+                vec!["other_4"]
+            );
+
+            assert!(db.execute("select id from missing.users").is_err());
+            assert!(db.execute("select id from other.orders").is_err());
+            assert!(db
+                .execute("attach 'test_attach_again.db' as other")
+                .is_err());
+            assert!(db.execute("attach 'test_attach_again.db' as main").is_err());
+            assert!(db.execute("attach test_attach_again.db as again").is_err());
+
+            db.execute("truncate other.users").unwrap();
+            assert!(select(&mut db, "select id from other.users").is_empty());
+            db.execute("insert into other.users 6 other_6 other_6@b.com")
+                .unwrap();
+        }
+
+        // the attached database was saved to its own file
+        let mut db = Db::new(String::from(other_db));
+        assert_eq!(table_keys(&db), vec![6]);
+        assert_eq!(select(&mut db, "select id from users").len(), 1);
+        drop(db);
+
+        // attached databases keep the options they were opened with, the attach statement
+        // uses those of the db
+        let quota_options = || DbOptions {
+            max_pages: Some(6),
+            ..Default::default()
+        };
+        let quota_error = Err(DbError::QuotaExceeded.to_string());
+        let mut db = Db::new(String::from(main_db));
+        db.attach(other_db, "other", &quota_options()).unwrap();
+        for id in 7..=8 {
+            db.execute(&format!("insert into other.users {} o o", id))
+                .unwrap();
+        }
+        assert_eq!(db.execute("insert into other.users 9 o o"), quota_error);
+        assert!(db.execute("insert 9 main_9 main_9@a.com").is_ok());
+        drop(db);
+
+        let mut db = Db::new_with_options(String::from(main_db), quota_options());
+        db.execute(&format!("attach '{}' as other", other_db))
+            .unwrap();
+        assert_eq!(db.execute("insert into other.users 9 o o"), quota_error);
+
+        drop(db);
+        fs::remove_file(main_db).unwrap();
+        fs::remove_file(other_db).unwrap();
+    }
+
+    #[test]
+    fn busy_test() {
+        init();
+        let busy_db = "test_busy.db";
+        if Path::exists(Path::new(busy_db)) {
+            fs::remove_file(busy_db).unwrap();
+        }
+        {
+            let mut db = Db::new(String::from(busy_db));
+            db.run_db_test(String::from("insert 1 user_1 email_1"));
+        }
+
+        let grouped_options = || DbOptions {
+            commit_window: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let mut other_db = Db::new_with_options(
+            String::from(busy_db),
+            DbOptions {
+                busy_timeout: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+
+        // the uncommitted write keeps the db file locked
+        let mut db = Db::new_with_options(String::from(busy_db), grouped_options());
+        db.execute("insert 2 user_2 email_2").unwrap();
+        let busy = Err(DbError::Busy.to_string());
+        assert_eq!(other_db.execute("insert 3 user_3 email_3"), busy);
+        assert_eq!(other_db.execute("select"), busy);
+        assert_eq!(
+            other_db.execute_retrying_busy("select", 2, Duration::from_millis(1)),
+            busy
+        );
+        // opening restores and writes headers, so it waits for the write to be flushed too
+        let open_options = DbOptions {
+            busy_timeout: Duration::from_millis(20),
+            double_write: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            Db::open(String::from(busy_db), open_options).err(),
+            Some(DbError::Busy)
+        );
+
+        // committing releases the lock, and the other connection sees the committed row
+        drop(db);
+        other_db.execute("insert 3 user_3 email_3").unwrap();
+        assert_eq!(table_keys(&other_db), vec![1, 2, 3]);
+
+        let mut db = Db::new_with_options(String::from(busy_db), grouped_options());
+        db.execute("select").unwrap();
+        assert_eq!(table_keys(&db), vec![1, 2, 3]);
+        db.execute("insert 4 user_4 email_4").unwrap();
+
+        let committer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(db);
+        });
+        other_db
+            .execute_retrying_busy("insert 5 user_5 email_5", 10, Duration::from_millis(5))
+            .unwrap();
+        committer.join().unwrap();
+        assert_eq!(table_keys(&other_db), vec![1, 2, 3, 4, 5]);
+
+        // meta commands and show read the pages the other connection committed
+        let mut db = Db::new(String::from(busy_db));
+        db.execute("insert 6 user_6 email_6").unwrap();
+        drop(db);
+        assert_eq!(
+            other_db.execute_for_test("show tables").values(),
+            vec![vec!["users", "6", "7"]]
+        );
+        assert!(other_db.execute(".check").is_ok());
+        assert_eq!(other_db.table_sizes().unwrap()[0].tree_pages, 7);
+
+        drop(other_db);
+        fs::remove_file(busy_db).unwrap();
+    }
+
+    #[test]
+    fn export_snapshot_test() {
+        init();
+        let ascending_db = "test_snapshot_ascending.db";
+        let shuffled_db = "test_snapshot_shuffled.db";
+        for file in [ascending_db, shuffled_db] {
+            if Path::exists(Path::new(file)) {
+                fs::remove_file(file).unwrap();
+            }
+        }
+
+        let mut ascending = Db::new(String::from(ascending_db));
+        let mut shuffled = Db::new_with_options(
+            String::from(shuffled_db),
+            DbOptions {
+                fill_factor: 0.5,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            ascending.export_snapshot().unwrap(),
+            shuffled.export_snapshot().unwrap()
+        );
+
+        for id in 1..=8 {
+            ascending.run_db_test(format!("insert {} user_{} email_{}", id, id, id));
+        }
+        for id in [5, 3, 8, 1, 7, 2, 6, 4] {
+            shuffled.run_db_test(format!("insert {} user_{} email_{}", id, id, id));
+        }
+
+        let snapshot = ascending.export_snapshot().unwrap();
+        assert!(snapshot.starts_with(b"qba-snap"));
+        assert_eq!(snapshot, shuffled.export_snapshot().unwrap());
+
+        shuffled.run_db_test(String::from("insert 9 user_9 email_9"));
+        assert_ne!(snapshot, shuffled.export_snapshot().unwrap());
+
+        drop(ascending);
+        drop(shuffled);
+        fs::remove_file(ascending_db).unwrap();
+        fs::remove_file(shuffled_db).unwrap();
+    }
+
+    #[test]
+    fn kv_test() {
+        init();
+        let kv_db = "test_kv.db";
+        if Path::exists(Path::new(kv_db)) {
+            fs::remove_file(kv_db).unwrap();
+        }
+
+        {
+            let mut kv = Kv::open(String::from(kv_db)).unwrap();
+            for key in [5, 3, 8, 1, 9, 2, 7, 4, 6] {
+                kv.put(key, format!("value_{}", key).as_bytes()).unwrap();
+            }
+            kv.put(4, b"").unwrap();
+            kv.put(6, &[0, 1, 2]).unwrap();
+            assert!(kv.put(10, &[0; KV_MAX_VALUE_SIZE + 1]).is_err());
+
+            assert_eq!(kv.get(5).unwrap(), Some(b"value_5".to_vec()));
+            assert_eq!(kv.get(4).unwrap(), Some(vec![]));
+            assert_eq!(kv.get(10).unwrap(), None);
+
+            // emptied leaves are skipped by ranges
+            assert!(kv.delete(2).unwrap());
+            assert!(kv.delete(3).unwrap());
+            assert!(!kv.delete(3).unwrap());
+        }
+
+        let mut kv = Kv::open(String::from(kv_db)).unwrap();
+        let keys = |range: Vec<(u32, Vec<u8>)>| range.into_iter().map(|(key, _)| key).collect();
+        let all_keys: Vec<u32> = keys(kv.range(..).unwrap().collect());
+        assert_eq!(all_keys, vec![1, 4, 5, 6, 7, 8, 9]);
+        let middle_keys: Vec<u32> = keys(kv.range(2..=6).unwrap().collect());
+        assert_eq!(middle_keys, vec![4, 5, 6]);
+        let tail_keys: Vec<u32> = keys(kv.range(8..).unwrap().collect());
+        assert_eq!(tail_keys, vec![8, 9]);
+        assert_eq!(kv.range(6..7).unwrap().next(), Some((6, vec![0, 1, 2])));
+
+        kv.put(3, b"back").unwrap();
+        assert_eq!(kv.get(3).unwrap(), Some(b"back".to_vec()));
+
+        // each write reloads what another connection wrote, instead of saving over it
+        let mut other_kv = Kv::open(String::from(kv_db)).unwrap();
+        kv.put(10, b"first").unwrap();
+        other_kv.put(11, b"second").unwrap();
+        kv.put(12, b"third").unwrap();
+        // and reads see it too
+        other_kv.put(13, b"fourth").unwrap();
+        assert_eq!(kv.get(13).unwrap(), Some(b"fourth".to_vec()));
+        drop(other_kv);
+        let tail_keys: Vec<u32> = keys(kv.range(10..).unwrap().collect());
+        assert_eq!(tail_keys, vec![10, 11, 12, 13]);
+
+        drop(kv);
+        fs::remove_file(kv_db).unwrap();
+    }
+
+    #[test]
+    fn truncate_test() {
+        init();
+        let truncate_db = "test_truncate.db";
+        if Path::exists(Path::new(truncate_db)) {
+            fs::remove_file(truncate_db).unwrap();
+        }
+
+        {
+            let mut db = Db::new(String::from(truncate_db));
+            for id in 1..=5 {
+                db.run_db_test(format!("insert {} test_user test_email", id));
+            }
+
+            assert!(db.execute("truncate").is_ok());
+            assert!(table_keys(&db).is_empty());
+            assert!(db.execute(".dbinfo").is_ok());
+            assert_eq!(
+                fs::metadata(truncate_db).unwrap().len(),
+                (TABLE_HEADER_SIZE + PAGE_SIZE) as u64
+            );
+
+            db.run_db_test(String::from("insert 7 test_user test_email"));
+            db.run_db_test(String::from("insert 6 test_user test_email"));
+        }
+
+        let mut db = Db::new(String::from(truncate_db));
+        assert_eq!(table_keys(&db), vec![6, 7]);
+        assert!(db.execute(".check").is_ok());
+
+        drop(db);
+        fs::remove_file(truncate_db).unwrap();
+    }
+
+    #[test]
+    fn commit_window_test() {
+        init();
+        let commit_db = "test_commit.db";
+        let commit_window_db = "test_commit_window.db";
+        for file in [commit_db, commit_window_db] {
+            if Path::exists(Path::new(file)) {
+                fs::remove_file(file).unwrap();
+            }
+        }
+
+        // every write is committed on its own by default
+        let mut db = Db::new(String::from(commit_db));
+        db.run_db_test(String::from("insert 1 test_user test_email"));
+        assert_eq!(
+            fs::metadata(commit_db).unwrap().len(),
+            (TABLE_HEADER_SIZE + PAGE_SIZE) as u64
+        );
+
+        // writes wait for the commit window to close
+        let mut grouped_db = Db::new_with_options(
+            String::from(commit_window_db),
+            DbOptions {
+                commit_window: Duration::from_millis(100),
+                ..Default::default()
+            },
+        );
+        grouped_db.run_db_test(String::from("insert 1 test_user test_email"));
+        assert_eq!(
+            fs::metadata(commit_window_db).unwrap().len(),
+            TABLE_HEADER_SIZE as u64
+        );
+
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(
+            fs::metadata(commit_window_db).unwrap().len(),
+            (TABLE_HEADER_SIZE + PAGE_SIZE) as u64
+        );
+
+        drop(db);
+        drop(grouped_db);
+        fs::remove_file(commit_db).unwrap();
+        fs::remove_file(commit_window_db).unwrap();
+    }
+
+    #[test]
+    fn double_write_test() {
+        init();
+        let double_write_db = "test_double_write.db";
+        let buffer_file = "test_double_write.db.dwb";
+        let _ = fs::remove_file(double_write_db);
+        let double_write_options = || DbOptions {
+            double_write: true,
+            ..Default::default()
+        };
+
+        {
+            let mut db =
+                Db::new_with_options(String::from(double_write_db), double_write_options());
+            for id in 1..=3 {
+                db.run_db_test(format!("insert {} user_{} email_{}", id, id, id));
+            }
+            // emptied once each commit reached the db file
+            assert_eq!(fs::metadata(buffer_file).unwrap().len(), 0);
+        }
+        let saved_file = fs::read(double_write_db).unwrap();
+
+        // buffer left by a crash in the middle of overwriting page 1, laid out as
+        // file length | slot count | offset, length, bytes of each slot | checksum
+        let page_offset = TABLE_HEADER_SIZE + PAGE_SIZE;
+        let page = &saved_file[page_offset..page_offset + PAGE_SIZE];
+        let mut buffer = vec![];
+        buffer.extend_from_slice(&(saved_file.len() as u64).to_ne_bytes());
+        buffer.extend_from_slice(&1u32.to_ne_bytes());
+        buffer.extend_from_slice(&(page_offset as u64).to_ne_bytes());
+        buffer.extend_from_slice(&(PAGE_SIZE as u32).to_ne_bytes());
+        buffer.extend_from_slice(page);
+        let checksum = buffer.iter().fold(0xcbf29ce484222325u64, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+        buffer.extend_from_slice(&checksum.to_ne_bytes());
+
+        let mut torn_file = saved_file.clone();
+        torn_file[page_offset + PAGE_SIZE / 2..page_offset + PAGE_SIZE].fill(0xff);
+
+        // a buffer cut short before its checksum was written is ignored
+        fs::write(double_write_db, &torn_file).unwrap();
+        fs::write(buffer_file, &buffer[..buffer.len() - 1]).unwrap();
+        drop(Db::new_with_options(
+            String::from(double_write_db),
+            double_write_options(),
+        ));
+        assert_eq!(fs::read(double_write_db).unwrap(), torn_file);
+
+        // a complete one makes the torn page whole again
+        fs::write(buffer_file, &buffer).unwrap();
+        let db = Db::new_with_options(String::from(double_write_db), double_write_options());
+        assert_eq!(fs::read(double_write_db).unwrap(), saved_file);
+        assert_eq!(fs::metadata(buffer_file).unwrap().len(), 0);
+        assert_eq!(table_keys(&db), vec![1, 2, 3]);
+        assert_eq!(db.table.lock().unwrap().pager.recovery().restored_slots, 1);
+        drop(db);
+
+        fs::remove_file(double_write_db).unwrap();
+        fs::remove_file(buffer_file).unwrap();
+    }
+
+    #[test]
+    fn format_upgrade_test() {
+        init();
+        let upgrade_db = "test_upgrade.db";
+        let _ = fs::remove_file(upgrade_db);
+
+        {
+            let mut db = Db::new(String::from(upgrade_db));
+            for id in [4, 2, 5, 1, 3] {
+                db.run_db_test(format!("insert {} user_{} email_{}", id, id, id));
+            }
+        }
+
+        // the same pages without the table header, as written before the header existed
+        let headered_file = fs::read(upgrade_db).unwrap();
+        fs::write(upgrade_db, &headered_file[TABLE_HEADER_SIZE..]).unwrap();
+
+        // an upgrade aborted through the progress handler leaves the file as it was
+        let aborting_options = || DbOptions {
+            progress_handler: Some(ProgressHandler::new(1, |_| true)),
+            ..Default::default()
+        };
+        assert_eq!(
+            Db::open(String::from(upgrade_db), aborting_options()).err(),
+            Some(DbError::Storage("Recovery aborted by the progress handler"))
+        );
+        assert_eq!(
+            fs::read(upgrade_db).unwrap(),
+            &headered_file[TABLE_HEADER_SIZE..]
+        );
+
+        let mut db = Db::new(String::from(upgrade_db));
+        assert_eq!(table_keys(&db), vec![1, 2, 3, 4, 5]);
+        assert_eq!(db.table.lock().unwrap().pager.row_count(), 5);
+        assert_eq!(db.table.lock().unwrap().pager.recovery().upgraded_rows, 5);
+        assert_eq!(
+            db.execute_for_test("select * where id in (3)").rows[0][2].1,
+            "email_3"
+        );
+        drop(db);
+
+        let upgraded_file = fs::read(upgrade_db).unwrap();
+        assert_eq!(&upgraded_file[..7], b"qba-db\0");
+        assert_eq!(upgraded_file[7], FORMAT_VERSION);
+
+        // files from a later format version are refused rather than misread
+        let mut newer_file = upgraded_file.clone();
+        newer_file[7] = FORMAT_VERSION + 1;
+        fs::write(upgrade_db, &newer_file).unwrap();
+        assert_eq!(
+            Db::open(String::from(upgrade_db), DbOptions::default()).err(),
+            Some(DbError::Storage(
+                "Db file was written by a newer version of qba-db. Unsupported file format"
+            ))
+        );
+
+        fs::remove_file(upgrade_db).unwrap();
+    }
+
+    #[test]
+    fn salvage_test() {
+        init();
+        let damaged_db = "test_salvage_damaged.db";
+        let salvaged_db = "test_salvage_out.db";
+        let _ = fs::remove_file(damaged_db);
+        let _ = fs::remove_file(salvaged_db);
+
+        {
+            let mut db = Db::new(String::from(damaged_db));
+            for id in 1..=6 {
+                db.run_db_test(format!("insert {} user_{} email_{}", id, id, id));
+            }
+        }
+
+        // the root, an internal node, is overwritten, so no leaf can be reached from it
+        let mut file = fs::read(damaged_db).unwrap();
+        file[TABLE_HEADER_SIZE..TABLE_HEADER_SIZE + PAGE_SIZE].fill(0xff);
+        fs::write(damaged_db, &file).unwrap();
+
+        // aborted through the progress handler before the new file is created
+        let aborting_handler = ProgressHandler::new(2, |pages| pages >= 4);
+        assert_eq!(
+            salvage::salvage_with_progress(damaged_db, salvaged_db, Some(&aborting_handler)),
+            Err("Recovery aborted by the progress handler")
+        );
+        assert!(!Path::new(salvaged_db).exists());
+
+        let pages_read = Arc::new(AtomicU32::new(0));
+        let progress_handler = ProgressHandler::new(1, {
+            let pages_read = Arc::clone(&pages_read);
+            move |pages| {
+                pages_read.store(pages, Ordering::Relaxed);
+                false
+            }
+        });
+        let report =
+            salvage::salvage_with_progress(damaged_db, salvaged_db, Some(&progress_handler))
+                .unwrap();
+        assert_eq!(pages_read.load(Ordering::Relaxed), report.pages);
+        assert_eq!(report.rows, 6);
+        assert_eq!(report.damaged_pages, 1);
+        assert_eq!(report.duplicate_rows, 0);
+        assert_eq!(
+            report.pages as usize,
+            (file.len() - TABLE_HEADER_SIZE) / PAGE_SIZE
+        );
+
+        let mut db = Db::new(String::from(salvaged_db));
+        assert_eq!(table_keys(&db), (1..=6).collect::<Vec<u32>>());
+        assert_eq!(
+            db.execute_for_test("select * where id in (4)").rows[0][1].1,
+            "user_4"
+        );
+        drop(db);
+
+        // an existing file is never overwritten
+        assert!(salvage::salvage(damaged_db, salvaged_db).is_err());
+
+        fs::remove_file(damaged_db).unwrap();
+        fs::remove_file(salvaged_db).unwrap();
     }
 
+    #[cfg(feature = "encryption")]
     #[test]
-    fn basic_insert_test() {
+    fn encrypted_db_test() {
         init();
-        let mut db = Db::new(String::from("test.db"));
+        let encrypted_db = "test_encrypted.db";
+        if Path::exists(Path::new(encrypted_db)) {
+            fs::remove_file(encrypted_db).unwrap();
+        }
+        let encrypted_options = |passphrase: &str| DbOptions {
+            passphrase: Some(String::from(passphrase)),
+            ..Default::default()
+        };
+
+        {
+            let mut db =
+                Db::new_with_options(String::from(encrypted_db), encrypted_options("secret"));
+            db.run_db_test(String::from("insert 1 test_user plaintext_email"));
+        }
+
+        let file_contents = fs::read(encrypted_db).unwrap();
+        let needle = b"plaintext_email";
+        assert!(!file_contents.windows(needle.len()).any(|w| w == needle));
+
+        // reopening with the right passphrase reads the row back
+        let db = Db::new_with_options(String::from(encrypted_db), encrypted_options("secret"));
+        let mut table = db.table.lock().unwrap();
+        let cursor = Cursor::table_start(&mut table);
+        assert!(!cursor.end_of_table);
+        drop(table);
+        assert_eq!(db.scan_parallel(2).unwrap()[0].email, "plaintext_email");
+        drop(db);
 
-        let insert_command = String::from("insert 1 test_user test_email");
-        db.run_db_test(insert_command);
+        assert_eq!(
+            Db::open(String::from(encrypted_db), encrypted_options("wrong")).err(),
+            Some(DbError::Storage("Wrong passphrase for encrypted db file"))
+        );
+        assert_eq!(
+            Db::open(String::from(encrypted_db), DbOptions::default()).err(),
+            Some(DbError::Storage(
+                "Db file is encrypted but no passphrase was given"
+            ))
+        );
+
+        // a corrupt iteration count is rejected before a key is derived from it
+        let file_contents = fs::read(encrypted_db).unwrap();
+        let corrupt_db = "test_encrypted_corrupt.db";
+        for kdf_iterations in [0, u32::MAX] {
+            let mut corrupt_contents = file_contents.clone();
+            corrupt_contents[9..13].copy_from_slice(&kdf_iterations.to_ne_bytes());
+            fs::write(corrupt_db, corrupt_contents).unwrap();
+            assert_eq!(
+                Db::open(String::from(corrupt_db), encrypted_options("secret")).err(),
+                Some(DbError::Storage(
+                    "Db file has an invalid key derivation iteration count. Corrupt file"
+                ))
+            );
+        }
+        fs::remove_file(corrupt_db).unwrap();
 
-        let insert_command = String::from("insert 2 test_user_2 test_email_2");
-        db.run_db_test(insert_command);
+        // an encrypted file can be attached with its passphrase
+        let mut db = TestDb::new("encrypted_attach");
+        db.attach(encrypted_db, "secret", &encrypted_options("secret"))
+            .unwrap();
+        assert_eq!(
+            db.execute_for_test("select email from secret.users")
+                .values(),
+            vec![vec!["plaintext_email"]]
+        );
+        drop(db);
 
-        let select_command = String::from("select");
-        db.run_db_test(select_command);
+        fs::remove_file(encrypted_db).unwrap();
     }
 
+    #[cfg(feature = "mmap")]
     #[test]
-    fn page_full_test() {
+    fn mmap_db_test() {
         init();
-        let mut db = Db::new(String::from("test.db"));
+        let mmap_db = "test_mmap.db";
+        if Path::exists(Path::new(mmap_db)) {
+            fs::remove_file(mmap_db).unwrap();
+        }
+        let mmap_options = || DbOptions {
+            mmap: true,
+            ..Default::default()
+        };
+
+        {
+            let mut db = Db::new_with_options(String::from(mmap_db), mmap_options());
+            for id in 1..=3 {
+                db.run_db_test(format!("insert {} test_user test_email", id));
+            }
+        }
 
-        for _ in 0..35 {
-            let insert_command = String::from("insert 1 test_user test_email");
-            db.run_db_test(insert_command);
+        let mut db = Db::new_with_options(String::from(mmap_db), mmap_options());
+        assert_eq!(table_keys(&db), vec![1, 2, 3]);
+
+        db.run_db_test(String::from("insert 4 test_user test_email"));
+        drop(db);
+
+        let mut db = Db::new_with_options(String::from(mmap_db), mmap_options());
+        assert_eq!(table_keys(&db), vec![1, 2, 3, 4]);
+        assert_eq!(db.scan_parallel(2).unwrap().len(), 4);
+
+        // another connection shrinking the file makes the next statement map it again
+        let mut other_db = Db::new(String::from(mmap_db));
+        other_db.execute("truncate").unwrap();
+        other_db.execute("insert 9 test_user test_email").unwrap();
+        drop(other_db);
+        assert_eq!(db.execute_for_test("select id").values(), vec![vec!["9"]]);
+        assert!(db.execute(".check").is_ok());
+
+        drop(db);
+        fs::remove_file(mmap_db).unwrap();
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn export_parquet_test() {
+        use arrow_array::{Array, StringArray, UInt32Array};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        init();
+        let export_db = "test_export.db";
+        let export_file = "test_export.parquet";
+        if Path::exists(Path::new(export_db)) {
+            fs::remove_file(export_db).unwrap();
         }
+        let mut db = Db::new(String::from(export_db));
 
-        let select_command = String::from("select");
-        db.run_db_test(select_command);
+        for id in [3, 1, 2] {
+            db.run_db_test(format!("insert {} user_{} email_{}", id, id, id));
+        }
+        assert_eq!(db.export_parquet(export_file).unwrap(), 3);
 
-        // let page_num = {
-        //     let mut count = 0;
-        //     for p in db.table.pager.pages {
-        //         match p {
-        //             Some(_) => count = count + 1,
-        //             None => {}
-        //         }
-        //     }
+        let reader = ParquetRecordBatchReaderBuilder::try_new(fs::File::open(export_file).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(batches.len(), 1);
 
-        //     count
-        // };
+        let ids = batches[0]
+            .column(0)
+            .as_any()
+            .downcast_ref::<UInt32Array>()
+            .unwrap();
+        let emails = batches[0]
+            .column(2)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(ids.values().to_vec(), vec![1, 2, 3]);
+        assert_eq!(emails.value(1), "email_2");
+        assert_eq!(emails.len(), 3);
 
-        // assert_eq!(page_num, 2, "Table has wrong number of pages!");
+        drop(db);
+        fs::remove_file(export_db).unwrap();
+        fs::remove_file(export_file).unwrap();
     }
 
+    #[cfg(feature = "serde")]
     #[test]
-    fn insert_max_string_test() {
+    fn serde_rows_test() {
+        #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+        struct User {
+            id: u32,
+            username: String,
+            email: String,
+        }
+
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Email {
+            email: String,
+        }
+
         init();
-        let mut db = Db::new(String::from("test.db"));
+        let serde_db = "test_serde.db";
+        if Path::exists(Path::new(serde_db)) {
+            fs::remove_file(serde_db).unwrap();
+        }
+
+        {
+            let db = Db::new(String::from(serde_db));
+            let mut table = db.table.lock().unwrap();
+            for id in [2, 1] {
+                let user = User {
+                    id,
+                    username: format!("user_{}", id),
+                    email: format!("email_{}", id),
+                };
+                assert!(table.insert_serialized(&user).is_ok());
+            }
+            assert!(table
+                .insert_serialized(&Email {
+                    email: String::from("no_id")
+                })
+                .is_err());
+        }
 
-        let insert_command = String::from(
-            "insert 1 aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaabbcc test_email",
+        // inserted rows were saved when the db was closed
+        let db = Db::new(String::from(serde_db));
+        let mut table = db.table.lock().unwrap();
+        let users: Vec<User> = table.rows_as().map(Result::unwrap).collect();
+        assert_eq!(users.len(), 2);
+        assert_eq!(
+            users[0],
+            User {
+                id: 1,
+                username: String::from("user_1"),
+                email: String::from("email_1"),
+            }
         );
-        db.run_db_test(insert_command);
 
-        let select_command = String::from("select");
-        db.run_db_test(select_command);
+        let emails: Vec<String> = table
+            .rows_as::<Email>()
+            .map(|row| row.unwrap().email)
+            .collect();
+        assert_eq!(emails, vec!["email_1", "email_2"]);
+
+        drop(table);
+        drop(db);
+        fs::remove_file(serde_db).unwrap();
+    }
+
+    #[cfg(feature = "capi")]
+    #[test]
+    fn capi_test() {
+        use qba_db::capi::{
+            qba_close, qba_errmsg, qba_exec, qba_open, QBA_ABORT, QBA_ERROR, QBA_MISUSE, QBA_OK,
+        };
+        use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+        unsafe extern "C" fn collect_rows(
+            user_data: *mut c_void,
+            n_columns: c_int,
+            values: *const *const c_char,
+            names: *const *const c_char,
+        ) -> c_int {
+            let rows = &mut *(user_data as *mut Vec<String>);
+            for i in 0..n_columns as usize {
+                let name = CStr::from_ptr(*names.add(i)).to_str().unwrap();
+                let value = CStr::from_ptr(*values.add(i)).to_str().unwrap();
+                rows.push(format!("{}={}", name, value));
+            }
+            // stop after the second row
+            (rows.len() >= 4) as c_int
+        }
+
+        init();
+        let capi_db = "test_capi.db";
+        if Path::exists(Path::new(capi_db)) {
+            fs::remove_file(capi_db).unwrap();
+        }
+        let sql = |statement: &str| CString::new(statement).unwrap();
+
+        unsafe {
+            let mut db = std::ptr::null_mut();
+            assert_eq!(qba_open(sql(capi_db).as_ptr(), &mut db), QBA_OK);
+            assert!(!db.is_null());
+
+            for id in 1..=3 {
+                let insert = sql(&format!("insert {} user_{} email_{}", id, id, id));
+                assert_eq!(
+                    qba_exec(db, insert.as_ptr(), None, std::ptr::null_mut()),
+                    QBA_OK
+                );
+            }
+
+            let mut rows: Vec<String> = vec![];
+            let select = sql("select id, username where id in (1, 2)");
+            let rows_ptr = &mut rows as *mut Vec<String> as *mut c_void;
+            assert_eq!(
+                qba_exec(db, select.as_ptr(), Some(collect_rows), rows_ptr),
+                QBA_ABORT
+            );
+            assert_eq!(
+                rows,
+                vec!["id=1", "username=user_1", "id=2", "username=user_2"]
+            );
+
+            let bad = sql("select password");
+            assert_eq!(
+                qba_exec(db, bad.as_ptr(), None, std::ptr::null_mut()),
+                QBA_ERROR
+            );
+            assert!(CStr::from_ptr(qba_errmsg(db))
+                .to_str()
+                .unwrap()
+                .starts_with("Unknown column"));
+
+            assert_eq!(qba_close(db), QBA_OK);
+
+            // a failed open still hands back a handle that explains why
+            fs::write(capi_db, [0xff; 8]).unwrap();
+            let mut db = std::ptr::null_mut();
+            assert_eq!(qba_open(sql(capi_db).as_ptr(), &mut db), QBA_ERROR);
+            assert_eq!(
+                CStr::from_ptr(qba_errmsg(db)).to_str().unwrap(),
+                "Db file has no table header. Unsupported file format"
+            );
+            let select = sql("select");
+            assert_eq!(
+                qba_exec(db, select.as_ptr(), None, std::ptr::null_mut()),
+                QBA_MISUSE
+            );
+            assert_eq!(qba_close(db), QBA_OK);
+        }
+
+        fs::remove_file(capi_db).unwrap();
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn python_test() {
+        use pyo3::{prelude::*, types::PyDict, wrap_pymodule};
+
+        init();
+        let python_db = "test_python.db";
+        if Path::exists(Path::new(python_db)) {
+            fs::remove_file(python_db).unwrap();
+        }
+        let bad_db = "test_python_bad.db";
+        fs::write(bad_db, [0xff; 8]).unwrap();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let locals = PyDict::new(py);
+            locals
+                .set_item("qba_db", wrap_pymodule!(qba_db::python::qba_db)(py))
+                .unwrap();
+
+            py.run(
+                c"
+conn = qba_db.connect('test_python.db')
+conn.execute('insert 1 user_1 email_1')
+conn.cursor().execute('insert 2 user_2 email_2')
+
+rows = conn.execute('select id, username').fetchall()
+assert rows == [(1, 'user_1'), (2, 'user_2')], rows
+
+cursor = conn.execute('select')
+assert cursor.description[0][0] == 'id'
+assert cursor.fetchone() == (1, 'user_1', 'email_1')
+assert [row[0] for row in cursor] == [2]
+
+try:
+    conn.execute('select password')
+    raise AssertionError('expected DatabaseError')
+except qba_db.DatabaseError:
+    pass
+
+conn.close()
+
+try:
+    qba_db.connect('test_python_bad.db')
+    raise AssertionError('expected DatabaseError')
+except qba_db.DatabaseError as e:
+    assert str(e) == 'Db file has no table header. Unsupported file format', e
+",
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+        });
+
+        fs::remove_file(python_db).unwrap();
+        fs::remove_file(bad_db).unwrap();
     }
 }